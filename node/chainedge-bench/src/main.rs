@@ -0,0 +1,304 @@
+use std::{
+    net::TcpListener,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use chainedge::{
+    chain::OfflineChain,
+    config::{Config, SiteConfig},
+    Node,
+};
+use clap::{Parser, ValueEnum};
+use futures::StreamExt;
+use miette::{miette, IntoDiagnostic, Result};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use tokio::{sync::oneshot, task::JoinHandle};
+
+mod report;
+mod zipf;
+
+use report::{Report, Sample};
+use zipf::Zipf;
+
+/// Drives the proxy path of an edge node and reports throughput and latency.
+///
+/// Without `--edge` it starts the programmable origin and an offline node in-process.
+/// An external node has to proxy to `origin_server`, whose `/programmable` routes are requested.
+#[derive(Debug, Parser)]
+#[command(name = "chainedge-bench")]
+struct Args {
+    /// Base url of a running node, e.g. `http://127.0.0.1:3001`.
+    #[arg(long)]
+    edge: Option<String>,
+    /// Admin key of the node at `--edge`, to count its hits.
+    #[arg(long, env = "ADMIN_AUTH_KEY")]
+    admin_key: Option<String>,
+    /// Requests in flight at any time.
+    #[arg(long, short, default_value_t = 32)]
+    concurrency: usize,
+    /// Stop after this many seconds.
+    #[arg(long, short, default_value_t = 10)]
+    duration: u64,
+    /// Stop after this many requests, if earlier than `--duration`.
+    #[arg(long, short)]
+    requests: Option<usize>,
+    /// Distinct cacheable urls.
+    #[arg(long, default_value_t = 1000)]
+    urls: usize,
+    /// Zipf exponent of the url popularity, 0 for uniform.
+    #[arg(long, default_value_t = 1.0)]
+    zipf: f64,
+    /// Share of requests to the cacheable urls, the others request a url never seen before.
+    #[arg(long, default_value_t = 0.9)]
+    hit_ratio: f64,
+    /// Response body size in bytes.
+    #[arg(long, default_value_t = 4096)]
+    body_size: usize,
+    /// Time the origin takes to answer a miss.
+    #[arg(long, default_value_t = 0)]
+    origin_delay_ms: u64,
+    /// Do not request every cacheable url once before measuring.
+    #[arg(long)]
+    no_warmup: bool,
+    /// Seed of the url choices, runs with the same seed request the same urls.
+    #[arg(long, default_value_t = 1)]
+    seed: u64,
+    #[arg(long, value_enum, default_value_t = Format::Json)]
+    format: Format,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Format {
+    Json,
+    Text,
+}
+
+const LOCAL_ADMIN_KEY: &str = "bench";
+
+/// Origin and node started by the benchmark itself.
+struct Local {
+    shutdown: oneshot::Sender<()>,
+    node: JoinHandle<Result<()>>,
+    cache_dir: PathBuf,
+}
+
+async fn start_local() -> Result<(String, Local)> {
+    let cache_dir = std::env::temp_dir().join(format!("chainedge-bench-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&cache_dir);
+    std::env::set_var("CHAINEDGE_CACHE_DIR", &cache_dir);
+
+    let origin_listener = TcpListener::bind("127.0.0.1:0").into_diagnostic()?;
+    let origin = origin_listener.local_addr().into_diagnostic()?;
+    tokio::spawn(
+        axum::Server::from_tcp(origin_listener)
+            .into_diagnostic()?
+            .serve(origin_server::app().into_make_service()),
+    );
+
+    let edge_listener = TcpListener::bind("127.0.0.1:0").into_diagnostic()?;
+    let edge = edge_listener.local_addr().into_diagnostic()?;
+    let mut config = Config::default();
+    config.listen = edge.to_string();
+    config.sites = vec![SiteConfig {
+        front_domain: edge.to_string(),
+        origin_domain: origin.to_string(),
+        ..config.sites.remove(0)
+    }];
+    let node = Node {
+        config,
+        admin_password: LOCAL_ADMIN_KEY.to_owned(),
+        chain: Arc::new(OfflineChain::new(Vec::new())),
+    };
+
+    let (shutdown, stop) = oneshot::channel::<()>();
+    let node = tokio::spawn(chainedge::serve(node, edge_listener, async {
+        let _ = stop.await;
+    }));
+
+    Ok((
+        format!("http://{}", edge),
+        Local {
+            shutdown,
+            node,
+            cache_dir,
+        },
+    ))
+}
+
+fn origin_query(args: &Args) -> String {
+    format!(
+        "cache_control=max-age=3600&size={}&delay_ms={}",
+        args.body_size, args.origin_delay_ms
+    )
+}
+
+/// Total hits of the node, `None` without admin access.
+async fn edge_hits(client: &reqwest::Client, edge: &str, admin_key: Option<&str>) -> Option<u64> {
+    let status: serde_json::Value = client
+        .get(format!("{}/_chainedge/api/v1/status", edge))
+        .bearer_auth(admin_key?)
+        .send()
+        .await
+        .ok()?
+        .error_for_status()
+        .ok()?
+        .json()
+        .await
+        .ok()?;
+    status["cache"]["hits"].as_u64()
+}
+
+async fn fetch(client: &reqwest::Client, url: &str, cacheable: bool) -> Sample {
+    let started = Instant::now();
+    let result = match client.get(url).send().await {
+        Ok(response) => {
+            let status = response.status().as_u16();
+            response.bytes().await.map(|body| (status, body.len()))
+        }
+        Err(e) => Err(e),
+    };
+    Sample {
+        latency: started.elapsed(),
+        status: result.as_ref().ok().map(|(status, _)| *status),
+        bytes: result.map_or(0, |(_, bytes)| bytes),
+        cacheable,
+    }
+}
+
+async fn warm_up(client: &reqwest::Client, edge: &str, args: &Args) -> Result<()> {
+    let query = origin_query(args);
+    let failed = futures::stream::iter(0..args.urls)
+        .map(|i| {
+            let url = format!("{}/programmable/bench-{}?{}", edge, i, query);
+            async move { fetch(client, &url, true).await }
+        })
+        .buffer_unordered(args.concurrency)
+        .filter(|s| futures::future::ready(s.status != Some(200)))
+        .count()
+        .await;
+    match failed {
+        0 => Ok(()),
+        failed => Err(miette!(
+            "{} of {} urls failed to warm up",
+            failed,
+            args.urls
+        )),
+    }
+}
+
+async fn run(client: reqwest::Client, edge: String, args: Arc<Args>) -> (Vec<Sample>, Duration) {
+    let zipf = Arc::new(Zipf::new(args.urls, args.zipf));
+    let issued = Arc::new(AtomicUsize::new(0));
+    let started = Instant::now();
+    let deadline = started + Duration::from_secs(args.duration);
+
+    let workers: Vec<_> = (0..args.concurrency)
+        .map(|worker| {
+            let (client, edge, args, zipf, issued) = (
+                client.clone(),
+                edge.clone(),
+                args.clone(),
+                zipf.clone(),
+                issued.clone(),
+            );
+            tokio::spawn(async move {
+                let mut rng = SmallRng::seed_from_u64(args.seed.wrapping_add(worker as u64));
+                let query = origin_query(&args);
+                let mut samples = Vec::new();
+                let mut misses = 0;
+                while Instant::now() < deadline {
+                    let n = issued.fetch_add(1, Ordering::Relaxed);
+                    if args.requests.is_some_and(|limit| n >= limit) {
+                        break;
+                    }
+                    let cacheable = rng.gen_bool(args.hit_ratio.clamp(0.0, 1.0));
+                    let url = match cacheable {
+                        true => format!(
+                            "{}/programmable/bench-{}?{}",
+                            edge,
+                            zipf.sample(&mut rng),
+                            query
+                        ),
+                        false => {
+                            misses += 1;
+                            format!(
+                                "{}/programmable/miss-{}-{}-{}?{}",
+                                edge, args.seed, worker, misses, query
+                            )
+                        }
+                    };
+                    samples.push(fetch(&client, &url, cacheable).await);
+                }
+                samples
+            })
+        })
+        .collect();
+
+    let mut samples = Vec::new();
+    for worker in workers {
+        samples.extend(worker.await.unwrap_or_default());
+    }
+    (samples, started.elapsed())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Arc::new(Args::parse());
+    if args.concurrency == 0 || args.urls == 0 {
+        return Err(miette!("--concurrency and --urls must be at least 1"));
+    }
+
+    let (edge, local) = match &args.edge {
+        Some(edge) => (edge.trim_end_matches('/').to_owned(), None),
+        None => {
+            let (edge, local) = start_local().await?;
+            (edge, Some(local))
+        }
+    };
+    let admin_key = match &local {
+        Some(_) => Some(LOCAL_ADMIN_KEY),
+        None => args.admin_key.as_deref(),
+    };
+
+    let client = reqwest::Client::builder()
+        .pool_max_idle_per_host(args.concurrency)
+        .timeout(Duration::from_secs(30))
+        .build()
+        .into_diagnostic()?;
+
+    if !args.no_warmup {
+        warm_up(&client, &edge, &args).await?;
+    }
+
+    let hits_before = edge_hits(&client, &edge, admin_key).await;
+    let (samples, elapsed) = run(client.clone(), edge.clone(), args.clone()).await;
+    let hits_after = edge_hits(&client, &edge, admin_key).await;
+
+    let report = Report::new(
+        &samples,
+        elapsed,
+        // a counter reset during the run, e.g. by a restart, leaves the hits unknown
+        hits_before
+            .zip(hits_after)
+            .and_then(|(before, after)| after.checked_sub(before)),
+    );
+    match args.format {
+        Format::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&report).into_diagnostic()?
+        ),
+        Format::Text => report.print_text(),
+    }
+
+    if let Some(local) = local {
+        let _ = local.shutdown.send(());
+        local.node.await.into_diagnostic()??;
+        let _ = std::fs::remove_dir_all(&local.cache_dir);
+    }
+    Ok(())
+}
@@ -0,0 +1,164 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use serde::Serialize;
+
+/// Outcome of one request, as a worker saw it.
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub latency: Duration,
+    /// `None` when the request failed before a response.
+    pub status: Option<u16>,
+    pub bytes: usize,
+    /// Whether the url was one of the cacheable set rather than a one-off miss.
+    pub cacheable: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Latency {
+    pub mean_ms: f64,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    pub p999_ms: f64,
+    pub max_ms: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub requests: usize,
+    pub errors: usize,
+    pub duration_secs: f64,
+    pub requests_per_sec: f64,
+    pub bytes_per_sec: f64,
+    /// Requests sent to the cacheable urls, which are hits once they are warm.
+    pub cacheable_requests: usize,
+    /// Hits counted by the node over the run, when its admin API was reachable.
+    pub edge_hits: Option<u64>,
+    pub statuses: BTreeMap<String, usize>,
+    pub latency: Latency,
+    pub cacheable_latency: Option<Latency>,
+    pub miss_latency: Option<Latency>,
+}
+
+fn ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+/// Nearest-rank percentile of sorted latencies.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn latency(samples: impl Iterator<Item = Duration>) -> Option<Latency> {
+    let mut sorted: Vec<Duration> = samples.collect();
+    if sorted.is_empty() {
+        return None;
+    }
+    sorted.sort_unstable();
+    let total: Duration = sorted.iter().sum();
+    Some(Latency {
+        mean_ms: ms(total) / sorted.len() as f64,
+        p50_ms: ms(percentile(&sorted, 50.0)),
+        p90_ms: ms(percentile(&sorted, 90.0)),
+        p99_ms: ms(percentile(&sorted, 99.0)),
+        p999_ms: ms(percentile(&sorted, 99.9)),
+        max_ms: ms(*sorted.last().unwrap()),
+    })
+}
+
+impl Report {
+    pub fn new(samples: &[Sample], elapsed: Duration, edge_hits: Option<u64>) -> Report {
+        let mut statuses = BTreeMap::new();
+        for sample in samples {
+            let status = sample.status.map_or("error".to_owned(), |s| s.to_string());
+            *statuses.entry(status).or_insert(0) += 1;
+        }
+        let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+        let bytes: usize = samples.iter().map(|s| s.bytes).sum();
+
+        Report {
+            requests: samples.len(),
+            errors: samples.iter().filter(|s| s.status.is_none()).count(),
+            duration_secs: elapsed.as_secs_f64(),
+            requests_per_sec: samples.len() as f64 / secs,
+            bytes_per_sec: bytes as f64 / secs,
+            cacheable_requests: samples.iter().filter(|s| s.cacheable).count(),
+            edge_hits,
+            statuses,
+            latency: latency(samples.iter().map(|s| s.latency)).unwrap_or_default(),
+            cacheable_latency: latency(samples.iter().filter(|s| s.cacheable).map(|s| s.latency)),
+            miss_latency: latency(samples.iter().filter(|s| !s.cacheable).map(|s| s.latency)),
+        }
+    }
+
+    pub fn print_text(&self) {
+        println!(
+            "{} requests in {:.2}s, {:.0} req/s, {:.1} MiB/s, {} errors",
+            self.requests,
+            self.duration_secs,
+            self.requests_per_sec,
+            self.bytes_per_sec / (1024.0 * 1024.0),
+            self.errors
+        );
+        if let Some(hits) = self.edge_hits {
+            println!(
+                "{} edge hits, {} requests to cacheable urls",
+                hits, self.cacheable_requests
+            );
+        }
+        for (name, latency) in [
+            ("all", Some(&self.latency)),
+            ("cacheable", self.cacheable_latency.as_ref()),
+            ("misses", self.miss_latency.as_ref()),
+        ] {
+            let Some(l) = latency else { continue };
+            println!(
+                "{:10} mean {:.2}ms  p50 {:.2}ms  p90 {:.2}ms  p99 {:.2}ms  p99.9 {:.2}ms  max {:.2}ms",
+                name, l.mean_ms, l.p50_ms, l.p90_ms, l.p99_ms, l.p999_ms, l.max_ms
+            );
+        }
+        let statuses: Vec<String> = self
+            .statuses
+            .iter()
+            .map(|(status, count)| format!("{}: {}", status, count))
+            .collect();
+        println!("statuses   {}", statuses.join(", "));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(values: impl IntoIterator<Item = u64>) -> Vec<Duration> {
+        values.into_iter().map(Duration::from_millis).collect()
+    }
+
+    #[test]
+    fn takes_the_nearest_rank() {
+        let sorted = millis(1..=10);
+        assert_eq!(percentile(&sorted, 0.0), Duration::from_millis(1));
+        assert_eq!(percentile(&sorted, 10.0), Duration::from_millis(1));
+        assert_eq!(percentile(&sorted, 11.0), Duration::from_millis(2));
+        assert_eq!(percentile(&sorted, 50.0), Duration::from_millis(5));
+        assert_eq!(percentile(&sorted, 90.0), Duration::from_millis(9));
+        assert_eq!(percentile(&sorted, 99.9), Duration::from_millis(10));
+        assert_eq!(percentile(&sorted, 100.0), Duration::from_millis(10));
+
+        let single = millis([3]);
+        assert_eq!(percentile(&single, 50.0), Duration::from_millis(3));
+        assert_eq!(percentile(&single, 99.9), Duration::from_millis(3));
+    }
+
+    #[test]
+    fn summarizes_unsorted_samples() {
+        assert!(latency(std::iter::empty()).is_none());
+
+        let summary = latency(millis([40, 10, 30, 20]).into_iter()).unwrap();
+        assert_eq!(summary.mean_ms, 25.0);
+        assert_eq!(summary.p50_ms, 20.0);
+        assert_eq!(summary.p90_ms, 40.0);
+        assert_eq!(summary.max_ms, 40.0);
+    }
+}
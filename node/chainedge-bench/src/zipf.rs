@@ -0,0 +1,72 @@
+use rand::Rng;
+
+/// Ranks `0..n` drawn with probability proportional to `1 / (rank + 1)^exponent`.
+/// An exponent of 0 is uniform, around 1 is typical of web traffic.
+#[derive(Debug, Clone)]
+pub struct Zipf {
+    cumulative: Vec<f64>,
+}
+
+impl Zipf {
+    pub fn new(n: usize, exponent: f64) -> Self {
+        let mut total = 0.0;
+        let cumulative = (1..=n.max(1))
+            .map(|rank| {
+                total += 1.0 / (rank as f64).powf(exponent);
+                total
+            })
+            .collect();
+        Zipf { cumulative }
+    }
+
+    pub fn sample(&self, rng: &mut impl Rng) -> usize {
+        let total = *self.cumulative.last().unwrap();
+        let target = rng.gen::<f64>() * total;
+        self.cumulative
+            .partition_point(|c| *c <= target)
+            .min(self.cumulative.len() - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    fn counts(zipf: &Zipf, n: usize, draws: usize) -> Vec<usize> {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut counts = vec![0; n];
+        for _ in 0..draws {
+            counts[zipf.sample(&mut rng)] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn draws_ranks_in_range() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let zipf = Zipf::new(5, 1.2);
+        assert!((0..1000).all(|_| zipf.sample(&mut rng) < 5));
+
+        // an empty set still has one rank to draw
+        let empty = Zipf::new(0, 1.0);
+        assert_eq!(empty.sample(&mut rng), 0);
+    }
+
+    #[test]
+    fn is_uniform_with_a_zero_exponent() {
+        let counts = counts(&Zipf::new(4, 0.0), 4, 40_000);
+        assert!(counts.iter().all(|&c| (9_000..11_000).contains(&c)), "{:?}", counts);
+    }
+
+    #[test]
+    fn favors_low_ranks_as_the_exponent_says() {
+        let counts = counts(&Zipf::new(100, 1.0), 100, 100_000);
+        assert!(counts.windows(2).take(5).all(|w| w[0] > w[1]), "{:?}", counts);
+        // rank 0 is drawn twice as often as rank 1, three times as often as rank 2
+        let ratio = |rank: usize| counts[0] as f64 / counts[rank] as f64;
+        assert!((1.8..2.2).contains(&ratio(1)), "{}", ratio(1));
+        assert!((2.7..3.3).contains(&ratio(2)), "{}", ratio(2));
+    }
+}
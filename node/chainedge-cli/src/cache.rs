@@ -0,0 +1,387 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use chainedge::{config::Config, envelope, maintenance, snapshot};
+use clap::Subcommand;
+use miette::{miette, Context, IntoDiagnostic, Result};
+
+use crate::print_json;
+
+/// Maintenance of a node's cache directory. The node must not be running while entries
+/// are removed or rewritten (`verify --quarantine`, `gc`, `import`, `migrate`).
+#[derive(Debug, Subcommand)]
+pub enum CacheCommand {
+    /// List the entries with their size, age and remaining freshness.
+    Ls {
+        /// Only entries whose url (the key without its method) starts with this.
+        #[arg(long)]
+        prefix: Option<String>,
+    },
+    /// Print the stored request and response of an entry.
+    Dump {
+        key: String,
+        /// Write the response body to this file.
+        #[arg(long)]
+        body: Option<PathBuf>,
+    },
+    /// Check every entry against its integrity and that it can be decoded.
+    Verify {
+        /// Quarantine the entries that fail.
+        #[arg(long)]
+        quarantine: bool,
+    },
+    /// Remove the entries that are no longer fresh, and variants left without their entry.
+    Gc {
+        /// Only report what would be removed.
+        #[arg(long)]
+        dry_run: bool,
+        /// Compact the cache afterwards, to free the disk space of the removed entries.
+        #[arg(long)]
+        compact: bool,
+    },
+    /// Print how much disk the cache takes.
+    Du,
+    /// Write the fresh entries to a snapshot file, `-` for stdout.
+    Export {
+        file: PathBuf,
+        /// Only entries whose url starts with this.
+        #[arg(long)]
+        prefix: Option<String>,
+    },
+    /// Store the fresh entries of a snapshot file, `-` for stdin.
+    Import {
+        file: PathBuf,
+        /// Replace entries the cache already has.
+        #[arg(long)]
+        overwrite: bool,
+    },
+    /// Rewrite every entry with the current format and quarantine the unreadable ones.
+    Migrate {
+        /// Also copy the live entries into a fresh cache, dropping removed and replaced ones.
+        #[arg(long)]
+        compact: bool,
+        /// Move entries stored before keys started with the front domain under the keys of
+        /// this site of the node config (`CHAINEDGE_CONFIG`).
+        #[arg(long)]
+        front_domain: Option<String>,
+    },
+    /// List the entries moved to quarantine.
+    Quarantined,
+}
+
+fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+fn or_dash(value: Option<u64>) -> String {
+    value.map_or("-".to_owned(), |v| v.to_string())
+}
+
+/// Runs blocking cache work off the async runtime.
+async fn blocking<T: Send + 'static>(work: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+    tokio::task::spawn_blocking(work).await.into_diagnostic()?
+}
+
+pub async fn run(cache_dir: &str, command: &CacheCommand, json: bool) -> Result<()> {
+    let dir = cache_dir.to_owned();
+    match command {
+        CacheCommand::Ls { prefix } => {
+            let prefix = prefix.clone();
+            let entries = blocking(move || Ok(maintenance::list(&dir, prefix.as_deref()))).await?;
+            if json {
+                return print_json(&entries);
+            }
+            println!("{:>10} {:>8} {:>8} {:>6} {:>3}  key", "size", "age", "ttl", "status", "v");
+            for entry in entries {
+                match &entry.error {
+                    Some(error) => println!("{:>10} unreadable {:?}: {}", entry.size, entry.key, error),
+                    None => println!(
+                        "{:>10} {:>8} {:>8} {:>6} {:>3}  {:?}",
+                        entry.size,
+                        or_dash(entry.age_secs),
+                        or_dash(entry.ttl_secs),
+                        or_dash(entry.status.map(u64::from)),
+                        or_dash(entry.version.map(u64::from)),
+                        entry.key
+                    ),
+                }
+            }
+        }
+        CacheCommand::Dump { key, body } => {
+            let key = key.clone();
+            let entry = blocking(move || maintenance::dump(&dir, &key)).await?;
+            if let Some(path) = body {
+                std::fs::write(path, &entry.body)
+                    .into_diagnostic()
+                    .wrap_err_with(|| format!("Could not write {}", path.display()))?;
+            }
+            if json {
+                return print_json(&entry);
+            }
+            println!("key       {:?}", entry.key);
+            println!("version   {}", entry.version);
+            println!("cached at {}, fresh for {} s", entry.cached_at, entry.ttl_secs);
+            println!();
+            println!("{} {}", entry.request.method, entry.request.uri);
+            for (name, values) in &entry.request.headers {
+                values.iter().for_each(|v| println!("{}: {}", name, v));
+            }
+            println!();
+            println!("{} ({} bytes)", entry.response.status, entry.response.body_bytes);
+            for (name, values) in &entry.response.headers {
+                values.iter().for_each(|v| println!("{}: {}", name, v));
+            }
+        }
+        CacheCommand::Verify { quarantine } => {
+            let quarantine = *quarantine;
+            let report = blocking(move || maintenance::verify(&dir, quarantine)).await?;
+            if json {
+                return print_json(&report);
+            }
+            for failure in &report.failed {
+                println!("failed {:?}: {}", failure.key, failure.reason);
+            }
+            println!("{} checked, {} failed", report.checked, report.failed.len());
+        }
+        CacheCommand::Gc { dry_run, compact } => {
+            let (dry_run, compact) = (*dry_run, *compact);
+            let report = blocking(move || {
+                let mut report = maintenance::gc(&dir, dry_run)?;
+                if compact && !dry_run {
+                    report.bytes_after = envelope::migrate(&dir, true, None)?.bytes_after;
+                }
+                Ok(report)
+            })
+            .await?;
+            if json {
+                return print_json(&report);
+            }
+            let verb = if report.dry_run { "would remove" } else { "removed" };
+            for key in report.expired.iter().chain(&report.orphaned_variants) {
+                println!("{} {:?}", verb, key);
+            }
+            println!(
+                "{} {} expired entries and {} orphaned variants, {} bytes before, {} after",
+                verb,
+                report.expired.len(),
+                report.orphaned_variants.len(),
+                report.bytes_before,
+                report.bytes_after
+            );
+        }
+        CacheCommand::Du => {
+            let usage = blocking(move || maintenance::usage(&dir)).await?;
+            if json {
+                return print_json(&usage);
+            }
+            println!("{} entries, {} compressed variants", usage.entries, usage.variants);
+            println!("{} bytes live, {} bytes on disk", usage.live_bytes, usage.disk_bytes);
+            println!("{} quarantined, {} bytes", usage.quarantined, usage.quarantine_bytes);
+        }
+        CacheCommand::Export { file, prefix } => {
+            let (path, prefix) = (file.clone(), prefix.clone());
+            let report = blocking(move || match is_stdio(&path) {
+                true => snapshot::export(&dir, prefix.as_deref(), std::io::stdout().lock()),
+                false => {
+                    let out = File::create(&path)
+                        .into_diagnostic()
+                        .wrap_err_with(|| format!("Could not create {}", path.display()))?;
+                    snapshot::export(&dir, prefix.as_deref(), BufWriter::new(out))
+                }
+            })
+            .await?;
+            // stdout carries the snapshot
+            if json && !is_stdio(file) {
+                return print_json(&report);
+            }
+            eprintln!(
+                "exported {} entries ({} bytes), skipped {} expired and {} unreadable",
+                report.entries,
+                report.bytes,
+                report.expired,
+                report.unreadable.len()
+            );
+        }
+        CacheCommand::Import { file, overwrite } => {
+            let (path, overwrite) = (file.clone(), *overwrite);
+            let report = blocking(move || match is_stdio(&path) {
+                true => snapshot::import(&dir, std::io::stdin().lock(), overwrite, |_, _| true),
+                false => {
+                    let input = File::open(&path)
+                        .into_diagnostic()
+                        .wrap_err_with(|| format!("Could not open {}", path.display()))?;
+                    snapshot::import(&dir, BufReader::new(input), overwrite, |_, _| true)
+                }
+            })
+            .await?;
+            if json {
+                return print_json(&report);
+            }
+            for invalid in &report.invalid {
+                println!("invalid {:?}: {}", invalid.key, invalid.reason);
+            }
+            println!(
+                "imported {} entries, skipped {} expired, {} existing and {} invalid",
+                report.imported.len(),
+                report.expired,
+                report.existing,
+                report.invalid.len()
+            );
+        }
+        CacheCommand::Migrate { compact, front_domain } => {
+            let site = match front_domain {
+                Some(domain) => Some(
+                    Config::load()?
+                        .site_for_host(domain)
+                        .cloned()
+                        .ok_or_else(|| miette!("No site configured for {}", domain))?,
+                ),
+                None => None,
+            };
+            let compact = *compact;
+            let report = blocking(move || envelope::migrate(&dir, compact, site.as_ref())).await?;
+            if json {
+                return print_json(&report);
+            }
+            println!(
+                "{} entries, {} migrated to version {}, {} compressed variants kept",
+                report.entries,
+                report.migrated,
+                envelope::VERSION,
+                report.variants
+            );
+            if report.rekeyed > 0 {
+                println!("{} moved under the front domain", report.rekeyed);
+            }
+            for key in &report.quarantined {
+                println!("quarantined {:?}", key);
+            }
+            println!("{} bytes before, {} after", report.bytes_before, report.bytes_after);
+        }
+        CacheCommand::Quarantined => {
+            let records = envelope::quarantined(cache_dir);
+            if json {
+                return print_json(&records);
+            }
+            println!("in {}", envelope::quarantine_dir(cache_dir).display());
+            for record in records {
+                println!("{} {:?}: {}", record.quarantined_at, record.key, record.reason);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::{Cli, Command};
+
+    fn cache_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("chainedge-cli-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir.to_str().unwrap().to_owned()
+    }
+
+    fn store(dir: &str, key: &str, cache_control: &str, body: &'static [u8]) {
+        let request = http::Request::get("http://localhost:8080/a").body(()).unwrap();
+        let response = http::Response::builder()
+            .header("cache-control", cache_control)
+            .body(bytes::Bytes::from_static(body))
+            .unwrap();
+        // an hour ago, so `max-age=60` is expired and `max-age=86400` still fresh
+        let cached_at = SystemTime::now() - Duration::from_secs(3600);
+        maintenance::store(dir, key, request, response, cached_at).unwrap();
+    }
+
+    /// Parses `cache --cache-dir <dir> <args>` as the binary would.
+    fn command(dir: &str, args: &[&str]) -> CacheCommand {
+        let cli = Cli::try_parse_from(
+            ["chainedge-cli", "cache", "--cache-dir", dir].iter().chain(args).copied(),
+        )
+        .unwrap();
+        let Command::Cache { cache_dir, command } = cli.command else {
+            panic!("not a cache command: {:?}", cli.command)
+        };
+        assert_eq!(cache_dir, dir);
+        command
+    }
+
+    async fn run_args(dir: &str, args: &[&str]) -> Result<()> {
+        run(dir, &command(dir, args), true).await
+    }
+
+    fn keys(dir: &str) -> Vec<String> {
+        maintenance::list(dir, None).into_iter().map(|e| e.key).collect()
+    }
+
+    #[tokio::test]
+    async fn inspects_entries() {
+        let dir = cache_dir("inspect");
+        store(&dir, "GET\tlocalhost:8080/a", "max-age=86400", b"body of a");
+        let body = std::env::temp_dir().join(format!("chainedge-cli-{}-body", std::process::id()));
+
+        run_args(&dir, &["ls", "--prefix", "localhost:8080/"]).await.unwrap();
+        run_args(&dir, &["du"]).await.unwrap();
+        run_args(&dir, &["dump", "GET\tlocalhost:8080/a", "--body", body.to_str().unwrap()])
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&body).unwrap(), b"body of a");
+        assert!(run_args(&dir, &["dump", "GET\tlocalhost:8080/missing"]).await.is_err());
+
+        cacache::write_sync(&dir, "GET\tlocalhost:8080/broken", b"not an entry").unwrap();
+        run_args(&dir, &["verify", "--quarantine"]).await.unwrap();
+        assert_eq!(keys(&dir), ["GET\tlocalhost:8080/a"]);
+        run_args(&dir, &["quarantined"]).await.unwrap();
+        assert_eq!(envelope::quarantined(&dir).len(), 1);
+
+        std::fs::remove_file(body).unwrap();
+        let _ = std::fs::remove_dir_all(envelope::quarantine_dir(&dir));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn collects_expired_entries() {
+        let dir = cache_dir("gc");
+        store(&dir, "GET\tlocalhost:8080/fresh", "max-age=86400", b"fresh");
+        store(&dir, "GET\tlocalhost:8080/expired", "max-age=60", b"expired");
+
+        run_args(&dir, &["gc", "--dry-run"]).await.unwrap();
+        assert_eq!(keys(&dir).len(), 2);
+
+        run_args(&dir, &["gc", "--compact"]).await.unwrap();
+        assert_eq!(keys(&dir), ["GET\tlocalhost:8080/fresh"]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn carries_entries_to_another_cache() {
+        let (from, to) = (cache_dir("export"), cache_dir("import"));
+        let snapshot = std::env::temp_dir().join(format!("chainedge-cli-{}-snapshot", std::process::id()));
+        let snapshot = snapshot.to_str().unwrap();
+        store(&from, "GET\tlocalhost:8080/a", "max-age=86400", b"a");
+        store(&from, "GET\tlocalhost:8080/b", "max-age=86400", b"b");
+        store(&from, "GET\tlocalhost:8080/expired", "max-age=60", b"expired");
+
+        run_args(&from, &["export", snapshot, "--prefix", "localhost:8080/"]).await.unwrap();
+        run_args(&to, &["import", snapshot]).await.unwrap();
+        assert_eq!(keys(&to), ["GET\tlocalhost:8080/a", "GET\tlocalhost:8080/b"]);
+        assert_eq!(maintenance::dump(&to, "GET\tlocalhost:8080/b").unwrap().body, b"b");
+
+        run_args(&to, &["migrate", "--compact"]).await.unwrap();
+        assert_eq!(keys(&to).len(), 2);
+
+        std::fs::remove_file(snapshot).unwrap();
+        for dir in [from, to] {
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use std::{
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ethers::types::H256;
+use miette::{Context, IntoDiagnostic, Result};
+use serde::{Deserialize, Serialize};
+
+/// A transaction sent by the CLI, kept until it is mined so `pending` can report on it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SentTransaction {
+    pub hash: H256,
+    pub action: String,
+    pub links: Vec<String>,
+    /// Seconds since the unix epoch.
+    pub sent_at: u64,
+}
+
+impl SentTransaction {
+    pub fn new(hash: H256, action: &str, links: Vec<String>) -> Self {
+        SentTransaction {
+            hash,
+            action: action.to_owned(),
+            links,
+            sent_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+pub fn load(path: &Path) -> Result<Vec<SentTransaction>> {
+    match std::fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Could not parse journal {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Could not read journal {}", path.display())),
+    }
+}
+
+pub fn save(path: &Path, transactions: &[SentTransaction]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).into_diagnostic()?;
+    }
+    let json = serde_json::to_vec_pretty(transactions).into_diagnostic()?;
+    std::fs::write(path, json)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Could not write journal {}", path.display()))
+}
+
+pub fn append(path: &Path, transaction: SentTransaction) -> Result<()> {
+    let mut transactions = load(path)?;
+    transactions.push(transaction);
+    save(path, &transactions)
+}
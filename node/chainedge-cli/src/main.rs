@@ -0,0 +1,491 @@
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use chainedge::{
+    cdn_list::{self, LinkOutcome, ListAction},
+    IChainEdge, CONTRACT_ADDRESS, RPC_URL,
+};
+use clap::{Args, Parser, Subcommand};
+use ethers::prelude::*;
+use miette::{miette, Context, IntoDiagnostic, Result};
+use serde::Serialize;
+
+mod cache;
+mod journal;
+
+/// Operates the on-chain CDN list the chainedge nodes follow.
+#[derive(Debug, Parser)]
+#[command(name = "chainedge-cli")]
+struct Cli {
+    #[arg(long, env = "CHAINEDGE_RPC_URL", default_value = RPC_URL, global = true)]
+    rpc_url: String,
+    #[arg(long, env = "CHAINEDGE_CONTRACT", default_value = CONTRACT_ADDRESS, global = true)]
+    contract: Address,
+    /// Print results as JSON.
+    #[arg(long, global = true)]
+    json: bool,
+    /// Where transactions sent by the CLI are tracked until they are mined.
+    #[arg(long, default_value = "./tmp/cli-transactions.json", global = true)]
+    journal: PathBuf,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Add links to the CDN list.
+    Add(Batch),
+    /// Remove links from the CDN list.
+    Remove(Batch),
+    /// Print the links of the CDN list (`getCDNList`).
+    List,
+    /// Print the index of a link in the CDN list (`getIndexOf`).
+    IndexOf { link: String },
+    /// Print the serve count the nodes reported (`getServeCount`).
+    ServeCount,
+    /// Show the transactions sent by the CLI that are not mined yet.
+    Pending,
+    /// Work on a cache directory offline.
+    Cache {
+        #[arg(long, env = "CHAINEDGE_CACHE_DIR", default_value = "./tmp/cache")]
+        cache_dir: String,
+        #[command(subcommand)]
+        command: cache::CacheCommand,
+    },
+}
+
+#[derive(Debug, Args)]
+struct Batch {
+    /// Links such as `get@/index.html`. Without any, they are read one per line from `--file` or stdin.
+    links: Vec<String>,
+    /// File with one link per line, `-` for stdin. Empty lines and lines starting with `#` are skipped.
+    #[arg(long, short)]
+    file: Option<PathBuf>,
+    /// Links sent per transaction.
+    #[arg(long, default_value_t = 50)]
+    batch_size: usize,
+    /// Check the links and estimate gas as the contract owner without sending anything,
+    /// which needs no `WALLET_PRIV_KEY`.
+    #[arg(long)]
+    dry_run: bool,
+    /// Return once the transactions are sent instead of waiting for them to be mined.
+    #[arg(long)]
+    no_wait: bool,
+}
+
+type Client = SignerMiddleware<Provider<Http>, LocalWallet>;
+
+#[derive(Debug, Serialize)]
+struct TransactionOutcome {
+    links: Vec<String>,
+    gas_estimate: String,
+    hash: Option<H256>,
+    /// `dry-run`, `sent`, `mined`, `failed` or `dropped`.
+    status: &'static str,
+    block: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct BatchReport {
+    action: &'static str,
+    dry_run: bool,
+    links: Vec<LinkOutcome>,
+    transactions: Vec<TransactionOutcome>,
+}
+
+#[derive(Debug, Serialize)]
+struct PendingTransaction {
+    #[serde(flatten)]
+    sent: journal::SentTransaction,
+    /// `pending`, `unknown` (not seen by the node), `mined` or `failed`.
+    status: &'static str,
+    block: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct PendingReport {
+    address: Option<Address>,
+    /// Transactions of the wallet the node has seen but not mined, sent by the CLI or not.
+    pending_nonces: Option<u64>,
+    transactions: Vec<PendingTransaction>,
+}
+
+fn provider(cli: &Cli) -> Result<Provider<Http>> {
+    Provider::<Http>::try_from(cli.rpc_url.as_str()).into_diagnostic()
+}
+
+async fn wallet(provider: &Provider<Http>) -> Result<LocalWallet> {
+    let chain_id = provider.get_chainid().await.into_diagnostic()?;
+    Ok(std::env::var("WALLET_PRIV_KEY")
+        .into_diagnostic()
+        .wrap_err("WALLET_PRIV_KEY must hold the key of the contract owner")?
+        .parse::<LocalWallet>()
+        .into_diagnostic()?
+        .with_chain_id(chain_id.as_u64()))
+}
+
+fn read_links(batch: &Batch) -> Result<Vec<String>> {
+    let input = match &batch.file {
+        Some(path) if path == Path::new("-") => Some(read_stdin()?),
+        Some(path) => Some(
+            std::fs::read_to_string(path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Could not read {}", path.display()))?,
+        ),
+        None if batch.links.is_empty() => Some(read_stdin()?),
+        None => None,
+    };
+
+    let mut links = batch.links.clone();
+    if let Some(input) = input {
+        links.extend(
+            input
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(str::to_owned),
+        );
+    }
+    Ok(links)
+}
+
+fn read_stdin() -> Result<String> {
+    let mut input = String::new();
+    std::io::stdin()
+        .read_to_string(&mut input)
+        .into_diagnostic()?;
+    Ok(input)
+}
+
+/// `addToCDN` or `removeFromCDN` with `links`.
+fn list_call<M: Middleware>(
+    contract: &IChainEdge<M>,
+    action: ListAction,
+    links: Vec<String>,
+) -> ContractCall<M, ()> {
+    match action {
+        ListAction::Add => contract.add_to_cdn(links),
+        ListAction::Remove => contract.remove_from_cdn(links),
+    }
+}
+
+async fn run_batch(cli: &Cli, action: ListAction, batch: &Batch) -> Result<BatchReport> {
+    if batch.batch_size == 0 {
+        return Err(miette!("--batch-size must be at least 1"));
+    }
+
+    let links = read_links(batch)?;
+    let provider = provider(cli)?;
+    // the key is only needed to send, a dry run estimates as the contract owner
+    let sender = match batch.dry_run {
+        true => None,
+        false => {
+            let wallet = wallet(&provider).await?;
+            let client: Arc<Client> = Arc::new(SignerMiddleware::new(provider.clone(), wallet));
+            Some(IChainEdge::new(cli.contract, client))
+        }
+    };
+    let reader = IChainEdge::new(cli.contract, Arc::new(provider));
+    let from = match &sender {
+        Some(sender) => sender.client().address(),
+        None => reader.owner().call().await.into_diagnostic()?,
+    };
+    let listed = reader.get_cdn_list().call().await.into_diagnostic()?;
+
+    let plan = cdn_list::plan(action, &listed, links)?;
+
+    let mut transactions = Vec::new();
+    for chunk in plan.queued.chunks(batch.batch_size) {
+        let gas_estimate = list_call(&reader, action, chunk.to_vec())
+            .from(from)
+            .estimate_gas()
+            .await
+            .into_diagnostic()
+            .wrap_err("Gas estimation failed, is the wallet the contract owner?")?;
+
+        let mut outcome = TransactionOutcome {
+            links: chunk.to_vec(),
+            gas_estimate: gas_estimate.to_string(),
+            hash: None,
+            status: "dry-run",
+            block: None,
+        };
+        let Some(sender) = &sender else {
+            transactions.push(outcome);
+            continue;
+        };
+
+        let call = list_call(sender, action, chunk.to_vec());
+        let pending = call.send().await.into_diagnostic()?;
+        let hash = pending.tx_hash();
+        journal::append(
+            &cli.journal,
+            journal::SentTransaction::new(hash, action.as_str(), chunk.to_vec()),
+        )?;
+        outcome.hash = Some(hash);
+        outcome.status = "sent";
+
+        if !batch.no_wait {
+            let receipt = pending.await.into_diagnostic()?;
+            outcome.status = match &receipt {
+                Some(r) if r.status == Some(1.into()) => "mined",
+                Some(_) => "failed",
+                None => "dropped",
+            };
+            outcome.block = receipt.and_then(|r| r.block_number).map(|b| b.as_u64());
+            if outcome.status != "dropped" {
+                let mut journal = journal::load(&cli.journal)?;
+                journal.retain(|t| t.hash != hash);
+                journal::save(&cli.journal, &journal)?;
+            }
+        }
+        transactions.push(outcome);
+    }
+
+    Ok(BatchReport {
+        action: action.as_str(),
+        dry_run: batch.dry_run,
+        links: plan.links,
+        transactions,
+    })
+}
+
+async fn pending(cli: &Cli) -> Result<PendingReport> {
+    let provider = provider(cli)?;
+
+    let address = match std::env::var("WALLET_PRIV_KEY") {
+        Ok(_) => Some(wallet(&provider).await?.address()),
+        Err(_) => None,
+    };
+    let pending_nonces = match address {
+        Some(address) => {
+            let latest = provider
+                .get_transaction_count(address, Some(BlockNumber::Latest.into()))
+                .await
+                .into_diagnostic()?;
+            let pending = provider
+                .get_transaction_count(address, Some(BlockNumber::Pending.into()))
+                .await
+                .into_diagnostic()?;
+            Some(pending.saturating_sub(latest).as_u64())
+        }
+        None => None,
+    };
+
+    let mut transactions = Vec::new();
+    let mut still_pending = Vec::new();
+    for sent in journal::load(&cli.journal)? {
+        let receipt = provider
+            .get_transaction_receipt(sent.hash)
+            .await
+            .into_diagnostic()?;
+        let (status, block) = match receipt {
+            Some(r) if r.status == Some(1.into()) => ("mined", r.block_number),
+            Some(r) => ("failed", r.block_number),
+            None => {
+                still_pending.push(sent.clone());
+                let known = provider
+                    .get_transaction(sent.hash)
+                    .await
+                    .into_diagnostic()?
+                    .is_some();
+                (if known { "pending" } else { "unknown" }, None)
+            }
+        };
+        transactions.push(PendingTransaction {
+            sent,
+            status,
+            block: block.map(|b| b.as_u64()),
+        });
+    }
+    // mined transactions are reported once, then forgotten
+    journal::save(&cli.journal, &still_pending)?;
+
+    Ok(PendingReport {
+        address,
+        pending_nonces,
+        transactions,
+    })
+}
+
+fn print_json(value: &impl Serialize) -> Result<()> {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(value).into_diagnostic()?
+    );
+    Ok(())
+}
+
+fn print_batch(report: &BatchReport) {
+    for link in &report.links {
+        match &link.reason {
+            Some(reason) => println!("{:8} {:?} ({})", link.status, link.link, reason),
+            None => println!("{:8} {:?}", link.status, link.link),
+        }
+    }
+    for tx in &report.transactions {
+        let links = tx.links.len();
+        match (tx.hash, tx.block) {
+            (Some(hash), Some(block)) => println!(
+                "{} {} links in {:?}, block {}, gas estimate {}",
+                tx.status, links, hash, block, tx.gas_estimate
+            ),
+            (Some(hash), None) => println!(
+                "{} {} links in {:?}, gas estimate {}",
+                tx.status, links, hash, tx.gas_estimate
+            ),
+            (None, _) => println!(
+                "{} {} links, gas estimate {}",
+                tx.status, links, tx.gas_estimate
+            ),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    match &cli.command {
+        Command::Add(batch) | Command::Remove(batch) => {
+            let action = match cli.command {
+                Command::Add(_) => ListAction::Add,
+                _ => ListAction::Remove,
+            };
+            let report = run_batch(&cli, action, batch).await?;
+            match cli.json {
+                true => print_json(&report)?,
+                false => print_batch(&report),
+            }
+        }
+        Command::List => {
+            let contract = IChainEdge::new(cli.contract, Arc::new(provider(&cli)?));
+            let links = contract.get_cdn_list().call().await.into_diagnostic()?;
+            match cli.json {
+                true => print_json(&links)?,
+                false => links.iter().for_each(|l| println!("{}", l)),
+            }
+        }
+        Command::IndexOf { link } => {
+            let contract = IChainEdge::new(cli.contract, Arc::new(provider(&cli)?));
+            let index = contract
+                .get_index_of(link.clone())
+                .call()
+                .await
+                .into_diagnostic()?;
+            match cli.json {
+                true => print_json(&serde_json::json!({ "link": link, "index": index.to_string() }))?,
+                false => println!("{}", index),
+            }
+        }
+        Command::ServeCount => {
+            let contract = IChainEdge::new(cli.contract, Arc::new(provider(&cli)?));
+            let count = contract.get_serve_count().call().await.into_diagnostic()?;
+            match cli.json {
+                true => print_json(&serde_json::json!({ "serve_count": count.to_string() }))?,
+                false => println!("{}", count),
+            }
+        }
+        Command::Pending => {
+            let report = pending(&cli).await?;
+            if cli.json {
+                return print_json(&report);
+            }
+            if let (Some(address), Some(nonces)) = (report.address, report.pending_nonces) {
+                println!("{:?}: {} pending nonces", address, nonces);
+            }
+            for tx in &report.transactions {
+                println!(
+                    "{:8} {:?} {} {} links",
+                    tx.status,
+                    tx.sent.hash,
+                    tx.sent.action,
+                    tx.sent.links.len()
+                );
+            }
+        }
+        Command::Cache { cache_dir, command } => cache::run(cache_dir, command, cli.json).await?,
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    fn parse(args: &[&str]) -> Cli {
+        Cli::try_parse_from(std::iter::once("chainedge-cli").chain(args.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn has_a_consistent_command_line() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn parses_batches() {
+        let cli = parse(&["add", "get@/a", "get\t/b", "--dry-run", "--batch-size", "10", "--json"]);
+        assert!(cli.json);
+        let Command::Add(batch) = cli.command else {
+            panic!("not an add: {:?}", cli.command)
+        };
+        assert_eq!(batch.links, ["get@/a", "get\t/b"]);
+        assert_eq!(batch.batch_size, 10);
+        assert!(batch.dry_run && !batch.no_wait);
+
+        let cli = parse(&["--rpc-url", "http://node:8545", "remove", "-f", "-", "--no-wait"]);
+        assert_eq!(cli.rpc_url, "http://node:8545");
+        let Command::Remove(batch) = cli.command else {
+            panic!("not a remove: {:?}", cli.command)
+        };
+        assert!(batch.links.is_empty());
+        assert_eq!(batch.file, Some(PathBuf::from("-")));
+        assert_eq!(batch.batch_size, 50);
+        assert!(!batch.dry_run && batch.no_wait);
+    }
+
+    #[test]
+    fn parses_queries() {
+        let contract = "0x5FbDB2315678afecb367f032d93F642f64180aa3";
+        let cli = parse(&["index-of", "get@/a", "--contract", contract]);
+        assert_eq!(cli.contract, contract.parse::<Address>().unwrap());
+        assert!(matches!(cli.command, Command::IndexOf { link } if link == "get@/a"));
+
+        assert!(matches!(parse(&["list"]).command, Command::List));
+        assert!(matches!(parse(&["serve-count"]).command, Command::ServeCount));
+        assert!(matches!(parse(&["pending"]).command, Command::Pending));
+        assert_eq!(
+            parse(&["pending", "--journal", "/tmp/j.json"]).journal,
+            PathBuf::from("/tmp/j.json")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        let parse = |args: &[&str]| {
+            Cli::try_parse_from(std::iter::once("chainedge-cli").chain(args.iter().copied()))
+        };
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["add", "--batch-size", "many"]).is_err());
+        assert!(parse(&["list", "--contract", "not-an-address"]).is_err());
+        assert!(parse(&["index-of"]).is_err());
+    }
+
+    #[test]
+    fn reads_links_from_a_file() {
+        let path = std::env::temp_dir().join(format!("chainedge-cli-links-{}", std::process::id()));
+        std::fs::write(&path, "# images\nget@/a.png\n\n  get@/b.png  \n").unwrap();
+
+        let cli = parse(&["add", "get@/c.png", "--file", path.to_str().unwrap()]);
+        let Command::Add(batch) = &cli.command else { unreachable!() };
+        assert_eq!(read_links(batch).unwrap(), ["get@/c.png", "get@/a.png", "get@/b.png"]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
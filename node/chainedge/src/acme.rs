@@ -0,0 +1,709 @@
+use std::{
+    collections::HashMap,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Path as UrlPath, State},
+    response::IntoResponse,
+};
+use http::StatusCode;
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, HttpClient, Identifier,
+    NewAccount, NewOrder, Order, OrderStatus,
+};
+use miette::{miette, Context, IntoDiagnostic, Result};
+use rcgen::{Certificate, CertificateParams, CustomExtension, DistinguishedName};
+use rustls::{sign::CertifiedKey, PrivateKey, RootCertStore};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::{
+    config::Config,
+    tls::{host_name, CertResolver},
+    AppState,
+};
+
+pub const HTTP_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// Automatic certificate issuance for the sites with `tls.acme = true`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AcmeConfig {
+    #[serde(default = "default_directory_url")]
+    pub directory_url: String,
+    #[serde(default)]
+    pub contact: Vec<String>,
+    /// Holds the account credentials and the issued certificates, one directory per domain.
+    #[serde(default = "default_data_dir")]
+    pub data_dir: PathBuf,
+    #[serde(default)]
+    pub challenge: AcmeChallenge,
+    #[serde(default = "default_renew_before_days")]
+    pub renew_before_days: u64,
+    #[serde(default = "default_check_interval_secs")]
+    pub check_interval_secs: u64,
+    /// Extra PEM root trusted for the directory, e.g. the CA of a local Pebble instance.
+    pub root_ca: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub enum AcmeChallenge {
+    #[default]
+    #[serde(rename = "http-01")]
+    Http01,
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+fn default_directory_url() -> String {
+    instant_acme::LetsEncrypt::Production.url().to_owned()
+}
+
+fn default_data_dir() -> PathBuf {
+    PathBuf::from("./tmp/acme")
+}
+
+fn default_renew_before_days() -> u64 {
+    30
+}
+
+fn default_check_interval_secs() -> u64 {
+    12 * 60 * 60
+}
+
+impl AcmeConfig {
+    pub fn cert_path(&self, domain: &str) -> PathBuf {
+        self.data_dir.join(host_name(domain)).join("cert.pem")
+    }
+
+    pub fn key_path(&self, domain: &str) -> PathBuf {
+        self.data_dir.join(host_name(domain)).join("key.pem")
+    }
+
+    fn account_path(&self) -> PathBuf {
+        self.data_dir.join("account.json")
+    }
+}
+
+/// Pending HTTP-01 key authorizations, by token.
+#[derive(Debug, Default)]
+pub struct Challenges {
+    http: RwLock<HashMap<String, String>>,
+}
+
+/// Serves `/.well-known/acme-challenge/:token` on the existing router.
+pub(crate) async fn http_challenge(
+    State(app_state): State<AppState>,
+    UrlPath(token): UrlPath<String>,
+) -> impl IntoResponse {
+    match app_state.acme_challenges.http.read().unwrap().get(&token) {
+        Some(key_authorization) => (StatusCode::OK, key_authorization.clone()),
+        None => (StatusCode::NOT_FOUND, String::new()),
+    }
+}
+
+fn http_client(config: &AcmeConfig) -> Result<Box<dyn HttpClient>> {
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_native_certs::load_native_certs().into_diagnostic()? {
+        let _ = roots.add(&rustls::Certificate(cert.0));
+    }
+    if let Some(root_ca) = &config.root_ca {
+        let file = std::fs::File::open(root_ca)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Could not open ACME root {}", root_ca.display()))?;
+        for cert in rustls_pemfile::certs(&mut BufReader::new(file)).into_diagnostic()? {
+            roots
+                .add(&rustls::Certificate(cert))
+                .map_err(|e| miette!("Invalid ACME root {}: {}", root_ca.display(), e))?;
+        }
+    }
+
+    let tls = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_tls_config(tls)
+        .https_or_http()
+        .enable_http1()
+        .enable_http2()
+        .build();
+
+    Ok(Box::new(hyper::Client::builder().build::<_, hyper::Body>(connector)))
+}
+
+async fn load_or_create_account(config: &AcmeConfig) -> Result<Account> {
+    let path = config.account_path();
+
+    if let Ok(raw) = tokio::fs::read(&path).await {
+        let credentials: AccountCredentials = serde_json::from_slice(&raw).into_diagnostic()?;
+        return Account::from_credentials_and_http(credentials, http_client(config)?)
+            .await
+            .into_diagnostic();
+    }
+
+    let contact: Vec<&str> = config.contact.iter().map(String::as_str).collect();
+    let (account, credentials) = Account::create_with_http(
+        &NewAccount {
+            contact: &contact,
+            terms_of_service_agreed: true,
+            only_return_existing: false,
+        },
+        &config.directory_url,
+        None,
+        http_client(config)?,
+    )
+    .await
+    .into_diagnostic()?;
+
+    write_private(&path, &serde_json::to_vec(&credentials).into_diagnostic()?).await?;
+    info!("Created ACME account at {}", config.directory_url);
+
+    Ok(account)
+}
+
+/// Writes through a temporary file so readers never see a partial file.
+async fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await.into_diagnostic()?;
+    }
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, data).await.into_diagnostic()?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        tokio::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o600))
+            .await
+            .into_diagnostic()?;
+    }
+    tokio::fs::rename(&tmp, path).await.into_diagnostic()
+}
+
+/// Seconds since the epoch at which the first certificate in a PEM file expires.
+fn not_after(cert: &Path) -> Option<u64> {
+    let file = std::fs::File::open(cert).ok()?;
+    let der = rustls_pemfile::certs(&mut BufReader::new(file))
+        .ok()?
+        .into_iter()
+        .next()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(&der).ok()?;
+    u64::try_from(cert.validity().not_after.timestamp()).ok()
+}
+
+fn needs_renewal(config: &AcmeConfig, domain: &str) -> bool {
+    let Some(not_after) = not_after(&config.cert_path(domain)) else {
+        return true;
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    not_after.saturating_sub(now) < config.renew_before_days * 24 * 60 * 60
+}
+
+fn alpn_challenge_cert(domain: &str, digest: &[u8]) -> Result<CertifiedKey> {
+    let mut params = CertificateParams::new(vec![domain.to_owned()]);
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(digest)];
+    let cert = Certificate::from_params(params).into_diagnostic()?;
+
+    let key = rustls::sign::any_supported_type(&PrivateKey(cert.serialize_private_key_der()))
+        .map_err(|_| miette!("Unsupported challenge key"))?;
+    Ok(CertifiedKey::new(
+        vec![rustls::Certificate(cert.serialize_der().into_diagnostic()?)],
+        key,
+    ))
+}
+
+/// Answers the pending authorizations of `order` and waits for it to be validated.
+/// The HTTP-01 tokens it publishes are pushed onto `tokens`, even when it fails.
+async fn validate(
+    config: &AcmeConfig,
+    order: &mut Order,
+    domain: &str,
+    challenges: &Challenges,
+    resolver: &CertResolver,
+    tokens: &mut Vec<String>,
+) -> Result<()> {
+    let wanted = match config.challenge {
+        AcmeChallenge::Http01 => ChallengeType::Http01,
+        AcmeChallenge::TlsAlpn01 => ChallengeType::TlsAlpn01,
+    };
+
+    for authorization in order.authorizations().await.into_diagnostic()? {
+        match authorization.status {
+            AuthorizationStatus::Pending => {}
+            AuthorizationStatus::Valid => continue,
+            status => return Err(miette!("Authorization for {} is {:?}", domain, status)),
+        }
+
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|c| c.r#type == wanted)
+            .ok_or_else(|| miette!("No {:?} challenge offered for {}", wanted, domain))?;
+        let key_authorization = order.key_authorization(challenge);
+
+        match wanted {
+            ChallengeType::TlsAlpn01 => resolver.set_alpn_challenge(
+                domain,
+                alpn_challenge_cert(domain, key_authorization.digest().as_ref())?,
+            ),
+            _ => {
+                challenges
+                    .http
+                    .write()
+                    .unwrap()
+                    .insert(challenge.token.clone(), key_authorization.as_str().to_owned());
+                tokens.push(challenge.token.clone());
+            }
+        }
+
+        order
+            .set_challenge_ready(&challenge.url)
+            .await
+            .into_diagnostic()?;
+    }
+
+    let mut delay = Duration::from_millis(500);
+    for _ in 0..10 {
+        tokio::time::sleep(delay).await;
+        let state = order.refresh().await.into_diagnostic()?;
+        match state.status {
+            OrderStatus::Ready | OrderStatus::Valid => return Ok(()),
+            OrderStatus::Invalid => {
+                return Err(miette!("Order for {} is invalid: {:?}", domain, state.error))
+            }
+            _ => delay = (delay * 2).min(Duration::from_secs(10)),
+        }
+    }
+
+    Err(miette!("Order for {} was not validated in time", domain))
+}
+
+async fn issue(
+    config: &AcmeConfig,
+    account: &Account,
+    domain: &str,
+    challenges: &Challenges,
+    resolver: &CertResolver,
+) -> Result<()> {
+    let identifier = Identifier::Dns(domain.to_owned());
+    let mut order = account
+        .new_order(&NewOrder {
+            identifiers: &[identifier],
+        })
+        .await
+        .into_diagnostic()?;
+
+    let mut tokens = Vec::new();
+    let validated = validate(config, &mut order, domain, challenges, resolver, &mut tokens).await;
+
+    // whatever the outcome, nothing is left to answer for this order
+    {
+        let mut http = challenges.http.write().unwrap();
+        for token in tokens {
+            http.remove(&token);
+        }
+    }
+    resolver.clear_alpn_challenge(domain);
+    validated?;
+
+    let mut params = CertificateParams::new(vec![domain.to_owned()]);
+    params.distinguished_name = DistinguishedName::new();
+    let key = Certificate::from_params(params).into_diagnostic()?;
+    order
+        .finalize(&key.serialize_request_der().into_diagnostic()?)
+        .await
+        .into_diagnostic()?;
+
+    let mut chain = None;
+    for _ in 0..10 {
+        if let Some(pem) = order.certificate().await.into_diagnostic()? {
+            chain = Some(pem);
+            break;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    let chain = chain.ok_or_else(|| miette!("Certificate for {} was not issued in time", domain))?;
+
+    // the key first, so a reload never pairs the new chain with the old key
+    write_private(&config.key_path(domain), key.serialize_private_key_pem().as_bytes()).await?;
+    write_private(&config.cert_path(domain), chain.as_bytes()).await?;
+
+    Ok(())
+}
+
+/// Issues missing certificates and renews the ones about to expire, then hands them
+/// to the resolver.
+pub fn start_renewal_thread(
+    config: Arc<Config>,
+    challenges: Arc<Challenges>,
+    resolver: Arc<CertResolver>,
+) -> Option<tokio::task::JoinHandle<()>> {
+    let acme = config.acme.clone()?;
+    let domains: Vec<String> = config
+        .sites
+        .iter()
+        .filter(|s| s.tls.as_ref().is_some_and(|t| t.acme))
+        .map(|s| host_name(&s.front_domain))
+        .collect();
+    if domains.is_empty() {
+        return None;
+    }
+
+    Some(tokio::spawn(async move {
+        loop {
+            let mut failed = false;
+            let due: Vec<&String> = domains
+                .iter()
+                .filter(|d| needs_renewal(&acme, d))
+                .collect();
+
+            if !due.is_empty() {
+                match load_or_create_account(&acme).await {
+                    Ok(account) => {
+                        for domain in due {
+                            info!("Requesting certificate for {}", domain);
+                            match issue(&acme, &account, domain, &challenges, &resolver).await {
+                                Ok(()) => info!("Issued certificate for {}", domain),
+                                Err(e) => {
+                                    failed = true;
+                                    error!("Could not issue certificate for {}: {}", domain, e)
+                                }
+                            }
+                        }
+                        let resolver = resolver.clone();
+                        let _ = tokio::task::spawn_blocking(move || resolver.reload_changed()).await;
+                    }
+                    Err(e) => {
+                        failed = true;
+                        error!("Could not load ACME account: {}", e)
+                    }
+                }
+            }
+
+            // failures are retried sooner than the regular check
+            let interval = match failed {
+                true => acme.check_interval_secs.min(5 * 60),
+                false => acme.check_interval_secs,
+            };
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    };
+
+    use axum::{
+        response::Response,
+        routing::{get, post},
+        Json, Router,
+    };
+    use http::{
+        header::{CONTENT_TYPE, LOCATION},
+        HeaderValue,
+    };
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    const DOMAIN: &str = "chainedge.test";
+
+    fn test_config(name: &str, directory_url: String, challenge: AcmeChallenge) -> AcmeConfig {
+        let data_dir =
+            std::env::temp_dir().join(format!("chainedge-acme-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&data_dir);
+
+        AcmeConfig {
+            directory_url,
+            contact: Vec::new(),
+            data_dir,
+            challenge,
+            renew_before_days: 30,
+            check_interval_secs: 60,
+            root_ca: None,
+        }
+    }
+
+    /// An ACME directory in the test process. It does not check signatures. It validates
+    /// a challenge only if the node published it before declaring it ready.
+    struct FakeDirectory {
+        base: String,
+        /// Fails the order even when its challenge was answered.
+        reject: bool,
+        challenges: Arc<Challenges>,
+        resolver: Arc<CertResolver>,
+        accounts: AtomicUsize,
+        status: Mutex<&'static str>,
+        /// The challenge types that were published when the node declared them ready.
+        answered: Mutex<Vec<String>>,
+    }
+
+    fn token(challenge_type: &str) -> String {
+        format!("{}-token", challenge_type)
+    }
+
+    /// Every response carries the nonce for the next request.
+    fn reply(status: StatusCode, location: Option<String>, body: Value) -> Response {
+        let mut response = (status, Json(body)).into_response();
+        response
+            .headers_mut()
+            .insert("replay-nonce", HeaderValue::from_static("fake-nonce"));
+        if let Some(location) = location {
+            response
+                .headers_mut()
+                .insert(LOCATION, HeaderValue::from_str(&location).unwrap());
+        }
+        response
+    }
+
+    impl FakeDirectory {
+        async fn start(
+            reject: bool,
+            challenges: Arc<Challenges>,
+            resolver: Arc<CertResolver>,
+        ) -> Arc<FakeDirectory> {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let fake = Arc::new(FakeDirectory {
+                base: format!("http://{}", listener.local_addr().unwrap()),
+                reject,
+                challenges,
+                resolver,
+                accounts: AtomicUsize::new(0),
+                status: Mutex::new("pending"),
+                answered: Mutex::new(Vec::new()),
+            });
+
+            let app = Router::new()
+                .route("/dir", get(directory))
+                .route("/nonce", get(nonce))
+                .route("/account", post(new_account))
+                .route("/new-order", post(new_order))
+                .route("/order", post(order))
+                .route("/authz", post(authorization))
+                .route("/challenge/:type", post(challenge_ready))
+                .route("/finalize", post(finalize))
+                .route("/cert", post(certificate))
+                .with_state(fake.clone());
+            tokio::spawn(
+                axum::Server::from_tcp(listener)
+                    .unwrap()
+                    .serve(app.into_make_service()),
+            );
+            fake
+        }
+
+        fn directory_url(&self) -> String {
+            format!("{}/dir", self.base)
+        }
+
+        fn order(&self) -> Value {
+            let status = *self.status.lock().unwrap();
+            let mut order = json!({
+                "status": status,
+                "authorizations": [format!("{}/authz", self.base)],
+                "finalize": format!("{}/finalize", self.base),
+            });
+            if status == "valid" {
+                order["certificate"] = json!(format!("{}/cert", self.base));
+            }
+            order
+        }
+
+        fn challenge(&self, challenge_type: &str, status: &str) -> Value {
+            json!({
+                "type": challenge_type,
+                "url": format!("{}/challenge/{}", self.base, challenge_type),
+                "token": token(challenge_type),
+                "status": status,
+            })
+        }
+    }
+
+    async fn directory(State(fake): State<Arc<FakeDirectory>>) -> Response {
+        reply(
+            StatusCode::OK,
+            None,
+            json!({
+                "newNonce": format!("{}/nonce", fake.base),
+                "newAccount": format!("{}/account", fake.base),
+                "newOrder": format!("{}/new-order", fake.base),
+            }),
+        )
+    }
+
+    async fn nonce() -> Response {
+        reply(StatusCode::OK, None, Value::Null)
+    }
+
+    async fn new_account(State(fake): State<Arc<FakeDirectory>>) -> Response {
+        fake.accounts.fetch_add(1, Ordering::SeqCst);
+        reply(
+            StatusCode::CREATED,
+            Some(format!("{}/account/1", fake.base)),
+            json!({ "status": "valid" }),
+        )
+    }
+
+    async fn new_order(State(fake): State<Arc<FakeDirectory>>) -> Response {
+        *fake.status.lock().unwrap() = "pending";
+        reply(StatusCode::CREATED, Some(format!("{}/order", fake.base)), fake.order())
+    }
+
+    async fn order(State(fake): State<Arc<FakeDirectory>>) -> Response {
+        reply(StatusCode::OK, None, fake.order())
+    }
+
+    async fn authorization(State(fake): State<Arc<FakeDirectory>>) -> Response {
+        reply(
+            StatusCode::OK,
+            None,
+            json!({
+                "identifier": { "type": "dns", "value": DOMAIN },
+                "status": "pending",
+                "challenges": [
+                    fake.challenge("http-01", "pending"),
+                    fake.challenge("tls-alpn-01", "pending"),
+                ],
+            }),
+        )
+    }
+
+    async fn challenge_ready(
+        State(fake): State<Arc<FakeDirectory>>,
+        UrlPath(challenge_type): UrlPath<String>,
+    ) -> Response {
+        let published = match challenge_type.as_str() {
+            "http-01" => fake
+                .challenges
+                .http
+                .read()
+                .unwrap()
+                .get(&token("http-01"))
+                .is_some_and(|k| k.starts_with(&format!("{}.", token("http-01")))),
+            _ => fake.resolver.alpn_challenge(DOMAIN).is_some(),
+        };
+        if published {
+            fake.answered.lock().unwrap().push(challenge_type.clone());
+        }
+        *fake.status.lock().unwrap() = match published && !fake.reject {
+            true => "ready",
+            false => "invalid",
+        };
+        reply(StatusCode::OK, None, fake.challenge(&challenge_type, "processing"))
+    }
+
+    async fn finalize(State(fake): State<Arc<FakeDirectory>>) -> Response {
+        *fake.status.lock().unwrap() = "valid";
+        reply(StatusCode::OK, None, fake.order())
+    }
+
+    async fn certificate() -> Response {
+        let cert = Certificate::from_params(CertificateParams::new(vec![DOMAIN.to_owned()]))
+            .unwrap()
+            .serialize_pem()
+            .unwrap();
+        let mut response = (StatusCode::OK, cert).into_response();
+        let headers = response.headers_mut();
+        headers.insert("replay-nonce", HeaderValue::from_static("fake-nonce"));
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/pem-certificate-chain"),
+        );
+        response
+    }
+
+    async fn issue_with(
+        name: &str,
+        challenge: AcmeChallenge,
+        reject: bool,
+    ) -> (AcmeConfig, Arc<FakeDirectory>, Result<()>) {
+        let challenges = Arc::new(Challenges::default());
+        let resolver = Arc::new(CertResolver::default());
+        let fake = FakeDirectory::start(reject, challenges.clone(), resolver.clone()).await;
+        let config = test_config(name, fake.directory_url(), challenge);
+
+        let account = load_or_create_account(&config).await.unwrap();
+        let issued = issue(&config, &account, DOMAIN, &challenges, &resolver).await;
+
+        // nothing is left to answer, whatever the outcome
+        assert!(challenges.http.read().unwrap().is_empty());
+        assert!(resolver.alpn_challenge(DOMAIN).is_none());
+        (config, fake, issued)
+    }
+
+    #[tokio::test]
+    async fn issues_certificates_over_http_01() {
+        let (config, fake, issued) = issue_with("http", AcmeChallenge::Http01, false).await;
+        issued.unwrap();
+
+        assert_eq!(*fake.answered.lock().unwrap(), vec!["http-01".to_owned()]);
+        assert!(config.key_path(DOMAIN).exists());
+        assert!(!needs_renewal(&config, DOMAIN));
+
+        // the stored credentials are reused rather than registering again
+        load_or_create_account(&config).await.unwrap();
+        assert_eq!(fake.accounts.load(Ordering::SeqCst), 1);
+        std::fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn issues_certificates_over_tls_alpn_01() {
+        let (config, fake, issued) = issue_with("alpn", AcmeChallenge::TlsAlpn01, false).await;
+        issued.unwrap();
+
+        assert_eq!(*fake.answered.lock().unwrap(), vec!["tls-alpn-01".to_owned()]);
+        assert!(!needs_renewal(&config, DOMAIN));
+        std::fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn clears_the_challenges_of_failed_orders() {
+        for (name, challenge) in [
+            ("failed-http", AcmeChallenge::Http01),
+            ("failed-alpn", AcmeChallenge::TlsAlpn01),
+        ] {
+            let (config, fake, issued) = issue_with(name, challenge, true).await;
+            assert!(issued.is_err());
+
+            assert_eq!(fake.answered.lock().unwrap().len(), 1);
+            assert!(!config.key_path(DOMAIN).exists());
+            assert!(needs_renewal(&config, DOMAIN));
+            std::fs::remove_dir_all(&config.data_dir).unwrap();
+        }
+    }
+
+    /// Runs against a local Pebble started with `PEBBLE_VA_ALWAYS_VALID=1`, e.g.
+    /// `CHAINEDGE_PEBBLE_URL=https://localhost:14000/dir` and
+    /// `CHAINEDGE_PEBBLE_ROOT=test/certs/pebble.minica.pem`.
+    #[tokio::test]
+    #[ignore = "needs a local Pebble, see CHAINEDGE_PEBBLE_URL"]
+    async fn issues_certificates_from_pebble() {
+        let directory_url =
+            std::env::var("CHAINEDGE_PEBBLE_URL").expect("CHAINEDGE_PEBBLE_URL is not set");
+        let mut config = test_config("pebble", directory_url, AcmeChallenge::Http01);
+        config.root_ca = std::env::var_os("CHAINEDGE_PEBBLE_ROOT").map(PathBuf::from);
+        let challenges = Challenges::default();
+        let resolver = CertResolver::default();
+
+        let account = load_or_create_account(&config).await.unwrap();
+        assert!(config.account_path().exists());
+        assert!(needs_renewal(&config, DOMAIN));
+
+        issue(&config, &account, DOMAIN, &challenges, &resolver).await.unwrap();
+
+        assert!(challenges.http.read().unwrap().is_empty());
+        assert!(config.key_path(DOMAIN).exists());
+        assert!(!needs_renewal(&config, DOMAIN));
+        std::fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+}
@@ -0,0 +1,94 @@
+use async_trait::async_trait;
+use axum::{
+    extract::FromRequestParts,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use http::{request::Parts, StatusCode};
+use serde_json::json;
+
+use crate::{admin::auth::is_authorized, AppState};
+
+pub mod entries;
+pub mod links;
+pub mod purge;
+pub mod snapshot;
+pub mod status;
+
+/// Versioned JSON counterpart of the admin pages, mounted under `/_chainedge/api/v1`.
+pub(crate) fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/entries", get(entries::list).delete(entries::clear))
+        .route("/entry", get(entries::inspect))
+        .route("/purge", post(purge::route))
+        .route("/status", get(status::node))
+        .route("/chain", get(status::chain))
+        .route("/config", get(status::config))
+        .route("/links", get(links::diff).post(links::submit))
+        .route("/transactions", get(links::transactions))
+}
+
+/// Error body of every API endpoint: `{"error": {"code": ..., "message": ...}}`.
+#[derive(Debug)]
+pub(crate) struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    pub(crate) fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "bad_request", message)
+    }
+
+    pub(crate) fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+}
+
+impl From<miette::Report> for ApiError {
+    fn from(err: miette::Report) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", err.to_string())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = json!({
+            "error": {
+                "code": self.code,
+                "message": self.message,
+            }
+        });
+        (self.status, Json(body)).into_response()
+    }
+}
+
+/// Extracting this rejects requests without the admin bearer token.
+pub(crate) struct Authorized;
+
+#[async_trait]
+impl FromRequestParts<AppState> for Authorized {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, ApiError> {
+        if is_authorized(&parts.headers, state) {
+            Ok(Authorized)
+        } else {
+            Err(ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "unauthorized",
+                "Missing or wrong admin bearer token",
+            ))
+        }
+    }
+}
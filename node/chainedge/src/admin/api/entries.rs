@@ -0,0 +1,209 @@
+use std::{collections::BTreeMap, time::SystemTime};
+
+use axum::{extract::Query, Json};
+use cacache::Metadata;
+use http::HeaderMap;
+use miette::IntoDiagnostic;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    admin::{
+        api::{ApiError, Authorized},
+        redact,
+    },
+    compression, hits, integrity, keying, memory, policy_from_cached, read_cached, CACHE_DIR,
+};
+
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 1000;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ListQuery {
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+    /// Only keys whose url (the key without its method) starts with this.
+    prefix: Option<String>,
+    /// Only keys containing this.
+    contains: Option<String>,
+    method: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct EntrySummary {
+    pub(crate) key: String,
+    pub(crate) method: String,
+    pub(crate) url: String,
+    /// Bytes stored for the entry, headers included.
+    pub(crate) size: usize,
+    pub(crate) stored_at: Option<u64>,
+    pub(crate) age_secs: Option<u64>,
+    pub(crate) ttl_secs: Option<u64>,
+    pub(crate) status: Option<u16>,
+    pub(crate) hits: u64,
+    /// Set instead of the fields read from the entry when it can not be read.
+    pub(crate) error: Option<String>,
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+pub(crate) fn headers_json(headers: &HeaderMap) -> BTreeMap<String, Vec<String>> {
+    let mut map: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (name, value) in headers {
+        map.entry(name.to_string())
+            .or_default()
+            .push(String::from_utf8_lossy(value.as_bytes()).into_owned());
+    }
+    map
+}
+
+pub(crate) async fn summarize(metadata: &Metadata) -> EntrySummary {
+    let (method, url) = metadata
+        .key
+        .split_once('\t')
+        .unwrap_or(("", metadata.key.as_str()));
+    let mut summary = EntrySummary {
+        key: metadata.key.clone(),
+        method: method.to_owned(),
+        url: url.to_owned(),
+        size: metadata.size,
+        stored_at: None,
+        age_secs: None,
+        ttl_secs: None,
+        status: None,
+        hits: hits::get(&metadata.key),
+        error: None,
+    };
+
+    let cached = match read_cached(&metadata.key).await {
+        Ok(cached) => cached,
+        Err(e) => {
+            summary.error = Some(e.to_string());
+            return summary;
+        }
+    };
+    let cached_at = cached.cached_at;
+    let now = SystemTime::now();
+    match policy_from_cached(cached) {
+        Ok((policy, response, _)) => {
+            summary.stored_at = Some(unix_secs(cached_at));
+            summary.age_secs = Some(now.duration_since(cached_at).unwrap_or_default().as_secs());
+            summary.ttl_secs = Some(policy.time_to_live(now).as_secs());
+            summary.status = Some(response.status().as_u16());
+        }
+        Err(e) => summary.error = Some(e.to_string()),
+    }
+
+    summary
+}
+
+pub(crate) async fn list(
+    _: Authorized,
+    Query(query): Query<ListQuery>,
+) -> Result<Json<Value>, ApiError> {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    if limit == 0 || limit > MAX_LIMIT {
+        return Err(ApiError::bad_request(format!(
+            "limit must be between 1 and {}",
+            MAX_LIMIT
+        )));
+    }
+
+    let mut entries: Vec<Metadata> = tokio::task::spawn_blocking(|| {
+        cacache::list_sync(CACHE_DIR.as_str())
+            .filter_map(|m| m.ok())
+            .filter(|m| !compression::is_variant_key(&m.key))
+            .collect()
+    })
+    .await
+    .into_diagnostic()?;
+
+    entries.retain(|m| {
+        let (method, url) = m.key.split_once('\t').unwrap_or(("", m.key.as_str()));
+        query.method.as_ref().is_none_or(|q| q.eq_ignore_ascii_case(method))
+            && query.prefix.as_ref().is_none_or(|p| url.starts_with(p.as_str()))
+            && query.contains.as_ref().is_none_or(|c| m.key.contains(c.as_str()))
+    });
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
+
+    let mut page = Vec::new();
+    for metadata in entries.iter().skip(query.offset).take(limit) {
+        page.push(summarize(metadata).await);
+    }
+
+    Ok(Json(json!({
+        "total": entries.len(),
+        "offset": query.offset,
+        "limit": limit,
+        "entries": page,
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct EntryQuery {
+    key: String,
+}
+
+pub(crate) async fn inspect(
+    _: Authorized,
+    Query(query): Query<EntryQuery>,
+) -> Result<Json<Value>, ApiError> {
+    let metadata = cacache::metadata(CACHE_DIR.as_str(), &query.key)
+        .await
+        .into_diagnostic()?
+        .filter(|_| !compression::is_variant_key(&query.key))
+        .ok_or_else(|| ApiError::not_found(format!("No entry for {:?}", query.key)))?;
+
+    let cached = read_cached(&query.key).await.map_err(|e| {
+        ApiError::new(
+            http::StatusCode::INTERNAL_SERVER_ERROR,
+            "corrupt_entry",
+            e.to_string(),
+        )
+    })?;
+    let request = json!({
+        "method": cached.request.method.as_str(),
+        "uri": cached.request.uri.to_string(),
+        "headers": headers_json(&redact(&cached.request.headers)),
+    });
+    let response_headers = headers_json(&redact(&cached.response.headers));
+
+    let variants: Vec<String> = {
+        let prefix = format!("{}\te:", query.key);
+        tokio::task::spawn_blocking(move || {
+            cacache::list_sync(CACHE_DIR.as_str())
+                .filter_map(|m| m.ok())
+                .filter_map(|m| m.key.strip_prefix(&prefix).map(str::to_owned))
+                .collect()
+        })
+        .await
+        .into_diagnostic()?
+    };
+
+    let summary = summarize(&metadata).await;
+
+    Ok(Json(json!({
+        "entry": summary,
+        "content_integrity": metadata.integrity.to_string(),
+        "pinned_integrity": integrity::expected(keying::base_key(&query.key)).map(|sri| sri.to_string()),
+        "compressed_variants": variants,
+        "in_memory": memory::contains(&query.key),
+        "request": request,
+        "response": {
+            "status": summary.status,
+            "headers": response_headers,
+        },
+    })))
+}
+
+/// Same as the `Clear FS` action of the admin page.
+pub(crate) async fn clear(_: Authorized) -> Result<Json<Value>, ApiError> {
+    cacache::clear(CACHE_DIR.as_str()).await.into_diagnostic()?;
+    memory::clear();
+    Ok(Json(json!({ "cleared": true })))
+}
@@ -0,0 +1,69 @@
+use axum::{extract::State, Json};
+use http::StatusCode;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    admin::api::{ApiError, Authorized},
+    cdn_list::{self, ListAction},
+    AppState,
+};
+
+fn default_batch_size() -> usize {
+    50
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct LinksRequest {
+    action: ListAction,
+    links: Vec<String>,
+    #[serde(default = "default_batch_size")]
+    batch_size: usize,
+}
+
+/// The on-chain list next to the local cache.
+pub(crate) async fn diff(
+    _: Authorized,
+    State(state): State<AppState>,
+) -> Result<Json<cdn_list::ListDiff>, ApiError> {
+    Ok(Json(cdn_list::diff(state.chain.as_ref(), &state.config).await?))
+}
+
+pub(crate) async fn submit(
+    _: Authorized,
+    State(state): State<AppState>,
+    Json(request): Json<LinksRequest>,
+) -> Result<(StatusCode, Json<Value>), ApiError> {
+    if request.batch_size == 0 {
+        return Err(ApiError::bad_request("batch_size must be at least 1"));
+    }
+    let listed = state.chain.cdn_list().await.map_err(|e| {
+        ApiError::new(StatusCode::BAD_GATEWAY, "chain_unavailable", e.to_string())
+    })?;
+    // an invalid link is the caller's mistake, checked before anything is sent
+    let plan = cdn_list::plan(request.action, &listed, request.links)
+        .map_err(|e| ApiError::bad_request(e.to_string()))?;
+
+    let sent = cdn_list::send(&state.chain, request.action, plan, request.batch_size).await?;
+
+    // the transactions sent before a batch failed are on their way, so they are listed too
+    let mut body = json!({
+        "links": sent.plan.links,
+        "transactions": sent.submitted,
+    });
+    let status = match sent.error {
+        Some(e) => {
+            body["error"] = json!({
+                "code": "chain_unavailable",
+                "message": e.to_string(),
+            });
+            StatusCode::BAD_GATEWAY
+        }
+        None => StatusCode::OK,
+    };
+    Ok((status, Json(body)))
+}
+
+pub(crate) async fn transactions(_: Authorized) -> Json<Vec<cdn_list::TrackedTransaction>> {
+    Json(cdn_list::transactions())
+}
@@ -0,0 +1,17 @@
+use axum::Json;
+
+use crate::{
+    admin::api::{ApiError, Authorized},
+    purge::{self, PurgeRequest, PurgeResult, PurgeTarget},
+};
+
+pub(crate) async fn route(
+    _: Authorized,
+    Json(request): Json<PurgeRequest>,
+) -> Result<Json<PurgeResult>, ApiError> {
+    if let PurgeTarget::Regex(pattern) = &request.target {
+        regex::Regex::new(pattern).map_err(|e| ApiError::bad_request(e.to_string()))?;
+    }
+
+    Ok(Json(purge::purge(&request).await?))
+}
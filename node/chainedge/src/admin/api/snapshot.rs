@@ -0,0 +1,137 @@
+use std::io::{self, BufWriter, Read, Write};
+
+use axum::{
+    body::{Bytes, StreamBody},
+    extract::{BodyStream, Query, State},
+    response::IntoResponse,
+    Json,
+};
+use futures::StreamExt;
+use http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use miette::IntoDiagnostic;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+
+use crate::{
+    admin::api::{ApiError, Authorized},
+    compression, integrity, keying, memory,
+    snapshot::{self, ImportReport},
+    AppState, CACHE_DIR,
+};
+
+/// Snapshots are written and read in chunks of this size.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Hands the chunks written by a blocking export to the response body.
+struct ChannelWriter(mpsc::Sender<io::Result<Bytes>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Snapshot download closed"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Hands the chunks of a request body to a blocking import.
+struct ChannelReader {
+    rx: mpsc::Receiver<io::Result<Bytes>>,
+    chunk: Bytes,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.rx.blocking_recv() {
+                Some(chunk) => self.chunk = chunk?,
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk.split_to(n));
+        Ok(n)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ExportQuery {
+    /// Only entries whose url (the key without its method) starts with this.
+    prefix: Option<String>,
+}
+
+/// Streams a snapshot of the fresh entries, as written by `chainedge-cli cache export`.
+pub(crate) async fn export(_: Authorized, Query(query): Query<ExportQuery>) -> impl IntoResponse {
+    let (tx, rx) = mpsc::channel(4);
+    tokio::task::spawn_blocking(move || {
+        let out = BufWriter::with_capacity(CHUNK_SIZE, ChannelWriter(tx.clone()));
+        if let Err(e) = snapshot::export(&CACHE_DIR, query.prefix.as_deref(), out) {
+            // Ends the body with an error, so the client does not take it for a whole snapshot
+            let _ = tx.blocking_send(Err(io::Error::other(e.to_string())));
+        }
+    });
+
+    let body = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    (
+        [
+            (CONTENT_TYPE, "application/octet-stream"),
+            (CONTENT_DISPOSITION, "attachment; filename=\"chainedge.snapshot\""),
+        ],
+        StreamBody::new(body),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ImportQuery {
+    /// Replace entries the cache already has.
+    #[serde(default)]
+    overwrite: bool,
+}
+
+/// Stores the fresh entries of a snapshot sent as the request body. Entries of sites this
+/// node does not serve, and entries not matching the body pinned for their link, are refused.
+pub(crate) async fn import(
+    _: Authorized,
+    State(state): State<AppState>,
+    Query(query): Query<ImportQuery>,
+    mut body: BodyStream,
+) -> Result<Json<ImportReport>, ApiError> {
+    let (tx, rx) = mpsc::channel(4);
+    let config = state.config.clone();
+    let import = tokio::task::spawn_blocking(move || {
+        let input = ChannelReader { rx, chunk: Bytes::new() };
+        snapshot::import(&CACHE_DIR, input, query.overwrite, |key, body| {
+            // only exact front domains, a host match without the port could take the entries
+            // of another node; pins cover every variant of a link, so they are looked up by the base key
+            let front_domain = keying::front_domain(key);
+            config.sites.iter().any(|site| site.front_domain.eq_ignore_ascii_case(front_domain))
+                && integrity::expected(keying::base_key(key))
+                    .is_none_or(|sri| sri.check(body).is_ok())
+        })
+    });
+
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| io::Error::other(e.to_string()));
+        if tx.send(chunk).await.is_err() {
+            // The import stopped early, its error tells why
+            break;
+        }
+    }
+    drop(tx);
+
+    let report = import
+        .await
+        .into_diagnostic()?
+        .map_err(|e| ApiError::bad_request(format!("{:#}", e)))?;
+    for key in &report.imported {
+        memory::invalidate(key);
+        compression::invalidate(key).await;
+    }
+    Ok(Json(report))
+}
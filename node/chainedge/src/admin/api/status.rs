@@ -0,0 +1,83 @@
+use std::{sync::atomic::Ordering, time::SystemTime};
+
+use axum::{extract::State, Json};
+use miette::IntoDiagnostic;
+use serde_json::{json, Value};
+
+use crate::{
+    admin::{
+        api::{ApiError, Authorized},
+        redact_url,
+    },
+    compression, envelope, hits, memory, reporter, AppState, CACHE_DIR,
+};
+
+pub(crate) async fn node(
+    _: Authorized,
+    State(state): State<AppState>,
+) -> Result<Json<Value>, ApiError> {
+    let (entries, bytes) = tokio::task::spawn_blocking(|| {
+        cacache::list_sync(CACHE_DIR.as_str())
+            .filter_map(|m| m.ok())
+            .fold((0usize, 0usize), |(entries, bytes), m| {
+                let entries = entries + usize::from(!compression::is_variant_key(&m.key));
+                (entries, bytes + m.size)
+            })
+    })
+    .await
+    .into_diagnostic()?;
+
+    let uptime = SystemTime::now()
+        .duration_since(state.started_at)
+        .unwrap_or_default();
+
+    Ok(Json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "uptime_secs": uptime.as_secs(),
+        "sites": state.config.sites.len(),
+        "tls": state.config.tls.is_some(),
+        "http3": state.config.tls.as_ref().is_some_and(|t| t.http3),
+        "cache": {
+            "entries": entries,
+            "bytes": bytes,
+            "hits": hits::total(),
+            "memory": memory::stats(),
+            "quarantined": envelope::quarantined(CACHE_DIR.as_str()).len(),
+        },
+        "unreported_served_bytes": state.accumulated_cnt.load(Ordering::SeqCst),
+        "reporter": reporter::status(),
+    })))
+}
+
+/// Live view of the contract. An unreachable RPC endpoint is reported, not failed on.
+pub(crate) async fn chain(
+    _: Authorized,
+    State(state): State<AppState>,
+) -> Result<Json<Value>, ApiError> {
+    let info = state.chain.info();
+    let block_number = state.chain.block_number().await;
+    let serve_count = state.chain.serve_count().await;
+    let error = match (&block_number, &serve_count) {
+        (Err(e), _) => Some(e.to_string()),
+        (_, Err(e)) => Some(e.to_string()),
+        _ => None,
+    };
+
+    Ok(Json(json!({
+        "backend": info.backend,
+        "rpc_url": info.rpc_url.as_deref().map(redact_url),
+        "contract_address": info.contract_address.map(|a| format!("{:?}", a)),
+        "chain_id": info.chain_id,
+        "reachable": error.is_none(),
+        "block_number": block_number.ok(),
+        "serve_count": serve_count.ok().map(|c| c.to_string()),
+        "error": error,
+    })))
+}
+
+/// The config the node runs with, without the API key of the RPC URL.
+pub(crate) async fn config(_: Authorized, State(state): State<AppState>) -> Json<Value> {
+    let mut config = json!(state.config.as_ref());
+    config["chain"]["rpc_url"] = json!(redact_url(&state.config.chain.rpc_url));
+    Json(config)
+}
@@ -1,12 +1,57 @@
+use async_trait::async_trait;
 use axum::{
-    extract::{State},
+    extract::{FromRequestParts, State},
     response::{IntoResponse, Redirect},
     Form,
 };
+use http::{header::AUTHORIZATION, request::Parts, HeaderMap};
 use maud::html;
 use serde::Deserialize;
+use tower_cookies::{cookie::SameSite, Cookie, Cookies};
+
 use crate::AppState;
 
+/// Private (encrypted) cookie marking a browser that logged in with the admin password.
+const SESSION_COOKIE: &str = "chainedge_admin";
+
+/// Programmatic admin requests carry the admin password as `Authorization: Bearer <password>`.
+pub(crate) fn is_authorized(headers: &HeaderMap, state: &AppState) -> bool {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|token| token.trim() == state.admin_password)
+}
+
+/// Admin pages that act on-chain require a login through `/_chainedge/auth`.
+pub(crate) fn has_session(cookies: &Cookies, state: &AppState) -> bool {
+    cookies
+        .private(&state.cookie_key)
+        .get(SESSION_COOKIE)
+        .is_some()
+}
+
+/// Extracting this sends browsers without an admin session (or bearer token) to the login.
+pub(crate) struct AdminSession;
+
+#[async_trait]
+impl FromRequestParts<AppState> for AdminSession {
+    type Rejection = Redirect;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Redirect> {
+        if is_authorized(&parts.headers, state) {
+            return Ok(AdminSession);
+        }
+        let cookies = Cookies::from_request_parts(parts, state)
+            .await
+            .map_err(|_| Redirect::to("/_chainedge/auth"))?;
+        match has_session(&cookies, state) {
+            true => Ok(AdminSession),
+            false => Err(Redirect::to("/_chainedge/auth")),
+        }
+    }
+}
+
 pub(crate) async fn get(State(_app_state): State<AppState>) -> impl IntoResponse {
     html! {
       form method="post" action="/_chainedge/auth" {
@@ -24,11 +69,19 @@ pub(crate) struct FormState {
 
 pub(crate) async fn post(
     State(state): State<AppState>,
+    cookies: Cookies,
     Form(form): Form<FormState>,
 ) -> impl IntoResponse {
     if form.password != state.admin_password {
-        return Redirect::to("/_chainedge/list");
+        return Redirect::to("/_chainedge/auth");
     }
-    
+
+    let session = Cookie::build(SESSION_COOKIE, "admin")
+        .path("/_chainedge")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .finish();
+    cookies.private(&state.cookie_key).add(session);
+
     Redirect::to("/_chainedge/list")
 }
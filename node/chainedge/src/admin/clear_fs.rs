@@ -1,14 +1,14 @@
 use axum::response::{IntoResponse, Redirect};
 use miette::IntoDiagnostic;
 
-use crate::CACHE_DIR;
+use crate::{admin::auth::AdminSession, memory, CACHE_DIR};
 
-
-pub(crate) async fn route() -> Result<impl IntoResponse, String> {
-    cacache::clear(CACHE_DIR)
+pub(crate) async fn route(_: AdminSession) -> Result<impl IntoResponse, String> {
+    cacache::clear(CACHE_DIR.as_str())
         .await
         .into_diagnostic()
         .map_err(|e| e.to_string())?;
+    memory::clear();
 
     Ok(Redirect::to("/_chainedge/list"))
 }
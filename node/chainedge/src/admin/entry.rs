@@ -0,0 +1,248 @@
+use std::time::{Duration, SystemTime};
+
+use axum::{
+    extract::{Query, State},
+    response::{IntoResponse, Redirect},
+    Form,
+};
+use http::{header::CACHE_CONTROL, HeaderMap, StatusCode};
+use http_cache_semantics::CachePolicy;
+use maud::{html, Markup};
+use serde::Deserialize;
+
+use crate::{
+    admin::{auth::AdminSession, format_time, page, query_escape, redact},
+    integrity, keying, policy_from_cached, populate,
+    purge::{self, PurgeRequest, PurgeTarget},
+    read_cached,
+    rules::EDGE_CACHE_CONTROL,
+    AppState, WrappedError,
+};
+
+/// Bytes of the body shown on the entry page.
+const PREVIEW_BYTES: usize = 4096;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct EntryQuery {
+    key: String,
+}
+
+fn headers_table(headers: &HeaderMap) -> Markup {
+    html! {
+        table {
+            @for (name, value) in headers {
+                tr {
+                    th { (name) }
+                    td { (String::from_utf8_lossy(value.as_bytes())) }
+                }
+            }
+        }
+    }
+}
+
+fn body_preview(body: &[u8]) -> Markup {
+    let prefix = &body[..body.len().min(PREVIEW_BYTES)];
+    // a multi-byte character may be cut at the end of the preview
+    let text = match std::str::from_utf8(prefix) {
+        Ok(text) => Some(text),
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&prefix[..e.valid_up_to()]).ok(),
+        Err(_) => None,
+    };
+
+    html! {
+        @match text {
+            Some(text) => {
+                pre { (text) }
+                @if body.len() > prefix.len() {
+                    p { "First " (prefix.len()) " of " (body.len()) " bytes." }
+                }
+            }
+            None => p { "Binary body of " (body.len()) " bytes." },
+        }
+    }
+}
+
+/// Why the proxy would, or would not, answer the next request from this entry.
+fn explain(
+    policy: &CachePolicy,
+    headers: &HeaderMap,
+    edge_cache_control: Option<&str>,
+    cached_at: SystemTime,
+) -> Vec<String> {
+    let now = SystemTime::now();
+    let mut reasons = Vec::new();
+
+    if !policy.is_storable() {
+        reasons.push(
+            "The response is not storable (e.g. no-store or private), a new response replaces it."
+                .to_owned(),
+        );
+    }
+    if cached_at == SystemTime::UNIX_EPOCH {
+        reasons.push("The entry was soft purged.".to_owned());
+    }
+
+    match headers.get(CACHE_CONTROL).and_then(|v| v.to_str().ok()) {
+        Some(cache_control) => {
+            reasons.push(format!("Cache-Control: {}", cache_control));
+            if cache_control
+                .split(',')
+                .any(|d| d.trim().eq_ignore_ascii_case("no-cache"))
+            {
+                reasons.push("no-cache: every request is revalidated with the origin.".to_owned());
+            }
+        }
+        None => reasons.push(
+            "No Cache-Control: freshness comes from Expires, or a heuristic on Last-Modified."
+                .to_owned(),
+        ),
+    }
+
+    if let Some(edge_cache_control) = edge_cache_control {
+        reasons.push(format!(
+            "The edge applies instead, from CDN-Cache-Control, Surrogate-Control or a cache rule: {}",
+            edge_cache_control
+        ));
+    }
+
+    reasons.push(format!(
+        "Age {} s, counting the Age the origin reported.",
+        policy.age(now).as_secs()
+    ));
+
+    let ttl = policy.time_to_live(now);
+    if ttl > Duration::ZERO {
+        reasons.push(format!(
+            "Fresh: served from the cache for another {} s.",
+            ttl.as_secs()
+        ));
+    } else {
+        reasons.push(
+            "Stale: the next request goes to the origin and replaces the entry.".to_owned(),
+        );
+    }
+
+    reasons
+}
+
+/// Purge, soft purge and refresh buttons of an entry.
+pub(crate) fn actions(key: &str) -> Markup {
+    html! {
+        form method="post" action="/_chainedge/entry/purge" {
+            input type="hidden" name="key" value=(key);
+            input type="submit" value="Purge";
+        }
+        form method="post" action="/_chainedge/entry/purge" {
+            input type="hidden" name="key" value=(key);
+            input type="hidden" name="soft" value="true";
+            input type="submit" value="Soft purge";
+        }
+        form method="post" action="/_chainedge/entry/refresh" {
+            input type="hidden" name="key" value=(key);
+            input type="submit" value="Refresh";
+        }
+    }
+}
+
+pub(crate) async fn get(
+    _: AdminSession,
+    Query(query): Query<EntryQuery>,
+) -> Result<impl IntoResponse, WrappedError> {
+    let Ok(cached) = read_cached(&query.key).await else {
+        let resp = page(
+            "Entry",
+            html! { p.error { "No readable entry for " (query.key) } },
+        );
+        return Ok((StatusCode::NOT_FOUND, resp));
+    };
+
+    let cached_at = cached.cached_at;
+    let request_headers = cached.request.headers.clone();
+    let request_line = format!("{} {}", cached.request.method, cached.request.uri);
+    let edge_cache_control = cached
+        .response
+        .headers
+        .get(EDGE_CACHE_CONTROL)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned);
+    let (policy, response, _) = policy_from_cached(cached)?;
+
+    let resp = page(
+        "Entry",
+        html! {
+            table {
+                tr { th { "Key" } td { code { (query.key) } } }
+                tr { th { "Stored" } td { (format_time(cached_at.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs())) } }
+                tr { th { "Status" } td { (response.status()) } }
+                tr { th { "Body" } td { (response.body().len()) " bytes" } }
+                tr {
+                    th { "Pinned integrity" }
+                    td { (integrity::expected(keying::base_key(&query.key)).map(|sri| sri.to_string()).unwrap_or_else(|| "none".to_owned())) }
+                }
+            }
+
+            h2 { "Policy" }
+            ul {
+                @for reason in explain(&policy, response.headers(), edge_cache_control.as_deref(), cached_at) {
+                    li { (reason) }
+                }
+            }
+
+            h2 { "Actions" }
+            (actions(&query.key))
+
+            h2 { "Request" }
+            p { code { (request_line) } }
+            (headers_table(&redact(&request_headers)))
+
+            h2 { "Response headers" }
+            (headers_table(&redact(response.headers())))
+
+            h2 { "Body" }
+            (body_preview(response.body()))
+        },
+    );
+
+    Ok((StatusCode::OK, resp))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct PurgeForm {
+    key: String,
+    #[serde(default)]
+    soft: bool,
+}
+
+pub(crate) async fn purge(
+    _: AdminSession,
+    Form(form): Form<PurgeForm>,
+) -> Result<impl IntoResponse, WrappedError> {
+    purge::purge(&PurgeRequest {
+        target: PurgeTarget::Key(form.key.clone()),
+        soft: form.soft,
+    })
+    .await?;
+
+    Ok(match form.soft {
+        true => Redirect::to(&format!("/_chainedge/entry?key={}", query_escape(&form.key))),
+        false => Redirect::to("/_chainedge/list"),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct RefreshForm {
+    key: String,
+}
+
+pub(crate) async fn refresh(
+    _: AdminSession,
+    State(state): State<AppState>,
+    Form(form): Form<RefreshForm>,
+) -> Result<impl IntoResponse, WrappedError> {
+    populate::refresh(&form.key, &state.config).await?;
+
+    Ok(Redirect::to(&format!(
+        "/_chainedge/entry?key={}",
+        query_escape(&form.key)
+    )))
+}
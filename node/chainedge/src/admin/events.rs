@@ -0,0 +1,28 @@
+use std::convert::Infallible;
+
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures::Stream;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{admin::auth::AdminSession, request_log};
+
+/// Live request log of the dashboard, one `request` event per proxied request.
+pub(crate) async fn route(_: AdminSession) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let stream = futures::stream::unfold(request_log::subscribe(), |mut live| async move {
+        loop {
+            match live.recv().await {
+                Ok(request) => {
+                    let Ok(event) = Event::default().event("request").json_data(&request) else {
+                        continue;
+                    };
+                    return Some((Ok(event), live));
+                }
+                // a slow dashboard skips what it missed
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
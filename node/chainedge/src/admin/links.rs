@@ -0,0 +1,166 @@
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    Form,
+};
+use maud::{html, Markup};
+use serde::Deserialize;
+
+use crate::{
+    admin::{auth::AdminSession, format_time, page, query_escape},
+    cdn_list::{self, ListAction, Plan, TrackedTransaction},
+    AppState,
+};
+
+fn transactions_table(transactions: &[TrackedTransaction]) -> Markup {
+    html! {
+        table {
+            tr { th { "Submitted (UTC)" } th { "Transaction" } th { "Action" } th { "Links" } th { "Status" } th { "Block" } }
+            @for tx in transactions {
+                tr {
+                    td { (format_time(tx.submitted_at)) }
+                    td { code { (format!("{:?}", tx.hash)) } }
+                    td { (tx.action.as_str()) }
+                    td { (tx.links.len()) }
+                    td { (format!("{:?}", tx.status).to_lowercase()) }
+                    td { (tx.block.map(|b| b.to_string()).unwrap_or_default()) }
+                }
+            }
+        }
+    }
+}
+
+fn submit_form() -> Markup {
+    html! {
+        form method="post" action="/_chainedge/links" {
+            p {
+                select name="action" {
+                    option value="add" { "Add" }
+                    option value="remove" { "Remove" }
+                }
+                " links per transaction: "
+                input type="number" name="batch_size" value="50" min="1";
+            }
+            p {
+                textarea name="links" rows="8" cols="80" placeholder="One link per line, e.g. get@/index.html" {}
+            }
+            input type="submit" value="Submit transaction";
+        }
+    }
+}
+
+pub(crate) async fn get(_: AdminSession, State(state): State<AppState>) -> Response {
+    let diff = cdn_list::diff(state.chain.as_ref(), &state.config).await;
+
+    page(
+        "CDN list",
+        html! {
+            h2 { "Change the list" }
+            (submit_form())
+
+            h2 { "Transactions" }
+            (transactions_table(&cdn_list::transactions()))
+
+            @match diff {
+                Ok(diff) => {
+                    h2 { "On chain" }
+                    table {
+                        tr { th { "Link" } th { "Cache key" } th { "Cached" } }
+                        @for listed in &diff.listed {
+                            tr {
+                                td { code { (listed.link) } }
+                                @if let Some(error) = &listed.error {
+                                    td.error colspan="2" { (error) }
+                                } @else {
+                                    td {
+                                        @if let Some(key) = &listed.cache_key {
+                                            a href={ "/_chainedge/entry?key=" (query_escape(key)) } { (key) }
+                                        }
+                                    }
+                                    @if listed.cached {
+                                        td { "yes" }
+                                    } @else {
+                                        td.stale { "missing" }
+                                    }
+                                }
+                            }
+                        }
+                    }
+
+                    h2 { "Cached but not on chain" }
+                    p { (diff.cached_not_listed.len()) " entries were cached from client requests." }
+                    ul {
+                        @for key in &diff.cached_not_listed {
+                            li { a href={ "/_chainedge/entry?key=" (query_escape(key)) } { (key) } }
+                        }
+                    }
+                }
+                Err(e) => p.error { "Could not read the on-chain list: " (e) },
+            }
+        },
+    )
+    .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct LinksForm {
+    action: ListAction,
+    links: String,
+    batch_size: usize,
+}
+
+fn plan_table(plan: &Plan) -> Markup {
+    html! {
+        table {
+            tr { th { "Link" } th { "Status" } th { "Reason" } }
+            @for link in &plan.links {
+                tr {
+                    td { code { (link.link) } }
+                    td { (link.status) }
+                    td { (link.reason.as_deref().unwrap_or_default()) }
+                }
+            }
+        }
+    }
+}
+
+pub(crate) async fn post(
+    _: AdminSession,
+    State(state): State<AppState>,
+    Form(form): Form<LinksForm>,
+) -> Response {
+    let links = form
+        .links
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(str::to_owned)
+        .collect();
+    let result = cdn_list::submit(&state.chain, form.action, links, form.batch_size).await;
+
+    page(
+        "CDN list",
+        html! {
+            @match result {
+                Ok(sent) => {
+                    h2 { "Links" }
+                    (plan_table(&sent.plan))
+                    h2 { "Submitted" }
+                    @if let Some(e) = &sent.error {
+                        pre.error {
+                            "Stopped after " (sent.submitted.len()) " transactions: " (format!("{}", e))
+                        }
+                    }
+                    @if !sent.submitted.is_empty() {
+                        (transactions_table(&sent.submitted))
+                    } @else if sent.error.is_none() {
+                        p { "Nothing to change." }
+                    }
+                }
+                Err(e) => pre.error { (format!("{}", e)) },
+            }
+            p { a href="/_chainedge/links" { "Back to the list" } }
+        },
+    )
+    .into_response()
+}
@@ -1,37 +1,365 @@
-use std::time::SystemTime;
+use std::{sync::atomic::Ordering, time::SystemTime};
 
-use axum::{extract::State, response::IntoResponse};
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+};
 use cacache::Metadata;
 use http::StatusCode;
-use maud::html;
+use maud::{html, Markup, PreEscaped};
 use miette::IntoDiagnostic;
+use serde::Deserialize;
 
-use crate::{get_policy_from_cache, AppState, CACHE_DIR};
+use crate::{
+    admin::{
+        auth::AdminSession,
+        api::entries::{summarize, EntrySummary},
+        entry,
+        format_time, page, query_escape,
+    },
+    compression,
+    config::Config,
+    envelope, hits, memory, reporter, request_log,
+    rules::CacheAction,
+    AppState, CACHE_DIR,
+};
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SortColumn {
+    #[default]
+    Key,
+    Size,
+    Age,
+    Ttl,
+    Hits,
+}
+
+impl SortColumn {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SortColumn::Key => "key",
+            SortColumn::Size => "size",
+            SortColumn::Age => "age",
+            SortColumn::Ttl => "ttl",
+            SortColumn::Hits => "hits",
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct ListQuery {
+    /// Only keys containing this.
+    #[serde(default)]
+    filter: String,
+    #[serde(default)]
+    sort: SortColumn,
+    #[serde(default)]
+    desc: bool,
+}
+
+const LIVE_LOG_SCRIPT: &str = r#"
+const log = document.getElementById('log');
+const source = new EventSource('/_chainedge/events');
+source.addEventListener('request', (e) => {
+  const r = JSON.parse(e.data);
+  const row = log.insertRow(0);
+  const at = new Date(r.at * 1000).toISOString().replace('T', ' ').slice(0, 19);
+  for (const value of [at, r.method, r.host + r.path, r.status, r.cache ?? 'error', r.bytes, r.millis + ' ms']) {
+    row.insertCell().textContent = value;
+  }
+  while (log.rows.length > 100) log.deleteRow(-1);
+});
+"#;
+
+fn sort_link(query: &ListQuery, column: SortColumn, label: &str) -> Markup {
+    let desc = query.sort == column && !query.desc;
+    let arrow = match (query.sort == column, query.desc) {
+        (true, false) => " ▲",
+        (true, true) => " ▼",
+        _ => "",
+    };
+    let href = format!(
+        "/_chainedge/list?filter={}&sort={}&desc={}",
+        query_escape(&query.filter),
+        column.as_str(),
+        desc
+    );
+    html! { a href=(href) { (label) (arrow) } }
+}
+
+fn hit_rate_chart() -> Markup {
+    let rate = hits::rate();
+    let max = rate.iter().map(|b| b.hits + b.misses).max().unwrap_or(0).max(1);
+    let hits: u64 = rate.iter().map(|b| b.hits).sum();
+    let misses: u64 = rate.iter().map(|b| b.misses).sum();
+    let ratio = match hits + misses {
+        0 => "-".to_owned(),
+        total => format!("{:.1}%", hits as f64 * 100.0 / total as f64),
+    };
+
+    const WIDTH: u64 = 8;
+    const HEIGHT: u64 = 100;
+    const BAR_WIDTH: u64 = WIDTH - 1;
+    let svg_width = WIDTH * rate.len() as u64;
+
+    html! {
+        p { "Last hour: " (hits) " hits, " (misses) " misses, hit rate " (ratio) }
+        svg width=(svg_width) height=(HEIGHT) style="background: #f5f5f5" {
+            @for (i, bucket) in rate.iter().enumerate() {
+                @let x = i as u64 * WIDTH;
+                @let hit_height = bucket.hits * HEIGHT / max;
+                @let miss_height = bucket.misses * HEIGHT / max;
+                @let hit_y = HEIGHT - hit_height;
+                @let miss_y = hit_y - miss_height;
+                @let minute = format_time(bucket.minute * 60);
+                rect x=(x) y=(hit_y) width=(BAR_WIDTH) height=(hit_height) fill="#16a34a" {
+                    title { (minute) ": " (bucket.hits) " hits" }
+                }
+                rect x=(x) y=(miss_y) width=(BAR_WIDTH) height=(miss_height) fill="#9ca3af" {
+                    title { (minute) ": " (bucket.misses) " misses" }
+                }
+            }
+        }
+    }
+}
+
+fn reporter_status() -> Markup {
+    let status = reporter::status();
+    html! {
+        table {
+            tr { th { "Reports sent" } td { (status.reports) } }
+            tr {
+                th { "Last report" }
+                td {
+                    @match status.last_report_at {
+                        Some(at) => { (format_time(at)) ", " (status.last_reported_bytes) " bytes" }
+                        None => { "never" }
+                    }
+                }
+            }
+            tr { th { "Serve count on chain" } td { (status.total_on_chain.as_deref().unwrap_or("unknown")) } }
+            tr { th { "Failures" } td { (status.failures) } }
+            @if let (Some(error), Some(at)) = (&status.last_error, status.last_error_at) {
+                tr { th { "Last error" } td.error { (format_time(at)) ": " (error) } }
+            }
+        }
+    }
+}
+
+fn entries_table(query: &ListQuery, entries: &[EntrySummary]) -> Markup {
+    html! {
+        form method="get" action="/_chainedge/list" {
+            input type="text" name="filter" value=(query.filter) placeholder="Filter keys";
+            input type="hidden" name="sort" value=(query.sort.as_str());
+            input type="hidden" name="desc" value=(query.desc);
+            input type="submit" value="Filter";
+        }
+        table {
+            tr {
+                th { (sort_link(query, SortColumn::Key, "Key")) }
+                th { "Status" }
+                th { (sort_link(query, SortColumn::Size, "Size")) }
+                th { (sort_link(query, SortColumn::Age, "Age")) }
+                th { (sort_link(query, SortColumn::Ttl, "TTL")) }
+                th { (sort_link(query, SortColumn::Hits, "Hits")) }
+                th { "Actions" }
+            }
+            @for entry in entries {
+                tr {
+                    td {
+                        a href={ "/_chainedge/entry?key=" (query_escape(&entry.key)) } {
+                            (entry.method) " " (entry.url)
+                        }
+                    }
+                    @if let Some(error) = &entry.error {
+                        td.error colspan="3" { "Unreadable: " (error) }
+                    } @else {
+                        td { (entry.status.unwrap_or_default()) }
+                        td { (entry.size) }
+                        td { (entry.age_secs.unwrap_or_default()) " s" }
+                    }
+                    @match entry.ttl_secs {
+                        Some(0) => td.stale { "stale" },
+                        Some(ttl) => td { (ttl) " s" },
+                        None => td { "-" },
+                    }
+                    td { (entry.hits) }
+                    td { (entry::actions(&entry.key)) }
+                }
+            }
+        }
+    }
+}
+
+fn cache_rules(config: &Config) -> Markup {
+    html! {
+        @for site in &config.sites {
+            h3 { (site.front_domain) }
+            @if site.cache_rules.is_empty() {
+                p { "No rules, the origin headers decide." }
+            } @else {
+                table {
+                    tr {
+                        th { "#" } th { "Path" } th { "Methods" } th { "Content types" }
+                        th { "Action" } th { "Edge TTL" } th { "Browser TTL" } th { "Ignores" }
+                    }
+                    @for (i, rule) in site.cache_rules.iter().enumerate() {
+                        tr {
+                            td { ((i + 1)) }
+                            td {
+                                @if let Some(glob) = &rule.path { code { (glob) } " " }
+                                @if let Some(regex) = &rule.path_regex { code { "~ " (regex.0.as_str()) } }
+                                @if rule.path.is_none() && rule.path_regex.is_none() { "any" }
+                            }
+                            td { @if rule.methods.is_empty() { "any" } @else { (rule.methods.join(", ")) } }
+                            td { @if rule.content_types.is_empty() { "any" } @else { (rule.content_types.join(", ")) } }
+                            td {
+                                @match rule.action {
+                                    CacheAction::Origin => "origin headers",
+                                    CacheAction::Cache => "cache",
+                                    CacheAction::Bypass => "bypass",
+                                }
+                            }
+                            td { @match rule.edge_ttl { Some(ttl) => { (ttl) " s" }, None => "origin" } }
+                            td { @match rule.browser_ttl { Some(ttl) => { (ttl) " s" }, None => "origin" } }
+                            td {
+                                @if rule.ignore_set_cookie { "Set-Cookie " }
+                                @if rule.ignore_private { "private" }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn live_log() -> Markup {
+    html! {
+        table {
+            thead {
+                tr {
+                    th { "Time (UTC)" } th { "Method" } th { "URL" } th { "Status" }
+                    th { "Cache" } th { "Bytes" } th { "Duration" }
+                }
+            }
+            tbody id="log" {
+                @for request in request_log::recent().iter().rev() {
+                    tr {
+                        td { (format_time(request.at)) }
+                        td { (request.method) }
+                        td { (request.host) (request.path) }
+                        td { (request.status) }
+                        td {
+                            @match request.cache {
+                                Some(request_log::CacheStatus::Hit) => "hit",
+                                Some(request_log::CacheStatus::Miss) => "miss",
+                                Some(request_log::CacheStatus::Bypass) => "bypass",
+                                Some(request_log::CacheStatus::Revalidated) => "revalidated",
+                                None => "error",
+                            }
+                        }
+                        td { (request.bytes) }
+                        td { (request.millis) " ms" }
+                    }
+                }
+            }
+        }
+        script { (PreEscaped(LIVE_LOG_SCRIPT)) }
+    }
+}
 
 #[axum_macros::debug_handler]
 pub(crate) async fn route(
-    State(_): State<AppState>,
+    _: AdminSession,
+    State(app_state): State<AppState>,
+    Query(query): Query<ListQuery>,
 ) -> Result<impl IntoResponse, String> {
-    let file_system_entries: Result<Vec<Metadata>, _> =
-        tokio::task::spawn_blocking(move || cacache::list_sync(CACHE_DIR).collect())
-            .await
-            .into_diagnostic()
-            .map_err(|e| e.to_string())?;
-    let file_system_entries = file_system_entries.unwrap_or_default();
-
-    let resp = html! {
-        h2 { "Actions" }
-        form method="post" action="/_chainedge/clear_fs" {
-            input type="submit" value="Clear FS";
-        }
+    // an unreadable index line only hides its own entry
+    let file_system_entries: Vec<Metadata> = tokio::task::spawn_blocking(move || {
+        cacache::list_sync(CACHE_DIR.as_str())
+            .filter_map(|m| m.ok())
+            .collect()
+    })
+    .await
+    .into_diagnostic()
+    .map_err(|e| e.to_string())?;
 
-        h2 { "Cached File" }
-        ul {
-            @for entry in file_system_entries {
-                li { (entry.key) " TTL Seconds: " (get_policy_from_cache(&entry.key).await.unwrap().0.time_to_live(SystemTime::now()).as_secs()) }
+    let stored_bytes: usize = file_system_entries.iter().map(|e| e.size).sum();
+    let cached_files: Vec<&Metadata> = file_system_entries
+        .iter()
+        .filter(|e| !compression::is_variant_key(&e.key))
+        .collect();
+    let mut entries = Vec::new();
+    for entry in cached_files
+        .iter()
+        .filter(|e| e.key.contains(&query.filter))
+    {
+        // a corrupt entry is listed as such, it must not take the whole page down
+        entries.push(summarize(entry).await);
+    }
+    entries.sort_by(|a, b| match query.sort {
+        SortColumn::Key => a.key.cmp(&b.key),
+        SortColumn::Size => a.size.cmp(&b.size),
+        SortColumn::Age => a.age_secs.cmp(&b.age_secs),
+        SortColumn::Ttl => a.ttl_secs.cmp(&b.ttl_secs),
+        SortColumn::Hits => a.hits.cmp(&b.hits),
+    });
+    if query.desc {
+        entries.reverse();
+    }
+
+    let uptime = SystemTime::now()
+        .duration_since(app_state.started_at)
+        .unwrap_or_default();
+
+    let memory = memory::stats();
+
+    let resp = page(
+        "Dashboard",
+        html! {
+            h2 { "Node" }
+            table {
+                tr { th { "Uptime" } td { (uptime.as_secs()) " s" } }
+                tr { th { "Cached files" } td { (cached_files.len()) } }
+                tr { th { "Stored bytes" } td { (stored_bytes) } }
+                tr { th { "Hits since start" } td { (hits::total()) } }
+                tr { th { "Quarantined entries" } td { (envelope::quarantined(CACHE_DIR.as_str()).len()) } }
+                tr {
+                    th { "Memory tier" }
+                    td {
+                        (memory.entries) " entries, " (memory.bytes) " / " (memory.max_bytes) " bytes, "
+                        (format!("{:.1}", memory.hit_ratio * 100.0)) "% of lookups hit"
+                    }
+                }
+                tr {
+                    th { "Served bytes not yet reported" }
+                    td { (app_state.accumulated_cnt.load(Ordering::SeqCst)) }
+                }
             }
-        }
-    };
+
+            h2 { "Hit rate" }
+            (hit_rate_chart())
+
+            h2 { "Reporter" }
+            (reporter_status())
+
+            h2 { "Actions" }
+            form method="post" action="/_chainedge/clear_fs" {
+                input type="submit" value="Clear FS";
+            }
+
+            h2 { "Cache rules" }
+            (cache_rules(&app_state.config))
+
+            h2 { "Cached Files" }
+            (entries_table(&query, &entries))
+
+            h2 { "Live requests" }
+            (live_log())
+        },
+    );
 
     Ok((StatusCode::OK, resp))
 }
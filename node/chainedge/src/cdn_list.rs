@@ -0,0 +1,512 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use ethers::types::H256;
+use lazy_static::lazy_static;
+use miette::{miette, IntoDiagnostic, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    chain::{self, ChainBackend},
+    compression,
+    config::Config,
+    keying,
+    link::CacheLink,
+    CACHE_DIR,
+};
+
+/// Submitted transactions kept for the admin pages.
+const TRACKED: usize = 100;
+/// A transaction without a receipt after this long is reported as dropped.
+const DROP_AFTER: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListAction {
+    Add,
+    Remove,
+}
+
+impl ListAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListAction::Add => "add",
+            ListAction::Remove => "remove",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LinkOutcome {
+    pub link: String,
+    /// `queued` or `skipped`.
+    pub status: &'static str,
+    pub reason: Option<String>,
+}
+
+/// What a batch of links turns into against the current on-chain list.
+#[derive(Debug, Clone, Serialize)]
+pub struct Plan {
+    pub links: Vec<LinkOutcome>,
+    /// The on-chain strings to send, without duplicates.
+    pub queued: Vec<String>,
+}
+
+/// Links are removed by their exact on-chain string, so an equivalent spelling
+/// (e.g. the legacy `get@/url` form) is looked up among the listed ones.
+pub fn find_listed<'a>(listed: &'a [String], link: &str) -> Option<&'a String> {
+    listed.iter().find(|l| *l == link).or_else(|| {
+        let parsed = CacheLink::parse(link).ok()?;
+        listed
+            .iter()
+            .find(|l| CacheLink::parse(l).is_ok_and(|l| l == parsed))
+    })
+}
+
+/// Validates `links` and decides which of them change the list. Adding a listed link
+/// or removing one that is not listed is skipped. Any invalid link fails the whole batch.
+pub fn plan(action: ListAction, listed: &[String], links: Vec<String>) -> Result<Plan> {
+    let invalid: Vec<String> = links
+        .iter()
+        .filter_map(|l| CacheLink::parse(l).err().map(|e| format!("{:?}: {}", l, e)))
+        .collect();
+    if !invalid.is_empty() {
+        return Err(miette!("Invalid links:\n{}", invalid.join("\n")));
+    }
+
+    let mut outcomes = Vec::new();
+    let mut queued: Vec<String> = Vec::new();
+    for link in links {
+        let (target, skip_reason) = match action {
+            ListAction::Add => match find_listed(listed, &link) {
+                Some(_) => (None, "already listed"),
+                None => (Some(link.clone()), ""),
+            },
+            ListAction::Remove => match find_listed(listed, &link) {
+                Some(listed) => (Some(listed.clone()), ""),
+                None => (None, "not listed"),
+            },
+        };
+
+        let outcome = match target {
+            Some(target) if find_listed(&queued, &target).is_some() => LinkOutcome {
+                link,
+                status: "skipped",
+                reason: Some("duplicate".to_owned()),
+            },
+            Some(target) => {
+                let reason = (target != link).then(|| format!("listed as {:?}", target));
+                queued.push(target);
+                LinkOutcome {
+                    link,
+                    status: "queued",
+                    reason,
+                }
+            }
+            None => LinkOutcome {
+                link,
+                status: "skipped",
+                reason: Some(skip_reason.to_owned()),
+            },
+        };
+        outcomes.push(outcome);
+    }
+
+    Ok(Plan {
+        links: outcomes,
+        queued,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionStatus {
+    Pending,
+    Mined,
+    Failed,
+    Dropped,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrackedTransaction {
+    pub hash: H256,
+    pub action: ListAction,
+    pub links: Vec<String>,
+    /// Seconds since the unix epoch.
+    pub submitted_at: u64,
+    pub status: TransactionStatus,
+    pub block: Option<u64>,
+}
+
+lazy_static! {
+    static ref TRANSACTIONS: Mutex<Vec<TrackedTransaction>> = Mutex::new(Vec::new());
+}
+
+fn track(transaction: TrackedTransaction) {
+    let mut transactions = TRANSACTIONS.lock().unwrap();
+    if transactions.len() == TRACKED {
+        transactions.remove(0);
+    }
+    transactions.push(transaction);
+}
+
+fn update(hash: H256, status: TransactionStatus, block: Option<u64>) {
+    let mut transactions = TRANSACTIONS.lock().unwrap();
+    if let Some(t) = transactions.iter_mut().find(|t| t.hash == hash) {
+        t.status = status;
+        t.block = block;
+    }
+}
+
+/// Newest first.
+pub fn transactions() -> Vec<TrackedTransaction> {
+    TRANSACTIONS.lock().unwrap().iter().rev().cloned().collect()
+}
+
+/// Follows a submitted transaction until it is mined or given up on.
+fn watch(backend: Arc<dyn ChainBackend>, hash: H256) {
+    tokio::spawn(async move {
+        match chain::wait_for_receipt(backend.as_ref(), hash, DROP_AFTER).await {
+            Some(receipt) => {
+                let status = match receipt.success {
+                    true => TransactionStatus::Mined,
+                    false => TransactionStatus::Failed,
+                };
+                info!("Transaction {:?} {:?}", hash, status);
+                update(hash, status, receipt.block);
+            }
+            None => {
+                warn!("Transaction {:?} not mined, giving up", hash);
+                update(hash, TransactionStatus::Dropped, None);
+            }
+        }
+    });
+}
+
+/// What sending a plan did. Batches are sent in order, so a failed batch stops the
+/// ones after it while the ones before it stay submitted.
+#[derive(Debug)]
+pub struct Sent {
+    pub plan: Plan,
+    pub submitted: Vec<TrackedTransaction>,
+    /// Why the batch after `submitted` could not be sent.
+    pub error: Option<miette::Report>,
+}
+
+/// Plans the batch against the on-chain list and sends the queued links in
+/// transactions of at most `batch_size` links, signed by the node's wallet.
+pub async fn submit(
+    backend: &Arc<dyn ChainBackend>,
+    action: ListAction,
+    links: Vec<String>,
+    batch_size: usize,
+) -> Result<Sent> {
+    let listed = backend.cdn_list().await?;
+    let plan = plan(action, &listed, links)?;
+    send(backend, action, plan, batch_size).await
+}
+
+/// Sends the links `plan` queued, for callers that planned against a list they already fetched.
+pub async fn send(
+    backend: &Arc<dyn ChainBackend>,
+    action: ListAction,
+    plan: Plan,
+    batch_size: usize,
+) -> Result<Sent> {
+    if batch_size == 0 {
+        return Err(miette!("batch size must be at least 1"));
+    }
+
+    let mut submitted = Vec::new();
+    for chunk in plan.queued.chunks(batch_size) {
+        let hash = match backend.change_list(action, chunk.to_vec()).await {
+            Ok(hash) => hash,
+            Err(error) => {
+                warn!("Could not submit {} of {} links: {}", action.as_str(), chunk.len(), error);
+                return Ok(Sent {
+                    plan,
+                    submitted,
+                    error: Some(error),
+                });
+            }
+        };
+        info!("Submitted {} of {} links in {:?}", action.as_str(), chunk.len(), hash);
+
+        let transaction = TrackedTransaction {
+            hash,
+            action,
+            links: chunk.to_vec(),
+            submitted_at: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            status: TransactionStatus::Pending,
+            block: None,
+        };
+        track(transaction.clone());
+        watch(backend.clone(), hash);
+        submitted.push(transaction);
+    }
+
+    Ok(Sent {
+        plan,
+        submitted,
+        error: None,
+    })
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ListedLink {
+    pub link: String,
+    pub cache_key: Option<String>,
+    pub cached: bool,
+    /// Why the link does not map to a cache key on this node.
+    pub error: Option<String>,
+}
+
+/// The on-chain list next to what this node has cached.
+#[derive(Debug, Clone, Serialize)]
+pub struct ListDiff {
+    pub listed: Vec<ListedLink>,
+    /// Entries cached from client requests rather than from the list.
+    pub cached_not_listed: Vec<String>,
+}
+
+pub async fn diff(backend: &dyn ChainBackend, config: &Config) -> Result<ListDiff> {
+    let links = backend.cdn_list().await?;
+
+    let cached: Vec<String> = tokio::task::spawn_blocking(|| {
+        cacache::list_sync(CACHE_DIR.as_str())
+            .filter_map(|m| m.ok())
+            .map(|m| m.key)
+            .filter(|k| !compression::is_variant_key(k))
+            .collect()
+    })
+    .await
+    .into_diagnostic()?;
+    let cached_bases: HashSet<&str> = cached.iter().map(|k| keying::base_key(k)).collect();
+
+    let mut listed = Vec::new();
+    for link in links {
+        let key = CacheLink::parse(&link)
+            .map_err(|e| e.to_string())
+            .and_then(|parsed| {
+                let site = config
+                    .site_for_link(&parsed)
+                    .ok_or_else(|| "no site configured".to_owned())?;
+                site.base_cache_key(&parsed.method, &parsed.path_and_query)
+                    .map_err(|e| e.to_string())
+            });
+        listed.push(match key {
+            Ok(key) => ListedLink {
+                link,
+                cached: cached_bases.contains(key.as_str()),
+                cache_key: Some(key),
+                error: None,
+            },
+            Err(error) => ListedLink {
+                link,
+                cache_key: None,
+                cached: false,
+                error: Some(error),
+            },
+        });
+    }
+
+    let listed_keys: HashSet<&str> = listed
+        .iter()
+        .filter_map(|l| l.cache_key.as_deref())
+        .collect();
+    let cached_not_listed = cached
+        .iter()
+        .filter(|k| !listed_keys.contains(keying::base_key(k)))
+        .cloned()
+        .collect();
+
+    Ok(ListDiff {
+        listed,
+        cached_not_listed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listed(links: &[&str]) -> Vec<String> {
+        links.iter().map(|l| l.to_string()).collect()
+    }
+
+    fn outcomes(plan: &Plan) -> Vec<(&str, &str, Option<&str>)> {
+        plan.links
+            .iter()
+            .map(|o| (o.link.as_str(), o.status, o.reason.as_deref()))
+            .collect()
+    }
+
+    #[test]
+    fn queues_links_to_add() {
+        let planned = plan(
+            ListAction::Add,
+            &listed(&["get\t/a"]),
+            listed(&["get\t/b", "get\t/c"]),
+        )
+        .unwrap();
+        assert_eq!(planned.queued, listed(&["get\t/b", "get\t/c"]));
+        assert_eq!(
+            outcomes(&planned),
+            [("get\t/b", "queued", None), ("get\t/c", "queued", None)]
+        );
+    }
+
+    #[test]
+    fn removes_links_by_their_listed_string() {
+        let planned = plan(
+            ListAction::Remove,
+            &listed(&["get\t/a", "get\t/b"]),
+            listed(&["get@/a"]),
+        )
+        .unwrap();
+        assert_eq!(planned.queued, listed(&["get\t/a"]));
+        assert_eq!(
+            outcomes(&planned),
+            [("get@/a", "queued", Some("listed as \"get\\t/a\""))]
+        );
+    }
+
+    #[test]
+    fn skips_duplicates_in_any_spelling() {
+        let planned = plan(
+            ListAction::Add,
+            &[],
+            listed(&["get\t/a", "get@/a", "get\t/a", "get\t/b"]),
+        )
+        .unwrap();
+        assert_eq!(planned.queued, listed(&["get\t/a", "get\t/b"]));
+        assert_eq!(
+            outcomes(&planned),
+            [
+                ("get\t/a", "queued", None),
+                ("get@/a", "skipped", Some("duplicate")),
+                ("get\t/a", "skipped", Some("duplicate")),
+                ("get\t/b", "queued", None),
+            ]
+        );
+
+        let removal = plan(
+            ListAction::Remove,
+            &listed(&["get\t/a"]),
+            listed(&["get@/a", "get\t/a"]),
+        )
+        .unwrap();
+        assert_eq!(removal.queued, listed(&["get\t/a"]));
+        assert_eq!(removal.links[1].reason.as_deref(), Some("duplicate"));
+    }
+
+    #[test]
+    fn skips_links_that_change_nothing() {
+        let planned = plan(
+            ListAction::Add,
+            &listed(&["get\t/a"]),
+            listed(&["get@/a", "get\t/a"]),
+        )
+        .unwrap();
+        assert!(planned.queued.is_empty());
+        assert_eq!(
+            outcomes(&planned),
+            [
+                ("get@/a", "skipped", Some("already listed")),
+                ("get\t/a", "skipped", Some("already listed")),
+            ]
+        );
+
+        let removal = plan(
+            ListAction::Remove,
+            &listed(&["get\t/a"]),
+            listed(&["get\t/b"]),
+        )
+        .unwrap();
+        assert!(removal.queued.is_empty());
+        assert_eq!(
+            outcomes(&removal),
+            [("get\t/b", "skipped", Some("not listed"))]
+        );
+    }
+
+    #[test]
+    fn rejects_the_batch_when_a_link_is_invalid() {
+        let error = plan(ListAction::Add, &[], listed(&["get\t/a", ""])).unwrap_err();
+        assert!(error.to_string().starts_with("Invalid links:"));
+    }
+
+    /// Accepts `accepted` list changes, then fails every one after them.
+    #[derive(Debug)]
+    struct FailingAfter {
+        chain: chain::MockChain,
+        accepted: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl ChainBackend for FailingAfter {
+        fn info(&self) -> chain::BackendInfo {
+            self.chain.info()
+        }
+
+        async fn block_number(&self) -> Result<u64> {
+            self.chain.block_number().await
+        }
+
+        async fn cdn_list(&self) -> Result<Vec<String>> {
+            self.chain.cdn_list().await
+        }
+
+        async fn serve_count(&self) -> Result<ethers::types::U256> {
+            self.chain.serve_count().await
+        }
+
+        async fn add_serve_count(&self, count: u64) -> Result<H256> {
+            self.chain.add_serve_count(count).await
+        }
+
+        async fn change_list(&self, action: ListAction, links: Vec<String>) -> Result<H256> {
+            let left = self.accepted.load(std::sync::atomic::Ordering::SeqCst);
+            if left == 0 {
+                return Err(miette!("nonce too low"));
+            }
+            self.accepted.store(left - 1, std::sync::atomic::Ordering::SeqCst);
+            self.chain.change_list(action, links).await
+        }
+
+        async fn receipt(&self, hash: H256) -> Result<Option<chain::Receipt>> {
+            self.chain.receipt(hash).await
+        }
+
+        async fn link_events(
+            &self,
+        ) -> Result<futures::stream::BoxStream<'static, Result<chain::LinkEvent>>> {
+            self.chain.link_events().await
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_the_batches_sent_before_one_failed() {
+        let backend: Arc<dyn ChainBackend> = Arc::new(FailingAfter {
+            chain: chain::MockChain::new(Vec::new()),
+            accepted: 1.into(),
+        });
+        let links = listed(&["get\t/a", "get\t/b", "get\t/c"]);
+
+        let sent = submit(&backend, ListAction::Add, links, 2).await.unwrap();
+        assert_eq!(sent.plan.queued.len(), 3);
+        assert_eq!(sent.submitted.len(), 1);
+        assert_eq!(sent.submitted[0].links, listed(&["get\t/a", "get\t/b"]));
+        assert!(sent.error.unwrap().to_string().contains("nonce too low"));
+        assert_eq!(backend.cdn_list().await.unwrap(), listed(&["get\t/a", "get\t/b"]));
+
+        assert!(send(&backend, ListAction::Add, sent.plan, 0).await.is_err());
+    }
+}
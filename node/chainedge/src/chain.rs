@@ -0,0 +1,174 @@
+use std::{
+    fmt::{self, Debug},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use ethers::types::{Address, H256, U256};
+use futures::stream::BoxStream;
+use miette::Result;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::{cdn_list::ListAction, CONTRACT_ADDRESS, RPC_URL};
+
+mod mock;
+mod offline;
+mod rpc;
+
+pub use mock::MockChain;
+pub use offline::OfflineChain;
+pub use rpc::RpcChain;
+
+/// Which contract the node follows and how it reaches it, the `[chain]` section of the config.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ChainConfig {
+    pub backend: BackendKind,
+    /// JSON-RPC endpoint, `https://` for `http` and `wss://` for `ws`.
+    pub rpc_url: String,
+    pub contract_address: String,
+    /// Skips asking the endpoint for it at startup.
+    pub chain_id: Option<u64>,
+    /// The CDN list of the `mock` and `offline` backends.
+    pub links: Vec<String>,
+    /// Signs what the RPC backends send, `WALLET_PRIV_KEY` when unset. Never serialized.
+    #[serde(skip_serializing)]
+    pub wallet_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    Http,
+    Ws,
+    /// In-process contract, list changes are applied and announced immediately.
+    Mock,
+    /// No contract at all, the list is fixed and served bytes are only counted locally.
+    Offline,
+}
+
+// by hand, so logging the config never prints the wallet key
+impl Debug for ChainConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChainConfig")
+            .field("backend", &self.backend)
+            .field("rpc_url", &self.rpc_url)
+            .field("contract_address", &self.contract_address)
+            .field("chain_id", &self.chain_id)
+            .field("links", &self.links)
+            .field("wallet_key", &self.wallet_key.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+impl Default for ChainConfig {
+    fn default() -> Self {
+        ChainConfig {
+            backend: BackendKind::Http,
+            rpc_url: RPC_URL.to_owned(),
+            contract_address: CONTRACT_ADDRESS.to_owned(),
+            chain_id: None,
+            links: Vec::new(),
+            wallet_key: None,
+        }
+    }
+}
+
+/// A link added to or removed from the CDN list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkEvent {
+    Added(String),
+    Removed(String),
+}
+
+/// Outcome of a mined transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Receipt {
+    pub success: bool,
+    pub block: Option<u64>,
+}
+
+/// What the status endpoints show about the backend.
+#[derive(Debug, Clone, Serialize)]
+pub struct BackendInfo {
+    pub backend: BackendKind,
+    pub rpc_url: Option<String>,
+    pub contract_address: Option<Address>,
+    pub chain_id: Option<u64>,
+}
+
+/// Everything the node does with the ChainEdge contract.
+#[async_trait]
+pub trait ChainBackend: Debug + Send + Sync {
+    fn info(&self) -> BackendInfo;
+
+    async fn block_number(&self) -> Result<u64>;
+
+    /// `getCDNList`.
+    async fn cdn_list(&self) -> Result<Vec<String>>;
+
+    /// `getServeCount`.
+    async fn serve_count(&self) -> Result<U256>;
+
+    /// Sends `addServeCount`, an error means nothing was sent.
+    async fn add_serve_count(&self, count: u64) -> Result<H256>;
+
+    /// Sends `addToCDN` or `removeFromCDN`, an error means nothing was sent.
+    async fn change_list(&self, action: ListAction, links: Vec<String>) -> Result<H256>;
+
+    /// `None` while the transaction is not mined.
+    async fn receipt(&self, hash: H256) -> Result<Option<Receipt>>;
+
+    /// `NewLink` and `RemoveLink` events from now on. The stream ends on the first error.
+    async fn link_events(&self) -> Result<BoxStream<'static, Result<LinkEvent>>>;
+}
+
+/// Builds the backend the config asks for. Only the RPC backends sign, with `chain.wallet_key`
+/// or `WALLET_PRIV_KEY`.
+pub async fn connect(config: &ChainConfig) -> Result<Arc<dyn ChainBackend>> {
+    Ok(match config.backend {
+        BackendKind::Http => Arc::new(RpcChain::http(config).await?),
+        BackendKind::Ws => Arc::new(RpcChain::ws(config).await?),
+        BackendKind::Mock => Arc::new(MockChain::new(config.links.clone())),
+        BackendKind::Offline => Arc::new(OfflineChain::new(config.links.clone())),
+    })
+}
+
+/// Polls for the receipt of `hash`, `None` if the transaction is not mined within `timeout`.
+pub async fn wait_for_receipt(
+    backend: &dyn ChainBackend,
+    hash: H256,
+    timeout: Duration,
+) -> Option<Receipt> {
+    let started = Instant::now();
+    loop {
+        match backend.receipt(hash).await {
+            Ok(Some(receipt)) => return Some(receipt),
+            Ok(None) => {}
+            Err(e) => warn!("Could not get receipt of {:?}: {}", hash, e),
+        }
+        if started.elapsed() > timeout {
+            return None;
+        }
+        tokio::time::sleep(Duration::from_secs(3)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_output_redacts_the_wallet_key() {
+        let config = ChainConfig {
+            wallet_key: Some("0xsecret".to_owned()),
+            ..ChainConfig::default()
+        };
+        let debug = format!("{:?}", config);
+        assert!(!debug.contains("0xsecret"));
+        assert!(debug.contains("<redacted>"));
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use async_trait::async_trait;
+use ethers::types::{H256, U256};
+use futures::{stream::BoxStream, StreamExt};
+use miette::Result;
+use tokio::sync::broadcast;
+
+use crate::{
+    cdn_list::ListAction,
+    chain::{BackendInfo, BackendKind, ChainBackend, LinkEvent, Receipt},
+};
+
+/// The contract simulated in memory: every transaction is mined in a block of its own
+/// as soon as it is sent, and list changes emit the events `ChainEdge.sol` would.
+#[derive(Debug)]
+pub struct MockChain {
+    state: Mutex<MockState>,
+    events: broadcast::Sender<LinkEvent>,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    links: Vec<String>,
+    serve_count: U256,
+    block: u64,
+    /// Receipts stay readable like on a chain, only the oldest age out past `KEPT_RECEIPTS`.
+    receipts: HashMap<H256, Receipt>,
+    mined: VecDeque<H256>,
+}
+
+/// Enough for any poll to see its receipt, without growing with every report of a long run.
+const KEPT_RECEIPTS: usize = 1024;
+
+impl MockState {
+    fn mine(&mut self) -> H256 {
+        self.block += 1;
+        let hash = H256::random();
+        self.receipts.insert(
+            hash,
+            Receipt {
+                success: true,
+                block: Some(self.block),
+            },
+        );
+        self.mined.push_back(hash);
+        if self.mined.len() > KEPT_RECEIPTS {
+            if let Some(oldest) = self.mined.pop_front() {
+                self.receipts.remove(&oldest);
+            }
+        }
+        hash
+    }
+}
+
+impl MockChain {
+    pub fn new(links: Vec<String>) -> Self {
+        MockChain {
+            state: Mutex::new(MockState {
+                links,
+                ..MockState::default()
+            }),
+            events: broadcast::channel(256).0,
+        }
+    }
+
+    /// How many `link_events` streams are open, events sent before a node follows them are missed.
+    pub fn listeners(&self) -> usize {
+        self.events.receiver_count()
+    }
+}
+
+#[async_trait]
+impl ChainBackend for MockChain {
+    fn info(&self) -> BackendInfo {
+        BackendInfo {
+            backend: BackendKind::Mock,
+            rpc_url: None,
+            contract_address: None,
+            chain_id: None,
+        }
+    }
+
+    async fn block_number(&self) -> Result<u64> {
+        Ok(self.state.lock().unwrap().block)
+    }
+
+    async fn cdn_list(&self) -> Result<Vec<String>> {
+        Ok(self.state.lock().unwrap().links.clone())
+    }
+
+    async fn serve_count(&self) -> Result<U256> {
+        Ok(self.state.lock().unwrap().serve_count)
+    }
+
+    async fn add_serve_count(&self, count: u64) -> Result<H256> {
+        let mut state = self.state.lock().unwrap();
+        state.serve_count += U256::from(count);
+        Ok(state.mine())
+    }
+
+    async fn change_list(&self, action: ListAction, links: Vec<String>) -> Result<H256> {
+        let mut state = self.state.lock().unwrap();
+        for link in links {
+            match action {
+                ListAction::Add => {
+                    if !state.links.contains(&link) {
+                        state.links.push(link.clone());
+                    }
+                    // like the contract, adding a listed link announces it again
+                    let _ = self.events.send(LinkEvent::Added(link));
+                }
+                ListAction::Remove => {
+                    if let Some(index) = state.links.iter().position(|l| *l == link) {
+                        state.links.swap_remove(index);
+                        let _ = self.events.send(LinkEvent::Removed(link));
+                    }
+                }
+            }
+        }
+        Ok(state.mine())
+    }
+
+    async fn receipt(&self, hash: H256) -> Result<Option<Receipt>> {
+        Ok(self.state.lock().unwrap().receipts.get(&hash).copied())
+    }
+
+    async fn link_events(&self) -> Result<BoxStream<'static, Result<LinkEvent>>> {
+        let rx = self.events.subscribe();
+        let stream = futures::stream::unfold(rx, |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => return Some((Ok(event), rx)),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        });
+        Ok(stream.boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn keeps_receipts_readable_until_they_age_out() {
+        let chain = MockChain::new(Vec::new());
+        let hash = chain.add_serve_count(10).await.unwrap();
+
+        for _ in 0..2 {
+            let receipt = chain.receipt(hash).await.unwrap().unwrap();
+            assert!(receipt.success);
+            assert_eq!(receipt.block, Some(1));
+        }
+
+        for _ in 0..KEPT_RECEIPTS {
+            chain.add_serve_count(1).await.unwrap();
+        }
+        assert!(chain.receipt(hash).await.unwrap().is_none());
+        assert_eq!(chain.state.lock().unwrap().receipts.len(), KEPT_RECEIPTS);
+    }
+}
@@ -0,0 +1,64 @@
+use async_trait::async_trait;
+use ethers::types::{H256, U256};
+use futures::{stream::BoxStream, StreamExt};
+use miette::{miette, Result};
+
+use crate::{
+    cdn_list::ListAction,
+    chain::{BackendInfo, BackendKind, ChainBackend, LinkEvent, MockChain, Receipt},
+};
+
+/// No chain at all: the CDN list is the configured one and never changes,
+/// served bytes are accepted and only counted in memory.
+#[derive(Debug)]
+pub struct OfflineChain {
+    local: MockChain,
+}
+
+impl OfflineChain {
+    pub fn new(links: Vec<String>) -> Self {
+        OfflineChain {
+            local: MockChain::new(links),
+        }
+    }
+}
+
+#[async_trait]
+impl ChainBackend for OfflineChain {
+    fn info(&self) -> BackendInfo {
+        BackendInfo {
+            backend: BackendKind::Offline,
+            rpc_url: None,
+            contract_address: None,
+            chain_id: None,
+        }
+    }
+
+    async fn block_number(&self) -> Result<u64> {
+        Err(miette!("The node runs offline"))
+    }
+
+    async fn cdn_list(&self) -> Result<Vec<String>> {
+        self.local.cdn_list().await
+    }
+
+    async fn serve_count(&self) -> Result<U256> {
+        self.local.serve_count().await
+    }
+
+    async fn add_serve_count(&self, count: u64) -> Result<H256> {
+        self.local.add_serve_count(count).await
+    }
+
+    async fn change_list(&self, _action: ListAction, _links: Vec<String>) -> Result<H256> {
+        Err(miette!("The node runs offline, its CDN list is read-only"))
+    }
+
+    async fn receipt(&self, hash: H256) -> Result<Option<Receipt>> {
+        self.local.receipt(hash).await
+    }
+
+    async fn link_events(&self) -> Result<BoxStream<'static, Result<LinkEvent>>> {
+        Ok(futures::stream::pending().boxed())
+    }
+}
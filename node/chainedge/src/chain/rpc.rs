@@ -0,0 +1,237 @@
+use std::{fmt, sync::Arc};
+
+use async_trait::async_trait;
+use ethers::prelude::*;
+use futures::{stream::BoxStream, Stream, StreamExt};
+use miette::{miette, Context, IntoDiagnostic, Result};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    cdn_list::ListAction,
+    chain::{BackendInfo, BackendKind, ChainBackend, ChainConfig, LinkEvent, Receipt},
+    IChainEdge, IChainEdgeEvents,
+};
+
+type Client<P> = SignerMiddleware<Provider<P>, LocalWallet>;
+
+/// The deployed contract over JSON-RPC, writes are signed with `chain.wallet_key` or `WALLET_PRIV_KEY`.
+pub struct RpcChain<P: JsonRpcClient> {
+    kind: BackendKind,
+    rpc_url: String,
+    chain_id: u64,
+    contract: IChainEdge<Client<P>>,
+}
+
+impl<P: JsonRpcClient> fmt::Debug for RpcChain<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RpcChain")
+            .field("kind", &self.kind)
+            .field("rpc_url", &self.rpc_url)
+            .field("chain_id", &self.chain_id)
+            .field("contract", &self.contract.address())
+            .finish()
+    }
+}
+
+impl RpcChain<Http> {
+    pub async fn http(config: &ChainConfig) -> Result<Self> {
+        let provider = Provider::<Http>::try_from(config.rpc_url.as_str()).into_diagnostic()?;
+        Self::new(BackendKind::Http, config, provider).await
+    }
+}
+
+impl RpcChain<Ws> {
+    pub async fn ws(config: &ChainConfig) -> Result<Self> {
+        let provider = Provider::<Ws>::connect(config.rpc_url.as_str())
+            .await
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Could not connect to {}", config.rpc_url))?;
+        Self::new(BackendKind::Ws, config, provider).await
+    }
+}
+
+impl<P: JsonRpcClient + 'static> RpcChain<P> {
+    async fn new(kind: BackendKind, config: &ChainConfig, provider: Provider<P>) -> Result<Self> {
+        let contract_address = config
+            .contract_address
+            .parse::<Address>()
+            .into_diagnostic()
+            .wrap_err("Invalid chain.contract_address")?;
+
+        let chain_id = match config.chain_id {
+            Some(chain_id) => chain_id,
+            None => provider
+                .get_chainid()
+                .await
+                .into_diagnostic()
+                .wrap_err_with(|| {
+                    format!(
+                        "Could not get the chain id from {}, set chain.chain_id or use the offline backend",
+                        config.rpc_url
+                    )
+                })?
+                .as_u64(),
+        };
+
+        let wallet_key = match &config.wallet_key {
+            Some(key) => key.clone(),
+            None => std::env::var("WALLET_PRIV_KEY")
+                .into_diagnostic()
+                .wrap_err("WALLET_PRIV_KEY must be set for the http and ws chain backends")?,
+        };
+        let wallet = wallet_key
+            .parse::<LocalWallet>()
+            .into_diagnostic()?
+            .with_chain_id(chain_id);
+        let client = Arc::new(SignerMiddleware::new(provider, wallet));
+
+        Ok(RpcChain {
+            kind,
+            rpc_url: config.rpc_url.clone(),
+            chain_id,
+            contract: IChainEdge::new(contract_address, client),
+        })
+    }
+}
+
+fn link_event(event: IChainEdgeEvents) -> Option<LinkEvent> {
+    match event {
+        IChainEdgeEvents::NewLinkFilter(e) => Some(LinkEvent::Added(e.link)),
+        IChainEdgeEvents::RemoveLinkFilter(e) => Some(LinkEvent::Removed(e.link)),
+        _ => None,
+    }
+}
+
+/// Sends the events of `stream` until it fails or nobody listens anymore.
+async fn forward<S, E>(stream: S, tx: &mpsc::Sender<Result<LinkEvent>>)
+where
+    S: Stream<Item = Result<IChainEdgeEvents, E>>,
+    E: fmt::Display,
+{
+    futures::pin_mut!(stream);
+    while let Some(event) = stream.next().await {
+        let event = match event {
+            Ok(event) => match link_event(event) {
+                Some(event) => Ok(event),
+                None => continue,
+            },
+            Err(e) => Err(miette!("{}", e)),
+        };
+        let failed = event.is_err();
+        if tx.send(event).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// How the event stream is obtained: polling a filter over HTTP, a subscription over WebSocket.
+/// `ready` is sent once the filter or subscription is in place, or failed to be.
+#[async_trait]
+trait EventSource: JsonRpcClient + Sized + 'static {
+    async fn send_events(
+        contract: IChainEdge<Client<Self>>,
+        tx: mpsc::Sender<Result<LinkEvent>>,
+        ready: oneshot::Sender<()>,
+    );
+}
+
+#[async_trait]
+impl EventSource for Http {
+    async fn send_events(
+        contract: IChainEdge<Client<Self>>,
+        tx: mpsc::Sender<Result<LinkEvent>>,
+        ready: oneshot::Sender<()>,
+    ) {
+        let events = contract.events();
+        let stream = events.stream().await;
+        let _ = ready.send(());
+        match stream {
+            Ok(stream) => forward(stream, &tx).await,
+            Err(e) => {
+                let _ = tx.send(Err(miette!("{}", e))).await;
+            }
+        };
+    }
+}
+
+#[async_trait]
+impl EventSource for Ws {
+    async fn send_events(
+        contract: IChainEdge<Client<Self>>,
+        tx: mpsc::Sender<Result<LinkEvent>>,
+        ready: oneshot::Sender<()>,
+    ) {
+        let events = contract.events();
+        let stream = events.subscribe().await;
+        let _ = ready.send(());
+        match stream {
+            Ok(stream) => forward(stream, &tx).await,
+            Err(e) => {
+                let _ = tx.send(Err(miette!("{}", e))).await;
+            }
+        };
+    }
+}
+
+#[async_trait]
+impl<P: EventSource> ChainBackend for RpcChain<P> {
+    fn info(&self) -> BackendInfo {
+        BackendInfo {
+            backend: self.kind,
+            rpc_url: Some(self.rpc_url.clone()),
+            contract_address: Some(self.contract.address()),
+            chain_id: Some(self.chain_id),
+        }
+    }
+
+    async fn block_number(&self) -> Result<u64> {
+        let block = self.contract.client().get_block_number().await.into_diagnostic()?;
+        Ok(block.as_u64())
+    }
+
+    async fn cdn_list(&self) -> Result<Vec<String>> {
+        self.contract.get_cdn_list().call().await.into_diagnostic()
+    }
+
+    async fn serve_count(&self) -> Result<U256> {
+        self.contract.get_serve_count().call().await.into_diagnostic()
+    }
+
+    async fn add_serve_count(&self, count: u64) -> Result<H256> {
+        let call = self.contract.add_serve_count(U256::from(count));
+        let pending = call.send().await.into_diagnostic()?;
+        Ok(pending.tx_hash())
+    }
+
+    async fn change_list(&self, action: ListAction, links: Vec<String>) -> Result<H256> {
+        let call = match action {
+            ListAction::Add => self.contract.add_to_cdn(links),
+            ListAction::Remove => self.contract.remove_from_cdn(links),
+        };
+        let pending = call.send().await.into_diagnostic()?;
+        Ok(pending.tx_hash())
+    }
+
+    async fn receipt(&self, hash: H256) -> Result<Option<Receipt>> {
+        let receipt = self
+            .contract
+            .client()
+            .get_transaction_receipt(hash)
+            .await
+            .into_diagnostic()?;
+        Ok(receipt.map(|r| Receipt {
+            success: r.status == Some(1.into()),
+            block: r.block_number.map(|b| b.as_u64()),
+        }))
+    }
+
+    async fn link_events(&self) -> Result<BoxStream<'static, Result<LinkEvent>>> {
+        let (tx, mut rx) = mpsc::channel(64);
+        let (ready, following) = oneshot::channel();
+        tokio::spawn(P::send_events(self.contract.clone(), tx, ready));
+        // events are only delivered from the filter or subscription on
+        let _ = following.await;
+
+        Ok(futures::stream::poll_fn(move |cx| rx.poll_recv(cx)).boxed())
+    }
+}
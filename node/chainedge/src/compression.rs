@@ -0,0 +1,315 @@
+use std::io::Write;
+
+use axum::body::Bytes;
+use http::{
+    header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, RANGE, VARY},
+    HeaderMap, HeaderValue, Response, StatusCode,
+};
+use miette::{IntoDiagnostic, Result};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::CACHE_DIR;
+
+/// Per-site on-the-fly compression of origin bodies.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    /// Bodies smaller than this are sent as they are.
+    pub min_size: usize,
+    /// Mime types (without parameters) eligible for compression. A trailing `*` matches a prefix.
+    pub content_types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            enabled: true,
+            min_size: 1024,
+            content_types: [
+                "text/*",
+                "application/javascript",
+                "application/json",
+                "application/xml",
+                "image/svg+xml",
+            ]
+            .into_iter()
+            .map(str::to_owned)
+            .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+/// In order of preference when the client accepts several with the same weight.
+const ENCODINGS: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn compress(&self, body: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut out = Vec::new();
+                let mut writer = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+                writer.write_all(body).into_diagnostic()?;
+                drop(writer);
+                Ok(out)
+            }
+            Encoding::Zstd => zstd::encode_all(body, 3).into_diagnostic(),
+            Encoding::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body).into_diagnostic()?;
+                encoder.finish().into_diagnostic()
+            }
+        }
+    }
+}
+
+/// Key of the compressed variant of the entry stored under `cache_key`.
+fn variant_key(cache_key: &str, encoding: Encoding) -> String {
+    format!("{}\te:{}", cache_key, encoding.as_str())
+}
+
+/// Compressed variants live next to the entries they were made from, not as entries of their own.
+pub fn is_variant_key(key: &str) -> bool {
+    ENCODINGS
+        .iter()
+        .any(|e| key.ends_with(&format!("\te:{}", e.as_str())))
+}
+
+/// Picks the encoding with the highest `q` the client accepts. `*` stands for the
+/// codings the header does not name, and `q=0` excludes a coding.
+pub fn negotiate(request: &HeaderMap) -> Option<Encoding> {
+    let mut named: Vec<(String, f32)> = Vec::new();
+    let mut any: Option<f32> = None;
+
+    for value in request.get_all(ACCEPT_ENCODING).iter() {
+        let Ok(value) = value.to_str() else { continue };
+        for item in value.split(',') {
+            let mut params = item.split(';');
+            let name = params.next().unwrap_or_default().trim().to_ascii_lowercase();
+            if name.is_empty() {
+                continue;
+            }
+            let q = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            match name.as_str() {
+                "*" => any = Some(q),
+                _ => named.push((name, q)),
+            }
+        }
+    }
+
+    let mut best: Option<(Encoding, f32)> = None;
+    // in order of preference, so only a higher q replaces an earlier encoding
+    for encoding in ENCODINGS {
+        let q = named
+            .iter()
+            .find(|(name, _)| name == encoding.as_str())
+            .map(|(_, q)| *q)
+            .or(any);
+        match q {
+            Some(q) if q > 0.0 && best.is_none_or(|(_, bq)| q > bq) => best = Some((encoding, q)),
+            _ => {}
+        }
+    }
+
+    best.map(|(encoding, _)| encoding)
+}
+
+fn is_compressible(config: &CompressionConfig, response: &Response<Bytes>) -> bool {
+    if !config.enabled
+        || response.status() != StatusCode::OK
+        || response.headers().contains_key(CONTENT_ENCODING)
+        || response.body().len() < config.min_size
+    {
+        return false;
+    }
+
+    let Some(mime) = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(';').next().unwrap_or_default().trim().to_ascii_lowercase())
+    else {
+        return false;
+    };
+
+    config.content_types.iter().any(|t| match t.strip_suffix('*') {
+        Some(prefix) => mime.starts_with(&prefix.to_ascii_lowercase()),
+        None => mime.eq_ignore_ascii_case(t),
+    })
+}
+
+fn add_vary_accept_encoding(headers: &mut HeaderMap) {
+    let varies = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim() == "*" || v.trim().eq_ignore_ascii_case("accept-encoding"));
+    if !varies {
+        headers.append(VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+}
+
+/// Serves the representation the client negotiated. With `cache_key` the compressed
+/// variant is read from, or stored once into, the cache next to the identity entry.
+pub async fn apply(
+    config: &CompressionConfig,
+    request: &HeaderMap,
+    response: Response<Bytes>,
+    cache_key: Option<&str>,
+) -> Result<Response<Bytes>> {
+    // ranges are served on the identity representation
+    if !is_compressible(config, &response) || request.contains_key(RANGE) {
+        return Ok(response);
+    }
+
+    let (mut parts, body) = response.into_parts();
+    add_vary_accept_encoding(&mut parts.headers);
+
+    let Some(encoding) = negotiate(request) else {
+        return Ok(Response::from_parts(parts, body));
+    };
+
+    let stored = match cache_key {
+        Some(key) => cacache::read(CACHE_DIR.as_str(), variant_key(key, encoding)).await.ok(),
+        None => None,
+    };
+
+    let compressed: Bytes = match stored {
+        Some(compressed) => compressed.into(),
+        None => {
+            let source = body.clone();
+            let compressed = tokio::task::spawn_blocking(move || encoding.compress(&source))
+                .await
+                .into_diagnostic()??;
+            // not worth it, e.g. already compressed content served as text
+            if compressed.len() >= body.len() {
+                return Ok(Response::from_parts(parts, body));
+            }
+            if let Some(key) = cache_key {
+                info!("Storing {} variant of {}", encoding.as_str(), key);
+                cacache::write(CACHE_DIR.as_str(), variant_key(key, encoding), &compressed)
+                    .await
+                    .into_diagnostic()?;
+            }
+            compressed.into()
+        }
+    };
+
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.as_str()));
+    parts
+        .headers
+        .insert(CONTENT_LENGTH, HeaderValue::from(compressed.len()));
+    // the compressed bytes differ from the identity ones, so a strong validator no longer holds
+    if let Some(etag) = parts.headers.get(ETAG).and_then(|v| v.to_str().ok()) {
+        if !etag.starts_with("W/") {
+            let weak = format!("W/{}", etag).parse().into_diagnostic()?;
+            parts.headers.insert(ETAG, weak);
+        }
+    }
+
+    Ok(Response::from_parts(parts, compressed))
+}
+
+/// Drops the compressed variants of an entry whose identity body was rewritten.
+pub async fn invalidate(cache_key: &str) {
+    for key in variant_keys(cache_key) {
+        let _ = cacache::remove(CACHE_DIR.as_str(), key).await;
+    }
+}
+
+/// Keys the compressed variants of an entry are stored under, whether they exist or not.
+pub fn variant_keys(cache_key: &str) -> impl Iterator<Item = String> + '_ {
+    ENCODINGS.into_iter().map(move |e| variant_key(cache_key, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepting(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn negotiates_the_highest_q() {
+        assert_eq!(negotiate(&accepting("gzip, br")), Some(Encoding::Brotli));
+        assert_eq!(negotiate(&accepting("gzip, br;q=0.5")), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&accepting("GZIP;q=0.2, zstd;q=0.8")), Some(Encoding::Zstd));
+        assert_eq!(negotiate(&accepting("deflate")), None);
+        assert_eq!(negotiate(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn applies_star_to_unnamed_codings_only() {
+        assert_eq!(negotiate(&accepting("*")), Some(Encoding::Brotli));
+        assert_eq!(negotiate(&accepting("br;q=0, *")), Some(Encoding::Zstd));
+        assert_eq!(negotiate(&accepting("*, br;q=0, zstd;q=0")), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&accepting("gzip;q=0.5, *;q=0.1")), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&accepting("gzip, *;q=0")), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&accepting("*;q=0")), None);
+    }
+
+    #[test]
+    fn treats_q_zero_as_an_exclusion() {
+        assert_eq!(negotiate(&accepting("gzip;q=0")), None);
+        assert_eq!(negotiate(&accepting("br;q=0.0, gzip;q=0.1")), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&accepting("identity")), None);
+        assert_eq!(negotiate(&accepting("identity;q=0, gzip;q=0.3")), Some(Encoding::Gzip));
+    }
+
+    fn text(body: &[u8]) -> Response<Bytes> {
+        Response::builder()
+            .header(CONTENT_TYPE, "text/plain")
+            .body(Bytes::copy_from_slice(body))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn compresses_only_when_it_pays_off() {
+        let config = CompressionConfig {
+            min_size: 16,
+            ..CompressionConfig::default()
+        };
+        let gzip = accepting("gzip");
+
+        let small = apply(&config, &gzip, text(b"too small"), None).await.unwrap();
+        assert!(!small.headers().contains_key(CONTENT_ENCODING));
+        assert!(!small.headers().contains_key(VARY));
+
+        // gzip adds more than it saves on a short body without repetitions
+        let incompressible = apply(&config, &gzip, text(b"0123456789abcdefghij"), None).await.unwrap();
+        assert!(!incompressible.headers().contains_key(CONTENT_ENCODING));
+        assert_eq!(&incompressible.body()[..], b"0123456789abcdefghij");
+
+        let body = "compressible ".repeat(100);
+        let compressed = apply(&config, &gzip, text(body.as_bytes()), None).await.unwrap();
+        assert_eq!(compressed.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(compressed.headers()[VARY], "Accept-Encoding");
+        assert!(compressed.body().len() < body.len());
+    }
+}
@@ -0,0 +1,166 @@
+use http::Method;
+use miette::{miette, Context, IntoDiagnostic, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    acme::AcmeConfig,
+    chain::ChainConfig,
+    compression::CompressionConfig,
+    keying::CacheKeyRules,
+    link::CacheLink,
+    memory::MemoryCacheConfig,
+    rules::CacheRule,
+    tls::{host_name, SiteTlsConfig, TlsConfig},
+    PROXY_FROM_DOMAIN, PROXY_ORIGIN_DOMAIN,
+};
+
+/// Node configuration, read from the TOML file named by `CHAINEDGE_CONFIG`.
+/// Without it the node proxies a single site built from the compiled-in domains.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Config {
+    /// Address of the plain HTTP listener.
+    #[serde(default = "default_listen")]
+    pub listen: String,
+    pub sites: Vec<SiteConfig>,
+    /// Enables the HTTPS listener for the sites that have a certificate.
+    pub tls: Option<TlsConfig>,
+    pub acme: Option<AcmeConfig>,
+    #[serde(default)]
+    pub chain: ChainConfig,
+    /// Hot entries kept decoded in memory in front of the disk cache.
+    #[serde(default)]
+    pub memory_cache: MemoryCacheConfig,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SiteConfig {
+    /// Host (and port) clients use to reach this site through the edge.
+    pub front_domain: String,
+    /// Host (and port) the edge fetches from on a miss.
+    pub origin_domain: String,
+    #[serde(default)]
+    pub cache_key: CacheKeyRules,
+    /// Methods whose responses may be cached. HEAD is answered from the GET entry,
+    /// methods other than GET and HEAD (e.g. POST) are keyed by a hash of the request body.
+    #[serde(default = "default_cache_methods")]
+    pub cache_methods: Vec<String>,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    /// Overrides of the origin caching headers, the first matching rule applies.
+    #[serde(default)]
+    pub cache_rules: Vec<CacheRule>,
+    pub tls: Option<SiteTlsConfig>,
+}
+
+fn default_listen() -> String {
+    "0.0.0.0:3001".to_owned()
+}
+
+fn default_cache_methods() -> Vec<String> {
+    vec!["GET".to_owned(), "HEAD".to_owned()]
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: default_listen(),
+            sites: vec![SiteConfig {
+                front_domain: PROXY_FROM_DOMAIN.to_owned(),
+                origin_domain: PROXY_ORIGIN_DOMAIN.to_owned(),
+                cache_key: CacheKeyRules::default(),
+                cache_methods: default_cache_methods(),
+                compression: CompressionConfig::default(),
+                cache_rules: Vec::new(),
+                tls: None,
+            }],
+            tls: None,
+            acme: None,
+            chain: ChainConfig::default(),
+            memory_cache: MemoryCacheConfig::default(),
+        }
+    }
+}
+
+impl SiteConfig {
+    pub fn is_cacheable(&self, method: &Method) -> bool {
+        self.cache_methods
+            .iter()
+            .any(|m| m.eq_ignore_ascii_case(method.as_str()))
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Self> {
+        let Ok(path) = std::env::var("CHAINEDGE_CONFIG") else {
+            return Ok(Config::default());
+        };
+
+        let raw = std::fs::read_to_string(&path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Could not read config {}", path))?;
+        let mut config: Config = toml::from_str(&raw)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Could not parse config {}", path))?;
+
+        if config.sites.is_empty() {
+            return Err(miette!("Config {} does not define any site", path));
+        }
+
+        for site in config.sites.iter_mut() {
+            let Some(tls) = site.tls.as_mut().filter(|t| t.acme) else {
+                continue;
+            };
+            let acme = config.acme.as_ref().ok_or_else(|| {
+                miette!("{} uses ACME but {} has no [acme] section", site.front_domain, path)
+            })?;
+            if tls.cert.as_os_str().is_empty() {
+                tls.cert = acme.cert_path(&site.front_domain);
+            }
+            if tls.key.as_os_str().is_empty() {
+                tls.key = acme.key_path(&site.front_domain);
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// What deserializing checks, for configs built in code.
+    pub fn validate(&self) -> Result<()> {
+        for site in &self.sites {
+            for (index, rule) in site.cache_rules.iter().enumerate() {
+                rule.validate().map_err(|e| {
+                    miette!("Cache rule {} of {}: {}", index + 1, site.front_domain, e)
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Matches the `Host` of a request, falling back to the host name alone since
+    /// the same site is reachable on both the plain and the TLS port.
+    pub fn site_for_host(&self, host: &str) -> Option<&SiteConfig> {
+        self.sites
+            .iter()
+            .find(|s| s.front_domain.eq_ignore_ascii_case(host))
+            .or_else(|| {
+                let name = host_name(host);
+                self.sites.iter().find(|s| host_name(&s.front_domain) == name)
+            })
+    }
+
+    /// Absolute links select the site by their host, relative links belong to the first site.
+    pub fn site_for_link(&self, link: &CacheLink) -> Option<&SiteConfig> {
+        match &link.origin {
+            Some(origin) => {
+                let host = origin.split_once("://").map_or(origin.as_str(), |(_, h)| h);
+                self.sites.iter().find(|s| {
+                    s.front_domain.eq_ignore_ascii_case(host)
+                        || s.front_domain
+                            .split_once(':')
+                            .is_some_and(|(h, _)| h.eq_ignore_ascii_case(host))
+                })
+            }
+            None => self.sites.first(),
+        }
+    }
+}
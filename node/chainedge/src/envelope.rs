@@ -0,0 +1,444 @@
+//! On-disk format of cache entries.
+//!
+//! An entry is `MAGIC`, the schema version (`u16`, little endian), a CRC32 of the payload
+//! (`u32`, little endian) and the postcard encoded `CachedResponse`. Entries written before
+//! the envelope are bare postcard and read as version 0. A change to `CachedResponse` bumps
+//! `VERSION` and keeps a reader for the layout it replaces in `decode_payload`.
+//!
+//! Entries that can not be read are moved to a quarantine directory next to the cache
+//! instead of failing every request for them, see `quarantine`.
+
+use std::{
+    collections::HashSet,
+    fmt,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use cacache::Integrity;
+use http::Method;
+use miette::{Context, IntoDiagnostic, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{compression, config::SiteConfig, CachedResponse};
+
+pub const MAGIC: [u8; 4] = *b"CHED";
+/// Schema version entries are written with.
+pub const VERSION: u16 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 2 + 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryError {
+    Truncated,
+    Checksum { stored: u32, computed: u32 },
+    UnsupportedVersion(u16),
+    Decode { version: u16, message: String },
+}
+
+impl fmt::Display for EntryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntryError::Truncated => write!(f, "entry is shorter than its header"),
+            EntryError::Checksum { stored, computed } => write!(
+                f,
+                "checksum mismatch, stored {:08x} but the payload has {:08x}",
+                stored, computed
+            ),
+            EntryError::UnsupportedVersion(v) => {
+                write!(f, "schema version {} is newer than this node ({})", v, VERSION)
+            }
+            EntryError::Decode { version, message } => {
+                write!(f, "could not decode a version {} entry: {}", version, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EntryError {}
+
+fn checksum(payload: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(payload);
+    crc.sum()
+}
+
+pub(crate) fn encode(cached: &CachedResponse) -> Result<Vec<u8>> {
+    let payload = postcard::to_allocvec(cached).into_diagnostic()?;
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(&MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&checksum(&payload).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    Ok(bytes)
+}
+
+fn decode_payload(version: u16, payload: &[u8]) -> Result<CachedResponse, EntryError> {
+    match version {
+        // the layout has not changed since the first entries were written
+        0 | 1 => postcard::from_bytes(payload).map_err(|e| EntryError::Decode {
+            version,
+            message: e.to_string(),
+        }),
+        v => Err(EntryError::UnsupportedVersion(v)),
+    }
+}
+
+/// The entry and the schema version it was written with.
+pub(crate) fn decode(bytes: &[u8]) -> Result<(CachedResponse, u16), EntryError> {
+    // a bare postcard entry starts with the length of its method, never with the magic
+    let Some(header) = bytes.strip_prefix(&MAGIC) else {
+        return Ok((decode_payload(0, bytes)?, 0));
+    };
+    if header.len() < HEADER_LEN - MAGIC.len() {
+        return Err(EntryError::Truncated);
+    }
+    let version = u16::from_le_bytes([header[0], header[1]]);
+    let stored = u32::from_le_bytes([header[2], header[3], header[4], header[5]]);
+    let payload = &header[6..];
+    let computed = checksum(payload);
+    if stored != computed {
+        return Err(EntryError::Checksum { stored, computed });
+    }
+    Ok((decode_payload(version, payload)?, version))
+}
+
+/// Where entries of `cache_dir` are quarantined. Outside of it, so clearing the cache keeps them.
+pub fn quarantine_dir(cache_dir: &str) -> PathBuf {
+    PathBuf::from(format!("{}.quarantine", cache_dir.trim_end_matches('/')))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuarantinedEntry {
+    pub key: String,
+    pub reason: String,
+    pub quarantined_at: u64,
+    /// Whether the raw bytes were saved next to this record, as `<name>.entry`.
+    pub saved_bytes: bool,
+}
+
+/// Moves an unreadable entry out of the cache, along with its compressed variants.
+/// `bytes` are kept for a post-mortem when they could be read at all.
+pub fn quarantine(cache_dir: &str, key: &str, bytes: Option<&[u8]>, reason: &str) -> Result<()> {
+    let dir = quarantine_dir(cache_dir);
+    std::fs::create_dir_all(&dir)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Could not create {}", dir.display()))?;
+
+    let name = Integrity::from(key).to_hex().1;
+    if let Some(bytes) = bytes {
+        std::fs::write(dir.join(format!("{}.entry", name)), bytes).into_diagnostic()?;
+    }
+    let record = QuarantinedEntry {
+        key: key.to_owned(),
+        reason: reason.to_owned(),
+        quarantined_at: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        saved_bytes: bytes.is_some(),
+    };
+    std::fs::write(
+        dir.join(format!("{}.json", name)),
+        serde_json::to_vec_pretty(&record).into_diagnostic()?,
+    )
+    .into_diagnostic()?;
+
+    for variant in compression::variant_keys(key).chain(std::iter::once(key.to_owned())) {
+        let _ = cacache::remove_sync(cache_dir, &variant);
+    }
+    warn!("Quarantined {:?}: {}", key, reason);
+    Ok(())
+}
+
+/// Records of the quarantined entries, unreadable records skipped.
+pub fn quarantined(cache_dir: &str) -> Vec<QuarantinedEntry> {
+    let Ok(entries) = std::fs::read_dir(quarantine_dir(cache_dir)) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|e| e == "json"))
+        .filter_map(|p| serde_json::from_slice(&std::fs::read(p).ok()?).ok())
+        .collect()
+}
+
+/// Bytes of the files under `path`.
+pub fn disk_usage(path: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .map(|e| match e.file_type() {
+            Ok(t) if t.is_dir() => disk_usage(&e.path()),
+            _ => e.metadata().map_or(0, |m| m.len()),
+        })
+        .sum()
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct MigrationReport {
+    /// Entries read, compressed variants not included.
+    pub entries: usize,
+    /// Entries rewritten with the current schema version.
+    pub migrated: usize,
+    pub quarantined: Vec<String>,
+    /// Compressed variants kept.
+    pub variants: usize,
+    /// Entries keyed without a front domain, moved under the key of the site given.
+    pub rekeyed: usize,
+    pub compacted: bool,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+/// Rewrites every entry of `cache_dir` with the current schema version and quarantines the
+/// unreadable ones. The node must not be running.
+///
+/// With `compact`, the live entries are copied into a fresh cache that then replaces the old
+/// one. This drops the index lines of removed and replaced entries and their orphaned content,
+/// which the cache otherwise keeps forever.
+///
+/// Keys used to start with the raw path, `GET\t/index.html`. With `site` such entries are
+/// moved under the key the node now looks them up by, as `SiteConfig::base_cache_key`
+/// builds it, and their compressed variants are dropped.
+pub fn migrate(
+    cache_dir: &str,
+    compact: bool,
+    site: Option<&SiteConfig>,
+) -> Result<MigrationReport> {
+    let mut report = MigrationReport {
+        compacted: compact,
+        bytes_before: disk_usage(Path::new(cache_dir)),
+        ..Default::default()
+    };
+    let fresh = format!("{}.compacting", cache_dir.trim_end_matches('/'));
+    let target = if compact {
+        let _ = std::fs::remove_dir_all(&fresh);
+        fresh.as_str()
+    } else {
+        cache_dir
+    };
+
+    let (variants, entries): (Vec<_>, Vec<_>) = cacache::list_sync(cache_dir)
+        .filter_map(|m| m.ok())
+        .map(|m| m.key)
+        .partition(|k| compression::is_variant_key(k));
+
+    let mut quarantined = HashSet::new();
+    for key in entries {
+        report.entries += 1;
+        let bytes = match cacache::read_sync(cache_dir, &key) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                quarantine(cache_dir, &key, None, &e.to_string())?;
+                quarantined.insert(key);
+                continue;
+            }
+        };
+        let rekeyed = site.and_then(|site| rekey(&key, site));
+        let target_key = rekeyed.as_deref().unwrap_or(&key);
+        match decode(&bytes) {
+            Ok((_, version)) if version == VERSION => {
+                if compact || rekeyed.is_some() {
+                    cacache::write_sync(target, target_key, &bytes).into_diagnostic()?;
+                }
+            }
+            Ok((cached, _)) => {
+                cacache::write_sync(target, target_key, encode(&cached)?).into_diagnostic()?;
+                report.migrated += 1;
+            }
+            Err(e) => {
+                quarantine(cache_dir, &key, Some(&bytes), &e.to_string())?;
+                quarantined.insert(key);
+                continue;
+            }
+        }
+        if rekeyed.is_some() {
+            if !compact {
+                cacache::remove_sync(cache_dir, &key).into_diagnostic()?;
+            }
+            report.rekeyed += 1;
+        }
+    }
+
+    for key in variants {
+        if quarantined.iter().any(|q| key.starts_with(&format!("{}\t", q))) {
+            continue;
+        }
+        if site.is_some() && is_legacy_key(&key) {
+            if !compact {
+                cacache::remove_sync(cache_dir, &key).into_diagnostic()?;
+            }
+            continue;
+        }
+        if compact {
+            // a variant that can not be read is compressed again on the next request
+            let Ok(bytes) = cacache::read_sync(cache_dir, &key) else {
+                continue;
+            };
+            cacache::write_sync(target, &key, &bytes).into_diagnostic()?;
+        }
+        report.variants += 1;
+    }
+
+    if compact {
+        let old = format!("{}.old", cache_dir.trim_end_matches('/'));
+        std::fs::create_dir_all(&fresh).into_diagnostic()?;
+        std::fs::rename(cache_dir, &old)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Could not move {} aside", cache_dir))?;
+        std::fs::rename(&fresh, cache_dir)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Could not move the compacted cache to {}", cache_dir))?;
+        std::fs::remove_dir_all(&old).into_diagnostic()?;
+    }
+
+    report.quarantined = quarantined.into_iter().collect();
+    report.quarantined.sort();
+    report.bytes_after = disk_usage(Path::new(cache_dir));
+    info!(
+        "Migrated {} of {} entries, quarantined {}",
+        report.migrated,
+        report.entries,
+        report.quarantined.len()
+    );
+    Ok(report)
+}
+
+/// Whether `key` was stored before keys started with the front domain.
+fn is_legacy_key(key: &str) -> bool {
+    key.split_once('\t').is_some_and(|(_, url)| url.starts_with('/'))
+}
+
+/// The key `site` looks a legacy entry up by, `None` when `key` already has a front domain.
+fn rekey(key: &str, site: &SiteConfig) -> Option<String> {
+    if !is_legacy_key(key) {
+        return None;
+    }
+    let (method, url) = key.split_once('\t')?;
+    let method = Method::from_bytes(method.as_bytes()).ok()?;
+    site.base_cache_key(&method, url).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{IntoInnerCachedRequest, IntoInnerCachedResponse};
+    use axum::body::Bytes;
+
+    fn cached() -> CachedResponse {
+        let request = http::Request::get("/a").body(()).unwrap();
+        let response = http::Response::builder()
+            .header("cache-control", "max-age=60")
+            .body(Bytes::from_static(b"body"))
+            .unwrap();
+        CachedResponse {
+            request: request.into_inner_cached_request().unwrap(),
+            response: response.into_inner_cached_response().unwrap(),
+            cached_at: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    #[test]
+    fn round_trips_the_current_version() {
+        let bytes = encode(&cached()).unwrap();
+        assert!(bytes.starts_with(&MAGIC));
+        let (decoded, version) = decode(&bytes).unwrap();
+        assert_eq!(version, VERSION);
+        assert_eq!(decoded.response.body, b"body");
+    }
+
+    #[test]
+    fn reads_entries_written_before_the_envelope() {
+        let bare = postcard::to_allocvec(&cached()).unwrap();
+        let (decoded, version) = decode(&bare).unwrap();
+        assert_eq!(version, 0);
+        assert_eq!(decoded.request.uri, "/a");
+    }
+
+    #[test]
+    fn rejects_damaged_entries() {
+        let mut bytes = encode(&cached()).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        assert!(matches!(decode(&bytes), Err(EntryError::Checksum { .. })));
+
+        assert_eq!(decode(&bytes[..7]).err(), Some(EntryError::Truncated));
+
+        let mut newer = encode(&cached()).unwrap();
+        newer[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(decode(&newer).err(), Some(EntryError::UnsupportedVersion(VERSION + 1)));
+
+        assert!(matches!(decode(b"garbage"), Err(EntryError::Decode { version: 0, .. })));
+    }
+
+    #[test]
+    fn migrates_and_quarantines_offline() {
+        let dir = std::env::temp_dir().join(format!("chainedge-migrate-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let _ = std::fs::remove_dir_all(quarantine_dir(dir.to_str().unwrap()));
+        let dir = dir.to_str().unwrap();
+
+        cacache::write_sync(dir, "GET\ta/legacy", postcard::to_allocvec(&cached()).unwrap()).unwrap();
+        cacache::write_sync(dir, "GET\ta/legacy\te:gzip", b"gz").unwrap();
+        cacache::write_sync(dir, "GET\ta/current", encode(&cached()).unwrap()).unwrap();
+        cacache::write_sync(dir, "GET\ta/corrupt", b"garbage").unwrap();
+        cacache::write_sync(dir, "GET\ta/corrupt\te:br", b"br").unwrap();
+
+        let report = migrate(dir, true, None).unwrap();
+        assert_eq!(report.entries, 3);
+        assert_eq!(report.migrated, 1);
+        assert_eq!(report.variants, 1);
+        assert_eq!(report.quarantined, vec!["GET\ta/corrupt"]);
+
+        let (_, version) = decode(&cacache::read_sync(dir, "GET\ta/legacy").unwrap()).unwrap();
+        assert_eq!(version, VERSION);
+        assert!(cacache::read_sync(dir, "GET\ta/legacy\te:gzip").is_ok());
+        assert!(cacache::read_sync(dir, "GET\ta/corrupt").is_err());
+        assert!(cacache::read_sync(dir, "GET\ta/corrupt\te:br").is_err());
+
+        let records = quarantined(dir);
+        assert_eq!(records.len(), 1);
+        assert!(records[0].saved_bytes);
+
+        let _ = std::fs::remove_dir_all(dir);
+        let _ = std::fs::remove_dir_all(quarantine_dir(dir));
+    }
+
+    #[test]
+    fn moves_entries_keyed_without_a_front_domain() {
+        let dir = std::env::temp_dir().join(format!("chainedge-rekey-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let dir = dir.to_str().unwrap();
+
+        cacache::write_sync(dir, "GET\t/old", encode(&cached()).unwrap()).unwrap();
+        cacache::write_sync(dir, "GET\t/old\te:gzip", b"gz").unwrap();
+        cacache::write_sync(dir, "GET\t/Docs/?b=2&a=1", encode(&cached()).unwrap()).unwrap();
+        cacache::write_sync(dir, "GET\tnode.test/new", encode(&cached()).unwrap()).unwrap();
+        let site: SiteConfig = toml::from_str(
+            r#"
+            front_domain = "Node.test"
+            origin_domain = "origin.test"
+            [cache_key]
+            ignore_case = true
+            trailing_slash = "strip"
+            "#,
+        )
+        .unwrap();
+
+        let report = migrate(dir, false, Some(&site)).unwrap();
+        assert_eq!(report.entries, 3);
+        assert_eq!(report.rekeyed, 2);
+        assert!(cacache::read_sync(dir, "GET\tnode.test/old").is_ok());
+        // the key rules of the site apply, as they do to the requests looking the entry up
+        assert!(cacache::read_sync(dir, "GET\tnode.test/docs?a=1&b=2").is_ok());
+        assert!(cacache::read_sync(dir, "GET\tnode.test/new").is_ok());
+        assert!(cacache::read_sync(dir, "GET\t/old").is_err());
+        assert!(cacache::read_sync(dir, "GET\t/old\te:gzip").is_err());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
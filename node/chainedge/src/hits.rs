@@ -0,0 +1,90 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, RwLock};
+use std::time::SystemTime;
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+/// Minutes of hit rate history kept for the dashboard.
+pub const RATE_WINDOW: u64 = 60;
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct RateBucket {
+    /// Minutes since the unix epoch.
+    pub minute: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+lazy_static! {
+    /// Fresh cache hits per cache key since the node started.
+    static ref HITS: RwLock<HashMap<String, u64>> = RwLock::new(HashMap::new());
+    static ref RATE: Mutex<VecDeque<RateBucket>> = Mutex::new(VecDeque::new());
+}
+
+fn current_minute() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs() / 60)
+        .unwrap_or_default()
+}
+
+fn count(hit: bool) {
+    let minute = current_minute();
+    let mut rate = RATE.lock().unwrap();
+    if rate.back().map(|b| b.minute) != Some(minute) {
+        rate.push_back(RateBucket {
+            minute,
+            ..Default::default()
+        });
+    }
+    while rate.front().is_some_and(|b| b.minute + RATE_WINDOW <= minute) {
+        rate.pop_front();
+    }
+
+    let bucket = rate.back_mut().unwrap();
+    if hit {
+        bucket.hits += 1;
+    } else {
+        bucket.misses += 1;
+    }
+}
+
+pub fn record(cache_key: &str) {
+    *HITS.write().unwrap().entry(cache_key.to_owned()).or_default() += 1;
+    count(true);
+}
+
+/// A cacheable request that had to go to the origin.
+pub fn record_miss() {
+    count(false);
+}
+
+pub fn get(cache_key: &str) -> u64 {
+    HITS.read().unwrap().get(cache_key).copied().unwrap_or_default()
+}
+
+pub fn total() -> u64 {
+    HITS.read().unwrap().values().sum()
+}
+
+pub fn forget(cache_key: &str) {
+    HITS.write().unwrap().remove(cache_key);
+}
+
+/// One bucket per minute of the window, oldest first, including the minutes without requests.
+pub fn rate() -> Vec<RateBucket> {
+    let now = current_minute();
+    let rate = RATE.lock().unwrap();
+    (now + 1 - RATE_WINDOW..=now)
+        .map(|minute| {
+            rate.iter()
+                .find(|b| b.minute == minute)
+                .copied()
+                .unwrap_or(RateBucket {
+                    minute,
+                    ..Default::default()
+                })
+        })
+        .collect()
+}
@@ -0,0 +1,98 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    body::{Body, Bytes},
+    Router,
+};
+use h3::server::RequestStream;
+use http::{header::HOST, HeaderValue, Request, Response};
+use hyper::body::{Buf, HttpBody};
+use miette::{miette, IntoDiagnostic, Result};
+use tower::ServiceExt;
+use tracing::{debug, warn};
+
+/// Serves `app` over HTTP/3 on a QUIC endpoint bound to `addr`.
+pub async fn serve(addr: SocketAddr, tls: Arc<rustls::ServerConfig>, app: Router) -> Result<()> {
+    let endpoint = quinn::Endpoint::server(quinn::ServerConfig::with_crypto(tls), addr)
+        .into_diagnostic()?;
+
+    while let Some(connecting) = endpoint.accept().await {
+        let app = app.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(connecting, app).await {
+                debug!("HTTP/3 connection closed: {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn handle_connection(connecting: quinn::Connecting, app: Router) -> Result<()> {
+    let connection = connecting.await.into_diagnostic()?;
+    let mut connection = h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(
+        connection,
+    ))
+    .await
+    .into_diagnostic()?;
+
+    while let Some((request, stream)) = connection.accept().await.into_diagnostic()? {
+        let app = app.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_request(request, stream, app).await {
+                warn!("HTTP/3 request failed: {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn handle_request<S>(
+    request: Request<()>,
+    mut stream: RequestStream<S, Bytes>,
+    app: Router,
+) -> Result<()>
+where
+    S: h3::quic::BidiStream<Bytes>,
+{
+    let mut body = Vec::new();
+    while let Some(mut chunk) = stream.recv_data().await.into_diagnostic()? {
+        if body.len() + chunk.remaining() > crate::MAX_REQUEST_BODY {
+            let (status, message) = crate::body_too_large();
+            let response = Response::builder().status(status).body(()).into_diagnostic()?;
+            stream.send_response(response).await.into_diagnostic()?;
+            stream.send_data(message.into()).await.into_diagnostic()?;
+            return stream.finish().await.into_diagnostic();
+        }
+        body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+    }
+
+    let (mut parts, ()) = request.into_parts();
+    // HTTP/3 carries the host as `:authority`, the site lookup expects a `Host` header
+    if !parts.headers.contains_key(HOST) {
+        if let Some(authority) = parts.uri.authority() {
+            let host = HeaderValue::from_str(authority.as_str()).into_diagnostic()?;
+            parts.headers.insert(HOST, host);
+        }
+    }
+
+    let response = app
+        .oneshot(Request::from_parts(parts, Body::from(body)))
+        .await
+        .map_err(|_| miette!("Router failed"))?;
+    let (parts, mut body) = response.into_parts();
+
+    stream
+        .send_response(Response::from_parts(parts, ()))
+        .await
+        .into_diagnostic()?;
+    // frames go out as the body yields them, a large or slow response is never held whole
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| miette!("Could not read the response body: {}", e))?;
+        if !chunk.is_empty() {
+            stream.send_data(chunk).await.into_diagnostic()?;
+        }
+    }
+    stream.finish().await.into_diagnostic()
+}
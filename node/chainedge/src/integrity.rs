@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use cacache::Integrity;
+use http::{header::ACCEPT_ENCODING, HeaderMap};
+use lazy_static::lazy_static;
+use miette::{miette, Result};
+use tracing::error;
+
+use crate::keying;
+
+lazy_static! {
+    /// Expected body hashes for links registered on-chain, keyed by base cache key.
+    static ref PINNED: RwLock<HashMap<String, Integrity>> = RwLock::new(HashMap::new());
+}
+
+pub fn pin(cache_key: &str, sri: Integrity) {
+    PINNED.write().unwrap().insert(cache_key.to_owned(), sri);
+}
+
+pub fn unpin(cache_key: &str) {
+    PINNED.write().unwrap().remove(cache_key);
+}
+
+/// Replaces the pins of the given front domains, e.g. with the pins of the whole CDN list once
+/// it is read again. Pins of other front domains, which other nodes in the process may hold, stay.
+pub fn replace(front_domains: &[String], pins: HashMap<String, Integrity>) {
+    let mut pinned = PINNED.write().unwrap();
+    pinned.retain(|key, _| {
+        let front_domain = keying::front_domain(key);
+        !front_domains.iter().any(|d| d.eq_ignore_ascii_case(front_domain))
+    });
+    pinned.extend(pins);
+}
+
+pub fn expected(cache_key: &str) -> Option<Integrity> {
+    PINNED.read().unwrap().get(cache_key).cloned()
+}
+
+/// Pins are hashes of the unencoded body, so the origin must not be asked to
+/// compress the body of a pinned key. The edge compresses it for the client.
+pub fn strip_accept_encoding(cache_key: &str, headers: &mut HeaderMap) {
+    if expected(cache_key).is_some() {
+        headers.remove(ACCEPT_ENCODING);
+    }
+}
+
+/// Checks an origin body against the hash pinned on-chain for `cache_key`, if any.
+/// A mismatch raises an alert and must not be cached.
+pub fn verify(cache_key: &str, body: &[u8]) -> Result<()> {
+    let Some(sri) = expected(cache_key) else {
+        return Ok(());
+    };
+
+    if sri.check(body).is_ok() {
+        return Ok(());
+    }
+
+    let actual = Integrity::from(body);
+    error!(
+        target: "chainedge::alert",
+        expected = %sri,
+        actual = %actual,
+        "Integrity mismatch for {}, refusing to cache origin response", cache_key
+    );
+
+    Err(miette!("Integrity mismatch for {}", cache_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the pins are global, so a single test changes them
+    #[test]
+    fn verifies_bodies_against_their_pins() {
+        let key = "GET\tintegrity.test/pinned";
+        assert!(verify(key, b"anything").is_ok());
+
+        pin(key, Integrity::from(b"pinned"));
+        assert!(verify(key, b"pinned").is_ok());
+        assert!(verify(key, b"tampered").is_err());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, "gzip, br".parse().unwrap());
+        strip_accept_encoding("GET\tintegrity.test/other", &mut headers);
+        assert!(headers.contains_key(ACCEPT_ENCODING));
+        strip_accept_encoding(key, &mut headers);
+        assert!(!headers.contains_key(ACCEPT_ENCODING));
+
+        unpin(key);
+        assert!(expected(key).is_none());
+        assert!(verify(key, b"tampered").is_ok());
+
+        pin(key, Integrity::from(b"pinned"));
+        let other = "GET\tother.integrity.test/pinned";
+        pin(other, Integrity::from(b"other"));
+        let listed = "GET\tintegrity.test/listed";
+        let domains = ["Integrity.test".to_owned()];
+        replace(&domains, HashMap::from([(listed.to_owned(), Integrity::from(b"listed"))]));
+        assert!(expected(key).is_none());
+        assert!(verify(listed, b"tampered").is_err());
+        assert!(expected(other).is_some());
+        replace(&domains, HashMap::new());
+        unpin(other);
+    }
+}
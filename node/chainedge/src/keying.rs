@@ -0,0 +1,320 @@
+use cacache::Integrity;
+use http::{header::COOKIE, HeaderMap, Method};
+use miette::{IntoDiagnostic, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{cache_key, config::SiteConfig, link::normalize_path_and_query};
+
+/// Per-site rules deciding which parts of a request make up its cache key.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CacheKeyRules {
+    /// When non-empty, only these query params are kept. A trailing `*` matches a prefix.
+    pub include_query_params: Vec<String>,
+    /// Query params dropped from the key, e.g. `utm_*`.
+    pub exclude_query_params: Vec<String>,
+    /// Lowercase the path before keying.
+    pub ignore_case: bool,
+    pub trailing_slash: TrailingSlash,
+    /// Request headers whose values become part of the key.
+    pub headers: Vec<String>,
+    /// Cookies whose values become part of the key.
+    pub cookies: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TrailingSlash {
+    #[default]
+    Keep,
+    Strip,
+    Add,
+}
+
+/// Header and cookie values are escaped in the key, so none can pass for the tab
+/// separating key components, e.g. `\te:gzip` or `\tb:<hash>`.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '%' => escaped.push_str("%25"),
+            '\t' => escaped.push_str("%09"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The base key a full key was built from, without its header, cookie, body or encoding
+/// components. Pins are keyed by it, so every variant of a link is checked against its pin.
+pub fn base_key(cache_key: &str) -> &str {
+    match cache_key.match_indices('\t').nth(1) {
+        Some((i, _)) => &cache_key[..i],
+        None => cache_key,
+    }
+}
+
+/// The front domain a key was built for.
+pub fn front_domain(cache_key: &str) -> &str {
+    let url = cache_key.split_once('\t').map_or(cache_key, |(_, url)| url);
+    url.split(['/', '\t']).next().unwrap_or_default()
+}
+
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+impl CacheKeyRules {
+    /// Rewrites a `path?query` the way it is keyed for this site.
+    pub fn path_and_query(&self, path_and_query: &str) -> Result<String> {
+        let normalized = normalize_path_and_query(path_and_query).into_diagnostic()?;
+        let (path, query) = match normalized.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (normalized.as_str(), None),
+        };
+
+        let mut path = if self.ignore_case {
+            path.to_lowercase()
+        } else {
+            path.to_owned()
+        };
+        match self.trailing_slash {
+            TrailingSlash::Keep => {}
+            TrailingSlash::Strip => {
+                while path.len() > 1 && path.ends_with('/') {
+                    path.pop();
+                }
+            }
+            TrailingSlash::Add => {
+                if !path.ends_with('/') {
+                    path.push('/');
+                }
+            }
+        }
+
+        let params: Vec<&str> = query
+            .into_iter()
+            .flat_map(|q| q.split('&'))
+            .filter(|p| {
+                let name = p.split_once('=').map_or(*p, |(k, _)| k);
+                (self.include_query_params.is_empty()
+                    || self.include_query_params.iter().any(|i| matches_pattern(i, name)))
+                    && !self.exclude_query_params.iter().any(|e| matches_pattern(e, name))
+            })
+            .collect();
+
+        if !params.is_empty() {
+            path.push('?');
+            path.push_str(&params.join("&"));
+        }
+
+        Ok(path)
+    }
+
+    /// The `\t`-separated header and cookie components appended to the base key.
+    pub fn variant(&self, headers: &HeaderMap) -> String {
+        let mut variant = String::new();
+
+        for name in &self.headers {
+            let values: Vec<&str> = headers
+                .get_all(name.as_str())
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .collect();
+            if !values.is_empty() {
+                variant.push_str(&format!(
+                    "\th:{}={}",
+                    name.to_ascii_lowercase(),
+                    escape(&values.join(","))
+                ));
+            }
+        }
+
+        if !self.cookies.is_empty() {
+            let cookies: Vec<(&str, &str)> = headers
+                .get_all(COOKIE)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(';'))
+                .filter_map(|c| c.trim().split_once('='))
+                .collect();
+            for name in &self.cookies {
+                if let Some((_, value)) = cookies.iter().find(|(n, _)| n == name) {
+                    variant.push_str(&format!("\tc:{}={}", name, escape(value)));
+                }
+            }
+        }
+
+        variant
+    }
+}
+
+impl SiteConfig {
+    /// Key of the entry shared by every variant of a request, as used by `populate` and `remove`.
+    pub fn base_cache_key(&self, method: &Method, path_and_query: &str) -> Result<String> {
+        let path_and_query = self.cache_key.path_and_query(path_and_query)?;
+        Ok(cache_key(
+            method,
+            format!("{}{}", self.front_domain.to_ascii_lowercase(), path_and_query),
+        ))
+    }
+
+    /// Full key of a request. Methods other than GET and HEAD also key on a hash of the body.
+    pub fn cache_key(
+        &self,
+        method: &Method,
+        path_and_query: &str,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<String> {
+        let mut key = format!(
+            "{}{}",
+            self.base_cache_key(method, path_and_query)?,
+            self.cache_key.variant(headers)
+        );
+        if method != Method::GET && method != Method::HEAD {
+            key.push_str(&format!("\tb:{}", Integrity::from(body)));
+        }
+
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site(toml: &str) -> SiteConfig {
+        let site = format!(
+            "front_domain = \"Edge.test\"\norigin_domain = \"origin.test\"\n{}",
+            toml
+        );
+        toml::from_str(&site).unwrap()
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(
+                http::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+        }
+        headers
+    }
+
+    #[test]
+    fn normalizes_the_path_and_query() {
+        let rules = CacheKeyRules::default();
+        assert_eq!(rules.path_and_query("/a/./b/../c?z=1&a=2").unwrap(), "/a/c?a=2&z=1");
+        assert_eq!(rules.path_and_query("/A/").unwrap(), "/A/");
+
+        let rules = site(
+            r#"
+            [cache_key]
+            exclude_query_params = ["utm_*"]
+            ignore_case = true
+            trailing_slash = "strip"
+            "#,
+        )
+        .cache_key;
+        assert_eq!(rules.path_and_query("/A/B//?utm_source=x&q=1").unwrap(), "/a/b?q=1");
+        assert_eq!(rules.path_and_query("/?utm_source=x").unwrap(), "/");
+
+        let rules = site(
+            r#"
+            [cache_key]
+            include_query_params = ["page", "sort*"]
+            trailing_slash = "add"
+            "#,
+        )
+        .cache_key;
+        assert_eq!(
+            rules.path_and_query("/list?page=2&session=s&sort_by=name").unwrap(),
+            "/list/?page=2&sort_by=name"
+        );
+    }
+
+    #[test]
+    fn keys_on_the_configured_headers_and_cookies() {
+        let rules = site(
+            r#"
+            [cache_key]
+            headers = ["Accept-Language"]
+            cookies = ["theme"]
+            "#,
+        )
+        .cache_key;
+
+        assert_eq!(rules.variant(&HeaderMap::new()), "");
+        let request = headers(&[
+            ("accept-language", "en"),
+            ("accept-language", "fr"),
+            ("cookie", "session=s; theme=dark"),
+            ("user-agent", "test"),
+        ]);
+        assert_eq!(rules.variant(&request), "\th:accept-language=en,fr\tc:theme=dark");
+    }
+
+    #[test]
+    fn escapes_values_that_look_like_separators() {
+        let rules = site(
+            r#"
+            [cache_key]
+            headers = ["X-Variant"]
+            cookies = ["theme"]
+            "#,
+        )
+        .cache_key;
+
+        let request = headers(&[("x-variant", "a\te:gzip"), ("cookie", "theme=50%\tb:x")]);
+        assert_eq!(rules.variant(&request), "\th:x-variant=a%09e:gzip\tc:theme=50%25%09b:x");
+        assert_ne!(
+            rules.variant(&headers(&[("x-variant", "a%09")])),
+            rules.variant(&headers(&[("x-variant", "a\t")]))
+        );
+    }
+
+    #[test]
+    fn prefixes_the_front_domain_and_hashes_bodies() {
+        let site = site("");
+        assert_eq!(
+            site.cache_key(&Method::GET, "/a?b=1", &HeaderMap::new(), b"ignored").unwrap(),
+            "GET\tedge.test/a?b=1"
+        );
+        assert_eq!(
+            site.base_cache_key(&Method::GET, "/a?b=1").unwrap(),
+            "GET\tedge.test/a?b=1"
+        );
+
+        let post = site.cache_key(&Method::POST, "/search", &HeaderMap::new(), b"q=1").unwrap();
+        assert_eq!(post, format!("POST\tedge.test/search\tb:{}", Integrity::from(b"q=1")));
+        let other = site.cache_key(&Method::POST, "/search", &HeaderMap::new(), b"q=2").unwrap();
+        assert_ne!(post, other);
+    }
+
+    #[test]
+    fn parses_the_base_key_and_front_domain() {
+        let site = site(
+            r#"
+            [cache_key]
+            headers = ["Accept-Language"]
+            "#,
+        );
+        let base = site.base_cache_key(&Method::POST, "/search").unwrap();
+        let full = site
+            .cache_key(&Method::POST, "/search", &headers(&[("accept-language", "en")]), b"q=1")
+            .unwrap();
+        assert_ne!(full, base);
+        assert_eq!(base_key(&full), base);
+        assert_eq!(base_key(&base), base);
+        assert_eq!(base_key("GET\tedge.test/a\te:gzip"), "GET\tedge.test/a");
+
+        assert_eq!(front_domain(&full), "edge.test");
+        assert_eq!(front_domain("GET\tedge.test"), "edge.test");
+        assert_eq!(front_domain("GET\tedge.test\th:x=1"), "edge.test");
+    }
+}
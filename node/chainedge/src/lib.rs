@@ -0,0 +1,1042 @@
+use std::{fmt::Display, net::SocketAddr, time::SystemTime, error::Error, time::Duration};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Host, State},
+    response::IntoResponse,
+    RequestExt, Router,
+};
+
+use http::{
+    header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, TRANSFER_ENCODING},
+    uri::PathAndQuery, HeaderMap, Method, Request, Response, StatusCode, Uri, Version};
+use http_cache_semantics::{AfterResponse, BeforeRequest, CachePolicy};
+use lazy_static::lazy_static;
+use maud::html;
+use miette::{miette, Context, IntoDiagnostic, Result};
+use serde::{Deserialize, Serialize};
+use tower_cookies::CookieManagerLayer;
+use tracing::info;
+
+use ethers::prelude::*;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::sync::atomic::Ordering; 
+use tokio::time::sleep;
+use tokio::signal;
+use tower_http::timeout::TimeoutLayer;
+use futures::StreamExt;
+
+pub mod acme;
+pub mod admin;
+pub mod cdn_list;
+pub mod chain;
+pub mod compression;
+pub mod config;
+pub mod envelope;
+pub mod hits;
+pub mod http3;
+pub mod integrity;
+pub mod keying;
+pub mod link;
+pub mod maintenance;
+pub mod memory;
+pub mod populate;
+pub mod purge;
+pub mod range;
+pub mod reporter;
+pub mod rules;
+pub mod request_log;
+pub mod snapshot;
+pub mod tls;
+
+const PROXY_FROM_DOMAIN: &str = "node1.chainedge.io:3001";
+const PROXY_ORIGIN_DOMAIN: &str = "node2.chainedge.io:3000";
+pub const CONTRACT_ADDRESS: &str = "0x365D9FFd3334d12f89f8510cd352b3DbB5f4Cf85";
+pub const RPC_URL: &str = "https://rpc.open-campus-codex.gelato.digital/";
+
+abigen!(IChainEdge, "./src/ChainEdge.json");
+
+#[derive(Debug, Clone)]
+struct AppState {
+    admin_password: String,
+    accumulated_cnt: Arc<AtomicU64>,
+    config: Arc<config::Config>,
+    acme_challenges: Arc<acme::Challenges>,
+    started_at: SystemTime,
+    chain: Arc<dyn chain::ChainBackend>,
+    /// Encrypts the admin session cookie, sessions end with the process.
+    cookie_key: debug_ignore::DebugIgnore<tower_cookies::Key>,
+}
+
+#[derive(Debug, Clone, EthEvent)]
+pub struct NewLink {
+    pub link: String,
+}
+
+#[derive(Debug, Clone, EthEvent)]
+pub struct RemoveLink {
+    pub link: String,
+}
+
+/// A report not mined within this long is counted as failed, its receipt is still polled.
+const REPORT_TIMEOUT: Duration = Duration::from_secs(300);
+/// Wait before following the contract events again after the stream ended.
+const EVENTS_RETRY: Duration = Duration::from_secs(10);
+/// Request bodies are buffered to key and forward them, larger ones are refused
+/// over HTTP/1, HTTP/2 and HTTP/3 alike.
+pub(crate) const MAX_REQUEST_BODY: usize = 2 * 1024 * 1024;
+
+fn start_record_thread(chain: Arc<dyn chain::ChainBackend>, 
+                            accumulate_cnt: Arc<AtomicU64>, 
+                            stop_flag: Arc<AtomicBool>) -> Result<tokio::task::JoinHandle<()>, Box<dyn Error>> {
+    let jh = tokio::spawn(async move {
+        loop {
+            if stop_flag.load(Ordering::Relaxed) {
+                break;
+            }
+
+            let cnt = accumulate_cnt.swap(0u64, Ordering::SeqCst);
+            if cnt > 0 {
+                let hash = match chain.add_serve_count(cnt).await {
+                    Ok(hash) => hash,
+                    Err(e) => {
+                        // not sent, so the bytes are reported with the next attempt
+                        accumulate_cnt.fetch_add(cnt, Ordering::SeqCst);
+                        println!("could not report count: {}", e);
+                        reporter::failed(e.to_string());
+                        sleep(tokio::time::Duration::from_secs(5)).await;
+                        continue;
+                    }
+                };
+                // a transaction that is slow to mine may still be, so the bytes are not
+                // counted as unreported until its receipt says it reverted
+                let receipt = loop {
+                    if let Some(receipt) = chain::wait_for_receipt(chain.as_ref(), hash, REPORT_TIMEOUT).await {
+                        break Some(receipt);
+                    }
+                    if stop_flag.load(Ordering::Relaxed) {
+                        break None;
+                    }
+                    println!("report transaction {:?} not mined yet", hash);
+                    reporter::failed(format!("transaction {:?} not mined yet", hash));
+                };
+                match receipt {
+                    Some(receipt) if receipt.success => {}
+                    Some(_) => {
+                        // reverted, so the bytes are reported with the next attempt
+                        accumulate_cnt.fetch_add(cnt, Ordering::SeqCst);
+                        println!("report transaction {:?} failed", hash);
+                        reporter::failed(format!("transaction {:?} failed", hash));
+                        continue;
+                    }
+                    None => break,
+                }
+
+                match chain.serve_count().await {
+                    Ok(total) => {
+                        println!("added count: {}, total count: {}", cnt, total);
+                        reporter::reported(cnt, Some(total.to_string()));
+                    }
+                    Err(e) => {
+                        println!("added count: {}, total count unknown: {}", cnt, e);
+                        reporter::reported(cnt, None);
+                    }
+                }
+            } else {
+                sleep(tokio::time::Duration::from_secs(1)).await;        
+            }
+        }
+        println!("record task exit");
+    });
+
+    Ok(jh)    
+}
+
+fn start_event_listening(chain: Arc<dyn chain::ChainBackend>, config: Arc<config::Config>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let mut stream = match chain.link_events().await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    println!("could not follow contract events: {}", e);
+                    sleep(EVENTS_RETRY).await;
+                    continue;
+                }
+            };
+            // read after subscribing, so no link added in between goes unpinned
+            match chain.cdn_list().await {
+                Ok(links) => populate::seed_pins(&links, &config),
+                Err(e) => println!("could not read the CDN list to pin its links: {}", e),
+            }
+            while let Some(evt) = stream.next().await {
+                match evt {
+                    Ok(chain::LinkEvent::Added(link)) => {
+                        println!("Fetch link: {link}");
+                        let _ = populate::populate(link, &config).await.map_err(|e| println!("{}", e.0));
+                    },
+                    Ok(chain::LinkEvent::Removed(link)) => {
+                        println!("Remove {link}");
+                        let _ = populate::remove(link, &config).await.map_err(|e| println!("{}", e.0));
+                    },
+                    Err(e) => {
+                        println!("contract event stream failed: {}", e);
+                        break;
+                    }
+                }
+            }
+            sleep(EVENTS_RETRY).await;
+        }
+    })
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install signal handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(unix)]
+    let quit = async {
+        signal::unix::signal(signal::unix::SignalKind::quit())
+            .expect("failed to install signal handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let quit = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => { println!("CTRL-C detected"); },
+        _ = terminate => { println!("SIGTERM detected"); },
+        _ = quit => { println!("SIGQUIT detected"); },
+    }
+}
+
+/// Everything a node runs with besides its listening socket.
+pub struct Node {
+    pub config: config::Config,
+    /// Password of the admin pages and bearer token of the admin API.
+    pub admin_password: String,
+    pub chain: Arc<dyn chain::ChainBackend>,
+}
+
+/// Runs the node until it is asked to shut down.
+pub async fn run() -> Result<()> {
+    let admin_password = std::env::var("ADMIN_AUTH_KEY").into_diagnostic()?;
+    let config = config::Config::load()?;
+
+    let chain = chain::connect(&config.chain).await?;
+    info!("Following the chain through the {:?} backend", config.chain.backend);
+
+    let listener = std::net::TcpListener::bind(&config.listen)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Could not listen on {}", config.listen))?;
+
+    let node = Node {
+        config,
+        admin_password,
+        chain,
+    };
+    serve(node, listener, shutdown_signal()).await
+}
+
+/// Serves plain HTTP on `listener`, and HTTPS when configured, until `shutdown` completes.
+pub async fn serve(
+    node: Node,
+    listener: std::net::TcpListener,
+    shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+) -> Result<()> {
+    let Node {
+        config,
+        admin_password,
+        chain,
+    } = node;
+    config.validate()?;
+    let config = Arc::new(config);
+    memory::configure(&config.memory_cache);
+
+    let stop_flag = Arc::new(AtomicBool::new(false));
+    let accumulated_cnt = Arc::new(AtomicU64::new(0));
+
+    let acme_challenges = Arc::new(acme::Challenges::default());
+
+    let app_state = AppState {
+        admin_password,
+        accumulated_cnt: accumulated_cnt.clone(),
+        config: config.clone(),
+        acme_challenges: acme_challenges.clone(),
+        started_at: SystemTime::now(),
+        chain: chain.clone(),
+        cookie_key: debug_ignore::DebugIgnore(tower_cookies::Key::generate()),
+    };
+
+    let record_jh = start_record_thread(chain.clone(), accumulated_cnt, stop_flag.clone())
+                        .map_err(|_| miette!("record thread error"))?;
+    let event_jh = start_event_listening(chain, config.clone());
+
+    let app = Router::new()
+        .route("/_chainedge/auth", axum::routing::get(admin::auth::get))
+        .route("/_chainedge/auth", axum::routing::post(admin::auth::post))
+        .route("/_chainedge/list", axum::routing::get(admin::list::route))
+        .route(
+            "/_chainedge/clear_fs",
+            axum::routing::post(admin::clear_fs::route),
+        )
+        .route("/_chainedge/entry", axum::routing::get(admin::entry::get))
+        .route(
+            "/_chainedge/entry/purge",
+            axum::routing::post(admin::entry::purge),
+        )
+        .route(
+            "/_chainedge/entry/refresh",
+            axum::routing::post(admin::entry::refresh),
+        )
+        .route("/_chainedge/events", axum::routing::get(admin::events::route))
+        .route(
+            "/_chainedge/links",
+            axum::routing::get(admin::links::get).post(admin::links::post),
+        )
+        .nest("/_chainedge/api/v1", admin::api::routes())
+        .route(
+            &format!("{}:token", acme::HTTP_CHALLENGE_PREFIX),
+            axum::routing::get(acme::http_challenge),
+        )
+        .fallback(proxy_request)
+        .layer((CookieManagerLayer::new(), TimeoutLayer::new(Duration::from_secs(6)),))
+        // Snapshots of a whole cache take longer than the timeout to stream
+        .route(
+            "/_chainedge/api/v1/snapshot",
+            axum::routing::get(admin::api::snapshot::export).post(admin::api::snapshot::import),
+        )
+        .with_state(app_state);
+
+    let tls_handle = axum_server::Handle::new();
+    let shutdown = {
+        let tls_handle = tls_handle.clone();
+        async move {
+            shutdown.await;
+            tls_handle.graceful_shutdown(Some(Duration::from_secs(6)));
+        }
+    };
+
+    let mut h3_jh = None;
+    let tls_server = match &config.tls {
+        Some(tls_config) => {
+            let resolver = Arc::new(tls::CertResolver::load(&config)?);
+            tls::start_reload_thread(
+                resolver.clone(),
+                Duration::from_secs(tls_config.reload_interval_secs),
+            );
+            acme::start_renewal_thread(config.clone(), acme_challenges, resolver.clone());
+
+            let tls_addr: SocketAddr = tls_config.listen.parse().into_diagnostic()?;
+            tracing::debug!("listening on {} (tls)", tls_addr);
+            let tls_app = app.clone().layer(axum::middleware::from_fn_with_state(
+                config.clone(),
+                tls::secure_headers,
+            ));
+
+            if tls_config.http3 {
+                tracing::debug!("listening on {} (http/3)", tls_addr);
+                let quic_config = tls::quic_server_config(resolver.clone());
+                let h3_app = tls_app.clone();
+                h3_jh = Some(tokio::spawn(async move {
+                    if let Err(e) = http3::serve(tls_addr, quic_config, h3_app).await {
+                        tracing::error!("HTTP/3 listener failed: {}", e);
+                    }
+                }));
+            }
+
+            Some(
+                axum_server::bind_rustls(
+                    tls_addr,
+                    axum_server::tls_rustls::RustlsConfig::from_config(tls::server_config(resolver)),
+                )
+                .handle(tls_handle)
+                .serve(tls_app.into_make_service()),
+            )
+        }
+        None => None,
+    };
+
+    let plain_app = app.layer(axum::middleware::from_fn_with_state(
+        config.clone(),
+        tls::redirect_http,
+    ));
+    tracing::debug!("listening on {:?}", listener.local_addr());
+    let plain_server = axum::Server::from_tcp(listener)
+        .into_diagnostic()?
+        .serve(plain_app.into_make_service())
+        .with_graceful_shutdown(shutdown);
+
+    match tls_server {
+        Some(tls_server) => {
+            let (plain, tls) = tokio::join!(plain_server, tls_server);
+            plain.into_diagnostic()?;
+            tls.into_diagnostic()?;
+        }
+        None => plain_server.await.into_diagnostic()?,
+    }
+
+    if let Some(h3_jh) = h3_jh {
+        h3_jh.abort();
+    }
+
+    stop_flag.store(true, Ordering::Relaxed);
+    record_jh.await.into_diagnostic()?;
+    event_jh.abort();
+
+    Ok(())
+}
+
+// #[axum_macros::debug_handler]
+async fn proxy_request(
+    State(app_state): State<AppState>,
+    mut request: Request<Body>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let host: Host = request
+        .extract_parts()
+        .await
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Could not extract host".to_owned()))?;
+
+    let Some(site) = app_state.config.site_for_host(&host.0).cloned() else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("We only proxy requests to the configured domains. Found: {}", host.0),
+        ));
+    };
+
+    let started = std::time::Instant::now();
+    let mut logged = request_log::LoggedRequest {
+        at: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        method: request.method().to_string(),
+        host: host.0.clone(),
+        path: request
+            .uri()
+            .path_and_query()
+            .map_or("/", |p| p.as_str())
+            .to_owned(),
+        status: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+        cache: None,
+        bytes: 0,
+        millis: 0,
+    };
+
+    let (parts, body) = request.into_parts();
+    let body = match read_body(body).await {
+        Ok(body) => body,
+        Err(e) => {
+            logged.status = e.0.as_u16();
+            request_log::log(logged);
+            return Err(e);
+        }
+    };
+    let request = Request::from_parts(parts, Body::from(body));
+
+    let response = get_potentially_cached_response(request, app_state, site).await;
+    logged.millis = started.elapsed().as_millis() as u64;
+    let response = match response {
+        Ok(response) => response,
+        Err(e) => {
+            request_log::log(logged);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+        }
+    };
+    logged.status = response.status().as_u16();
+    logged.cache = response.extensions().get::<request_log::CacheStatus>().copied();
+    logged.bytes = response.body().len();
+    request_log::log(logged);
+
+    let mut headers = response.headers().clone();
+    strip_hop_by_hop_headers(&mut headers);
+    rules::strip_edge_headers(&mut headers);
+
+    Ok((response.status(), headers, response.into_body()))
+}
+
+pub(crate) fn body_too_large() -> (StatusCode, String) {
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        format!("Request bodies are limited to {} bytes", MAX_REQUEST_BODY),
+    )
+}
+
+/// Buffers the request body, refusing it as soon as it grows past `MAX_REQUEST_BODY`.
+async fn read_body(mut body: Body) -> Result<Bytes, (StatusCode, String)> {
+    use hyper::body::HttpBody;
+
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| {
+            (StatusCode::BAD_REQUEST, format!("Could not read the request body: {}", e))
+        })?;
+        if bytes.len() + chunk.len() > MAX_REQUEST_BODY {
+            return Err(body_too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes.into())
+}
+
+/// Connection-specific headers of the origin connection, which HTTP/2 and HTTP/3
+/// clients treat as malformed.
+fn strip_hop_by_hop_headers(headers: &mut HeaderMap) {
+    let listed: Vec<String> = headers
+        .get_all(http::header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    for name in listed {
+        headers.remove(name.as_str());
+    }
+
+    for name in [
+        "connection",
+        "keep-alive",
+        "proxy-connection",
+        "transfer-encoding",
+        "upgrade",
+        "te",
+        "trailer",
+    ] {
+        headers.remove(name);
+    }
+}
+
+lazy_static! {
+    /// Where responses are stored, `CHAINEDGE_CACHE_DIR` or `./tmp/cache`.
+    static ref CACHE_DIR: String =
+        std::env::var("CHAINEDGE_CACHE_DIR").unwrap_or_else(|_| "./tmp/cache".to_owned());
+}
+
+#[derive(Deserialize, Serialize)]
+struct InnerCachedRequest {
+    #[serde(with = "http_serde::method")]
+    pub method: Method,
+
+    #[serde(with = "http_serde::uri")]
+    pub uri: Uri,
+
+    #[serde(with = "http_serde::version")]
+    pub version: Version,
+
+    #[serde(with = "http_serde::header_map")]
+    pub headers: HeaderMap,
+
+    // TODO: Can this just be a Bytes
+    body: Option<Vec<u8>>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+struct InnerCachedResponse {
+    #[serde(with = "http_serde::status_code")]
+    pub status_code: StatusCode,
+
+    #[serde(with = "http_serde::version")]
+    pub version: Version,
+
+    #[serde(with = "http_serde::header_map")]
+    pub headers: HeaderMap,
+
+    body: Vec<u8>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct CachedResponse {
+    request: InnerCachedRequest,
+    response: InnerCachedResponse,
+    cached_at: SystemTime,
+}
+
+/// Returns the policy and response stored under `key`, along with the uri of the request
+/// they were stored for, which requests mapping to the same key have to be matched against.
+async fn get_policy_from_cache(key: &str) -> Result<(CachePolicy, http::Response<Bytes>, Uri)> {
+    policy_from_cached(read_cached(key).await?)
+}
+
+/// Same as `get_policy_from_cache`, answered from the memory tier when the entry is hot.
+async fn lookup_cached(key: &str) -> Result<(CachePolicy, http::Response<Bytes>, Uri)> {
+    if let Some(hot) = memory::get(key) {
+        return Ok(hot);
+    }
+    let generation = memory::generation(key);
+    let (policy, response, uri) = get_policy_from_cache(key).await?;
+    memory::offer(key, generation, &policy, &response, &uri);
+    Ok((policy, response, uri))
+}
+
+fn policy_from_cached(cached: CachedResponse) -> Result<(CachePolicy, http::Response<Bytes>, Uri)> {
+    let response = http_response_from_parts(cached.response)
+        .map_err(|_| miette!("Could not build response"))?;
+
+    let request =
+        http_request_from_parts(cached.request).map_err(|_| miette!("Could not build request"))?;
+
+    let policy = rules::edge_policy(&request, &response, cached.cached_at);
+    let mut response = response;
+    response.headers_mut().remove(rules::EDGE_CACHE_CONTROL);
+
+    Ok((policy, response, request.uri().clone()))
+}
+
+/// Reads and decodes the entry. One that is damaged or can not be decoded is quarantined,
+/// so the next request for it goes to the origin instead of failing again.
+async fn read_cached(key: &str) -> Result<CachedResponse> {
+    let (bytes, reason) = match cacache::read(CACHE_DIR.as_str(), key).await {
+        Ok(bytes) => match envelope::decode(&bytes) {
+            Ok((cached, _)) => return Ok(cached),
+            Err(e) => (Some(bytes), e.to_string()),
+        },
+        Err(e @ (cacache::Error::IntegrityError(_) | cacache::Error::SizeMismatch(..))) => {
+            (None, e.to_string())
+        }
+        Err(e) => return Err(e).context("Could not read from cache"),
+    };
+
+    let quarantined_key = key.to_owned();
+    let quarantined_reason = reason.clone();
+    tokio::task::spawn_blocking(move || {
+        envelope::quarantine(
+            CACHE_DIR.as_str(),
+            &quarantined_key,
+            bytes.as_deref(),
+            &quarantined_reason,
+        )
+    })
+    .await
+    .into_diagnostic()??;
+    memory::invalidate(key);
+    hits::forget(key);
+
+    Err(miette!("Quarantined unreadable cache entry: {}", reason))
+}
+
+pub fn cache_key(method: impl Display, url: impl Display) -> String {
+    format!("{}\t{}", method, url)
+}
+
+pub struct WrappedError(miette::Report);
+
+impl IntoResponse for WrappedError {
+    fn into_response(self) -> axum::response::Response {
+        let err = self.0.to_string();
+        let resp = html! {
+            h1 { "Error" }
+            p { (err) }
+        };
+
+        (StatusCode::INTERNAL_SERVER_ERROR, resp).into_response()
+    }
+}
+
+impl<E> From<E> for WrappedError
+where
+    E: Into<miette::Report>,
+{
+    fn from(err: E) -> Self {
+        Self(err.into())
+    }
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_potentially_cached_response(
+    request: Request<Body>,
+    app_state: AppState,
+    site: config::SiteConfig,
+) -> Result<http::Response<Bytes>> {
+
+    let method = request.method().clone();
+    let url = request.uri().clone();
+    info!("Requesting: {}", url);
+
+    let (request_parts, body) = request.into_parts();
+    let headers = request_parts.headers.clone();
+    let bytes = hyper::body::to_bytes(body)
+        .await
+        .map_err(|_| miette!("Could not get bytes from body"))?;
+
+    // HEAD is answered from the GET entry, without a body
+    let lookup_method = if method == Method::HEAD {
+        Method::GET
+    } else {
+        method.clone()
+    };
+    let path_and_query = url.path_and_query().map_or("/", |p| p.as_str());
+    let bypassed = site
+        .request_rule(&lookup_method, path_and_query)
+        .is_some_and(|r| r.action == rules::CacheAction::Bypass);
+    let cacheable = site.is_cacheable(&method) && !bypassed;
+    let cache_key = site.cache_key(&lookup_method, path_and_query, &headers, &bytes)?;
+    // pins cover every variant of a link, so they are looked up by the base key
+    let base_key = site.base_cache_key(&lookup_method, path_and_query)?;
+    // a stale entry the origin may still confirm, with the conditional request to ask it
+    let mut revalidation = None;
+
+    if cacheable {
+        let policy = lookup_cached(&cache_key).await;
+
+        if let Ok((policy, response, stored_uri)) = policy {
+            // the key already decided the requests are equivalent, so match against the stored uri
+            let mut probe = Request::builder().method(lookup_method.clone()).uri(stored_uri);
+            for (key, value) in headers.iter() {
+                probe = probe.header(key, value);
+            }
+            let probe = probe
+                .body(())
+                .map_err(|_| miette!("Could not build request"))?;
+
+            let can_cache = policy.before_request(&probe, SystemTime::now());
+
+            match can_cache {
+                // TODO: Use the Parts from Fresh to build the response
+                BeforeRequest::Fresh(parts) => {
+                    info!(parts =? parts, "Cache hit for: {}", url);
+                    hits::record(&cache_key);
+                    let response = range::apply(&headers, response)?;
+                    let mut response = compression::apply(
+                        &site.compression,
+                        &headers,
+                        response,
+                        Some(&cache_key),
+                    )
+                    .await?;
+                    let rule = site.response_rule(&lookup_method, path_and_query, response.headers());
+                    rules::apply_browser_ttl(rule, response.headers_mut());
+                    response.extensions_mut().insert(request_log::CacheStatus::Hit);
+                    if method == Method::HEAD {
+                        return Ok(response.map(|_| Bytes::new()));
+                    }
+                    let resp_size = response.body().len();
+                    app_state.accumulated_cnt.fetch_add(resp_size as u64, Ordering::SeqCst);
+                    return Ok(response);
+                }
+                BeforeRequest::Stale {
+                    matches,
+                    request: revalidation_request,
+                } => {
+                    info!(
+                        matches =? matches,
+                        revalidation_request =? revalidation_request,
+                        original_request =? probe,
+                        ttl =? policy.time_to_live(SystemTime::now()),
+                        "Cache hit for: {} but not-usable", url
+                    );
+                    if matches && method != Method::HEAD {
+                        revalidation = Some((policy, response, revalidation_request));
+                    }
+                }
+            };
+        }
+    }
+
+    let mut cache_status = if cacheable {
+        hits::record_miss();
+        request_log::CacheStatus::Miss
+    } else {
+        request_log::CacheStatus::Bypass
+    };
+
+    let path = url
+        .path_and_query()
+        .cloned()
+        .unwrap_or_else(|| PathAndQuery::from_static("/"));
+
+    let proxy_url = http::Uri::builder()
+        .scheme("http")
+        .authority(site.origin_domain.as_str())
+        .path_and_query(path.clone())
+        .build()
+        .map_err(|_| miette!("Could not build url"))?;
+
+    // fetch the full object so it can be cached, ranges are then served from it
+    let mut origin_request_headers = headers.clone();
+    if cacheable && method == Method::GET {
+        range::strip_range_headers(&mut origin_request_headers);
+    }
+    integrity::strip_accept_encoding(&base_key, &mut origin_request_headers);
+
+    // a stale entry is asked for with the validators it was stored with
+    let conditional_headers = revalidation.as_ref().map(|(_, _, revalidation_request)| {
+        let mut conditional_headers = revalidation_request.headers.clone();
+        range::strip_range_headers(&mut conditional_headers);
+        integrity::strip_accept_encoding(&base_key, &mut conditional_headers);
+        conditional_headers
+    });
+    let mut parts = fetch_origin(
+        &method,
+        &proxy_url,
+        conditional_headers.unwrap_or_else(|| origin_request_headers.clone()),
+        bytes.clone(),
+    )
+    .await?;
+    if let Some((policy, stale, revalidation_request)) = revalidation {
+        if parts.status_code == StatusCode::NOT_MODIFIED {
+            match revalidated(&policy, stale, &revalidation_request, &parts) {
+                Some(refreshed) => {
+                    info!("Revalidated stale entry for: {}", url);
+                    parts = refreshed;
+                    cache_status = request_log::CacheStatus::Revalidated;
+                }
+                // the origin confirmed another representation than the stored one
+                None => {
+                    parts =
+                        fetch_origin(&method, &proxy_url, origin_request_headers.clone(), bytes.clone())
+                            .await?;
+                }
+            }
+        }
+    }
+    let origin_status = parts.status_code;
+    let origin_headers = parts.headers.clone();
+
+    if method != Method::HEAD && origin_status != StatusCode::PARTIAL_CONTENT {
+        integrity::verify(&base_key, &parts.body)?;
+    }
+    let rule = site.response_rule(&lookup_method, path_and_query, &origin_headers);
+
+    // a HEAD response has no body to store under the GET entry, and partial
+    // content must never be stored as if it were the whole object
+    if !cacheable || method == Method::HEAD || origin_status == StatusCode::PARTIAL_CONTENT {
+        let response = http_response_from_parts(parts)
+            .map_err(|_| miette::miette!("Could not build response"))?;
+        let mut response = compression::apply(&site.compression, &headers, response, None).await?;
+        rules::apply_browser_ttl(rule, response.headers_mut());
+        response.extensions_mut().insert(cache_status);
+        return Ok(response);
+    }
+
+    let response_to_cache = rules::edge_response(
+        rule,
+        http_response_from_parts(parts.clone()).map_err(|_| miette!("Could not build response"))?,
+    );
+    let mut request_to_cache = Request::builder().method(method.clone()).uri(url.clone());
+    for (key, value) in origin_request_headers.iter() {
+        request_to_cache = request_to_cache.header(key, value);
+    }
+
+    let request_to_cache = request_to_cache
+        .body(bytes)
+        .map_err(|_| miette!("Could not build request"))?;
+
+    let policy = rules::edge_policy(&request_to_cache, &response_to_cache, SystemTime::now());
+    let stored = rules::is_worth_storing(rule, &policy);
+    if stored {
+        let response_to_cache = CachedResponse {
+            request: request_to_cache.into_inner_cached_request()?,
+            response: response_to_cache.into_inner_cached_response()?,
+            cached_at: SystemTime::now(),
+        };
+
+        cacache::write(
+            CACHE_DIR.as_str(),
+            &cache_key,
+            envelope::encode(&response_to_cache)?,
+        )
+        .await
+        .context("Could not write to cache")?;
+        memory::invalidate(&cache_key);
+        compression::invalidate(&cache_key).await;
+    }
+
+    let response =
+        http_response_from_parts(parts).map_err(|_| miette::miette!("Could not build response"))?;
+
+    let response = range::apply(&headers, response)?;
+    let mut response = compression::apply(
+        &site.compression,
+        &headers,
+        response,
+        stored.then_some(cache_key.as_str()),
+    )
+    .await?;
+    rules::apply_browser_ttl(rule, response.headers_mut());
+    if cache_status == request_log::CacheStatus::Revalidated {
+        let resp_size = response.body().len();
+        app_state.accumulated_cnt.fetch_add(resp_size as u64, Ordering::SeqCst);
+    }
+    response.extensions_mut().insert(cache_status);
+    Ok(response)
+}
+
+/// Forwards a request to the origin and reads its whole response.
+async fn fetch_origin(
+    method: &Method,
+    url: &Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<InnerCachedResponse> {
+    let origin_response = reqwest::Client::new()
+        .request(method.clone(), url.to_string())
+        .headers(headers)
+        .body(body)
+        .timeout(Duration::from_secs(6))
+        .send()
+        .await
+        .map_err(|_| miette!("Request failed"))?;
+
+    let status_code = origin_response.status();
+    let headers = origin_response.headers().clone();
+    let version = origin_response.version();
+    let body = origin_response
+        .bytes()
+        .await
+        .map_err(|_| miette!("Could not get bytes from body"))?;
+
+    Ok(InnerCachedResponse {
+        status_code,
+        headers,
+        body: body.into(),
+        version,
+    })
+}
+
+/// The stale response refreshed with the headers of the origin's `304`, `None` when the
+/// `304` does not validate the stored representation. The headers of the updated policy
+/// carry the edge freshness, so the origin's are merged into the stored ones instead.
+fn revalidated(
+    policy: &CachePolicy,
+    stale: http::Response<Bytes>,
+    revalidation_request: &http::request::Parts,
+    not_modified: &InnerCachedResponse,
+) -> Option<InnerCachedResponse> {
+    let mut origin = http::Response::new(());
+    *origin.status_mut() = not_modified.status_code;
+    *origin.headers_mut() = not_modified.headers.clone();
+    let AfterResponse::NotModified(..) =
+        policy.after_response(revalidation_request, &origin, SystemTime::now())
+    else {
+        return None;
+    };
+
+    let (stale, body) = stale.into_parts();
+    let mut headers = stale.headers;
+    for name in not_modified.headers.keys() {
+        // these describe the empty 304 body, not the stored one
+        if [CONTENT_LENGTH, CONTENT_ENCODING, TRANSFER_ENCODING, CONTENT_RANGE].contains(name) {
+            continue;
+        }
+        headers.remove(name);
+        for value in not_modified.headers.get_all(name) {
+            headers.append(name, value.clone());
+        }
+    }
+
+    Some(InnerCachedResponse {
+        status_code: stale.status,
+        version: not_modified.version,
+        headers,
+        body: body.into(),
+    })
+}
+
+fn http_response_from_parts(parts: InnerCachedResponse) -> Result<http::Response<Bytes>> {
+    // the stored version is the origin's, the client connection has its own
+    let InnerCachedResponse {
+        status_code,
+        headers,
+        body,
+        version: _,
+    } = parts;
+
+    let mut builder = http::Response::builder().status(status_code);
+
+    for (key, value) in headers.iter() {
+        builder = builder.header(key, value);
+    }
+
+    let body: Bytes = body.into();
+
+    builder.body(body).into_diagnostic()
+}
+
+fn http_request_from_parts(parts: InnerCachedRequest) -> Result<http::Request<Bytes>> {
+    let InnerCachedRequest {
+        method,
+        uri,
+        version,
+        headers,
+        body,
+    } = parts;
+
+    let mut builder = http::Request::builder()
+        .method(method)
+        .uri(uri)
+        .version(version);
+
+    for (key, value) in headers.iter() {
+        builder = builder.header(key, value);
+    }
+
+    let body: Bytes = if let Some(b) = body {
+        b.into()
+    } else {
+        Bytes::new()
+    };
+
+    builder.body(body).into_diagnostic()
+}
+
+trait IntoInnerCachedRequest {
+    fn into_inner_cached_request(self) -> Result<InnerCachedRequest>;
+}
+
+impl IntoInnerCachedRequest for Request<Bytes> {
+    fn into_inner_cached_request(self) -> Result<InnerCachedRequest> {
+        let (parts, body) = self.into_parts();
+
+        Ok(InnerCachedRequest {
+            method: parts.method,
+            uri: parts.uri,
+            version: parts.version,
+            headers: parts.headers,
+            body: Some(body.to_vec()),
+        })
+    }
+}
+
+impl IntoInnerCachedRequest for Request<()> {
+    fn into_inner_cached_request(self) -> Result<InnerCachedRequest> {
+        let (parts, _) = self.into_parts();
+
+        Ok(InnerCachedRequest {
+            method: parts.method,
+            uri: parts.uri,
+            version: parts.version,
+            headers: parts.headers,
+            body: None,
+        })
+    }
+}
+
+trait IntoInnerCachedResponse {
+    fn into_inner_cached_response(self) -> Result<InnerCachedResponse>;
+}
+
+impl IntoInnerCachedResponse for Response<Bytes> {
+    fn into_inner_cached_response(self) -> Result<InnerCachedResponse> {
+        let (parts, body) = self.into_parts();
+
+        Ok(InnerCachedResponse {
+            status_code: parts.status,
+            version: parts.version,
+            headers: parts.headers,
+            body: body.to_vec(),
+        })
+    }
+}
@@ -0,0 +1,400 @@
+use std::{fmt, str::FromStr};
+
+use cacache::Integrity;
+use http::Method;
+
+/// A link as registered on-chain through `addToCDN`/`removeFromCDN`.
+///
+/// The canonical form is `<method>\t<url>[\t<sri>]`. The legacy `<method>@<url>`
+/// form used by the contract tests is accepted as well. The url is normalized
+/// when parsed so that equivalent links map to the same cache key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheLink {
+    pub method: Method,
+    /// Lowercased `scheme://host[:port]` prefix for absolute links.
+    pub origin: Option<String>,
+    pub path_and_query: String,
+    pub integrity: Option<Integrity>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkError {
+    Empty,
+    MissingSeparator(String),
+    InvalidMethod(String),
+    InvalidUrl(String),
+    InvalidIntegrity(String),
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkError::Empty => write!(f, "empty link"),
+            LinkError::MissingSeparator(l) => {
+                write!(f, "link {:?} has no method separator (tab or '@')", l)
+            }
+            LinkError::InvalidMethod(m) => write!(f, "invalid method {:?}", m),
+            LinkError::InvalidUrl(u) => write!(f, "invalid url {:?}", u),
+            LinkError::InvalidIntegrity(i) => write!(f, "invalid integrity {:?}", i),
+        }
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+impl CacheLink {
+    pub fn new(method: Method, url: &str) -> Result<Self, LinkError> {
+        let (origin, path_and_query) = normalize_url(url)?;
+        Ok(CacheLink {
+            method,
+            origin,
+            path_and_query,
+            integrity: None,
+        })
+    }
+
+    pub fn parse(link: &str) -> Result<Self, LinkError> {
+        let link = link.trim();
+        if link.is_empty() {
+            return Err(LinkError::Empty);
+        }
+
+        let sep = link
+            .find(['\t', '@'])
+            .ok_or_else(|| LinkError::MissingSeparator(link.to_owned()))?;
+        let (method, rest) = (&link[..sep], link[sep + 1..].trim_start());
+
+        if method.is_empty() || !method.bytes().all(|b| b.is_ascii_alphabetic()) {
+            return Err(LinkError::InvalidMethod(method.to_owned()));
+        }
+        let method = Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+            .map_err(|_| LinkError::InvalidMethod(method.to_owned()))?;
+
+        // urls cannot contain raw whitespace, so anything after it is the integrity
+        let (url, integrity) = match rest.split_once(['\t', ' ']) {
+            Some((url, sri)) => {
+                let sri = sri.trim();
+                let integrity = sri
+                    .parse::<Integrity>()
+                    .ok()
+                    .filter(|i| !i.hashes.is_empty())
+                    .ok_or_else(|| LinkError::InvalidIntegrity(sri.to_owned()))?;
+                (url, Some(integrity))
+            }
+            None => (rest, None),
+        };
+
+        let mut link = CacheLink::new(method, url)?;
+        link.integrity = integrity;
+        Ok(link)
+    }
+
+    pub fn url(&self) -> String {
+        match &self.origin {
+            Some(origin) => format!("{}{}", origin, self.path_and_query),
+            None => self.path_and_query.clone(),
+        }
+    }
+}
+
+impl FromStr for CacheLink {
+    type Err = LinkError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        CacheLink::parse(s)
+    }
+}
+
+impl fmt::Display for CacheLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}",
+            self.method.as_str().to_ascii_lowercase(),
+            self.url()
+        )?;
+        if let Some(integrity) = &self.integrity {
+            write!(f, "\t{}", integrity)?;
+        }
+        Ok(())
+    }
+}
+
+/// Normalizes an absolute url or an origin-form `path?query` into an optional
+/// `scheme://authority` and a normalized `path?query`.
+pub fn normalize_url(url: &str) -> Result<(Option<String>, String), LinkError> {
+    let invalid = || LinkError::InvalidUrl(url.to_owned());
+
+    if url.is_empty() || url.bytes().any(|b| b.is_ascii_whitespace() || b.is_ascii_control()) {
+        return Err(invalid());
+    }
+
+    let url = url.split_once('#').map_or(url, |(u, _)| u);
+
+    if url.starts_with('/') {
+        return Ok((None, normalize_path_and_query(url)?));
+    }
+
+    let (scheme, rest) = url.split_once("://").ok_or_else(invalid)?;
+    let scheme = scheme.to_ascii_lowercase();
+    if scheme != "http" && scheme != "https" {
+        return Err(invalid());
+    }
+
+    let split = rest.find(['/', '?']).unwrap_or(rest.len());
+    let (authority, path_and_query) = rest.split_at(split);
+    if authority.is_empty() || authority.contains('@') {
+        return Err(invalid());
+    }
+
+    let mut authority = authority.to_ascii_lowercase();
+    let default_port = if scheme == "http" { ":80" } else { ":443" };
+    if let Some(host) = authority.strip_suffix(default_port) {
+        authority = host.to_owned();
+    }
+    authority
+        .parse::<http::uri::Authority>()
+        .map_err(|_| invalid())?;
+
+    let path_and_query = if path_and_query.starts_with('/') {
+        normalize_path_and_query(path_and_query)?
+    } else {
+        normalize_path_and_query(&format!("/{}", path_and_query))?
+    };
+
+    Ok((Some(format!("{}://{}", scheme, authority)), path_and_query))
+}
+
+/// Normalizes an origin-form `path?query`: percent-encoding is canonicalized,
+/// dot segments are removed and query parameters are ordered by name.
+pub fn normalize_path_and_query(path_and_query: &str) -> Result<String, LinkError> {
+    let (path, query) = match path_and_query.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (path_and_query, None),
+    };
+
+    let path = if path.is_empty() { "/" } else { path };
+    if !path.starts_with('/') {
+        return Err(LinkError::InvalidUrl(path_and_query.to_owned()));
+    }
+
+    let mut normalized = remove_dot_segments(&normalize_component(path, b"/:@!$&'()*+,;="));
+
+    if let Some(query) = query {
+        let mut params: Vec<(String, Option<String>)> = query
+            .split('&')
+            .filter(|p| !p.is_empty())
+            .map(|p| match p.split_once('=') {
+                Some((k, v)) => (
+                    normalize_component(k, QUERY_CHARS),
+                    Some(normalize_component(v, QUERY_CHARS)),
+                ),
+                None => (normalize_component(p, QUERY_CHARS), None),
+            })
+            .collect();
+        // stable, so repeated parameters keep their relative order
+        params.sort_by(|a, b| a.0.cmp(&b.0));
+
+        if !params.is_empty() {
+            normalized.push('?');
+            let params: Vec<String> = params
+                .into_iter()
+                .map(|(k, v)| match v {
+                    Some(v) => format!("{}={}", k, v),
+                    None => k,
+                })
+                .collect();
+            normalized.push_str(&params.join("&"));
+        }
+    }
+
+    Ok(normalized)
+}
+
+const QUERY_CHARS: &[u8] = b"/?:@!$'()*+,;";
+
+fn is_unreserved(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~')
+}
+
+/// Decodes percent-escaped unreserved characters, uppercases the remaining escapes
+/// and escapes every byte that is neither unreserved nor in `allowed`.
+fn normalize_component(s: &str, allowed: &[u8]) -> String {
+    let bytes = s.as_bytes();
+    let mut out = String::with_capacity(s.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let b = bytes[i];
+        if b == b'%' {
+            if let (Some(hi), Some(lo)) = (
+                bytes.get(i + 1).and_then(|c| (*c as char).to_digit(16)),
+                bytes.get(i + 2).and_then(|c| (*c as char).to_digit(16)),
+            ) {
+                let decoded = (hi * 16 + lo) as u8;
+                if is_unreserved(decoded) {
+                    out.push(decoded as char);
+                } else {
+                    out.push_str(&format!("%{:02X}", decoded));
+                }
+                i += 3;
+                continue;
+            }
+        }
+
+        if is_unreserved(b) || allowed.contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+        i += 1;
+    }
+
+    out
+}
+
+/// RFC 3986 section 5.2.4, on an absolute path.
+fn remove_dot_segments(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    let mut parts = path[1..].split('/').peekable();
+
+    while let Some(segment) = parts.next() {
+        let last = parts.peek().is_none();
+        match segment {
+            "." => {
+                if last {
+                    segments.push("");
+                }
+            }
+            ".." => {
+                segments.pop();
+                if last {
+                    segments.push("");
+                }
+            }
+            s => segments.push(s),
+        }
+    }
+
+    format!("/{}", segments.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn accepts_both_separators() {
+        let tab: CacheLink = "get\t/url1/x?a=b".parse().unwrap();
+        let at: CacheLink = "get@/url1/x?a=b".parse().unwrap();
+        assert_eq!(tab, at);
+        assert_eq!(tab.method, Method::GET);
+        assert_eq!(tab.to_string(), "get\t/url1/x?a=b");
+
+        // the links registered by contract/test/ChainEdge.t.sol
+        for link in ["get@/url1/x?a=b", "post@/url2", "get@/url3", "get@/url=4"] {
+            assert!(CacheLink::parse(link).is_ok(), "{}", link);
+        }
+    }
+
+    #[test]
+    fn normalizes_urls() {
+        let link = CacheLink::parse("GET\thttp://Node1.ChainEdge.io:80/a/./b/../c?z=1&a=%7e&a=2").unwrap();
+        assert_eq!(link.url(), "http://node1.chainedge.io/a/c?a=~&a=2&z=1");
+        assert_eq!(link.path_and_query, "/a/c?a=~&a=2&z=1");
+
+        let link = CacheLink::parse("get\thttps://example.com:443").unwrap();
+        assert_eq!(link.url(), "https://example.com/");
+
+        let link = CacheLink::parse("get\t/caf%c3%a9?q=%2f&").unwrap();
+        assert_eq!(link.path_and_query, "/caf%C3%A9?q=%2F");
+    }
+
+    #[test]
+    fn rejects_malformed_links() {
+        assert_eq!(CacheLink::parse(""), Err(LinkError::Empty));
+        assert!(matches!(
+            CacheLink::parse("not_existing"),
+            Err(LinkError::MissingSeparator(_))
+        ));
+        assert!(matches!(
+            CacheLink::parse("g3t\t/x"),
+            Err(LinkError::InvalidMethod(_))
+        ));
+        assert!(matches!(
+            CacheLink::parse("get\turl"),
+            Err(LinkError::InvalidUrl(_))
+        ));
+        assert!(matches!(
+            CacheLink::parse("get\tftp://host/x"),
+            Err(LinkError::InvalidUrl(_))
+        ));
+        assert!(matches!(
+            CacheLink::parse("get\t/x\tnot-a-hash"),
+            Err(LinkError::InvalidIntegrity(_))
+        ));
+    }
+
+    #[test]
+    fn keeps_integrity() {
+        let sri = Integrity::from(b"hello");
+        let link = CacheLink::parse(&format!("get\t/x\t{}", sri)).unwrap();
+        assert_eq!(link.integrity, Some(sri));
+        assert_eq!(link.path_and_query, "/x");
+    }
+
+    fn arb_link() -> impl Strategy<Value = CacheLink> {
+        (
+            prop::sample::select(vec![Method::GET, Method::HEAD, Method::POST, Method::PUT]),
+            prop::option::of(prop::sample::select(vec![
+                "http://node1.chainedge.io:3001",
+                "https://Example.com:443",
+                "HTTP://example.com:80",
+            ])),
+            "(/[a-zA-Z0-9._~%!$&'()*+,;=:@ -]{0,8}){1,4}",
+            prop::option::of("[a-z0-9%=&+ ]{0,16}"),
+            prop::option::of(any::<Vec<u8>>()),
+        )
+            .prop_map(|(method, origin, path, query, body)| {
+                let url = format!(
+                    "{}{}{}",
+                    origin.unwrap_or(""),
+                    path,
+                    query.map(|q| format!("?{}", q)).unwrap_or_default()
+                );
+                let url = url.replace(' ', "%20");
+                let mut link = CacheLink::new(method, &url).unwrap();
+                link.integrity = body.map(Integrity::from);
+                link
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn format_parse_round_trip(link in arb_link()) {
+            let parsed = CacheLink::parse(&link.to_string()).unwrap();
+            prop_assert_eq!(&parsed, &link);
+            prop_assert_eq!(parsed.to_string(), link.to_string());
+        }
+
+        #[test]
+        fn normalization_is_idempotent(link in arb_link()) {
+            let again = CacheLink::new(link.method.clone(), &link.url()).unwrap();
+            prop_assert_eq!(again.url(), link.url());
+        }
+
+        #[test]
+        fn legacy_separator_is_equivalent(link in arb_link()) {
+            let legacy = format!("{}@{}", link.method.as_str().to_ascii_lowercase(), link.url());
+            let parsed = CacheLink::parse(&legacy).unwrap();
+            prop_assert_eq!(parsed.method, link.method);
+            prop_assert_eq!(parsed.path_and_query, link.path_and_query);
+        }
+
+        #[test]
+        fn parse_never_panics(s in "\\PC{0,64}") {
+            let _ = CacheLink::parse(&s);
+        }
+    }
+}
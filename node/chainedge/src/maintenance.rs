@@ -0,0 +1,317 @@
+//! Offline operations on a cache directory, for the CLI. The node must not be running
+//! while entries are removed or rewritten, since its memory tier would not know.
+
+use std::{collections::BTreeMap, path::Path, time::SystemTime};
+
+use miette::{miette, IntoDiagnostic, Result};
+use serde::Serialize;
+
+use axum::body::Bytes;
+use http::{Request, Response};
+
+use crate::{
+    admin::api::entries::headers_json,
+    compression,
+    envelope::{self, disk_usage},
+    policy_from_cached, CachedResponse, IntoInnerCachedRequest, IntoInnerCachedResponse,
+};
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EntryInfo {
+    pub key: String,
+    pub method: String,
+    pub url: String,
+    /// Bytes stored for the entry, headers included.
+    pub size: usize,
+    /// Schema version the entry was written with.
+    pub version: Option<u16>,
+    pub stored_at: Option<u64>,
+    pub age_secs: Option<u64>,
+    pub ttl_secs: Option<u64>,
+    pub status: Option<u16>,
+    /// Compressed variants stored next to the entry.
+    pub variants: usize,
+    /// Set instead of the fields read from the entry when it can not be read.
+    pub error: Option<String>,
+}
+
+/// Reads and decodes an entry without quarantining it.
+pub(crate) fn read_entry(cache_dir: &str, key: &str) -> Result<(CachedResponse, u16)> {
+    let bytes = cacache::read_sync(cache_dir, key).into_diagnostic()?;
+    envelope::decode(&bytes).into_diagnostic()
+}
+
+fn info(cache_dir: &str, metadata: &cacache::Metadata, variants: usize) -> EntryInfo {
+    let (method, url) = metadata
+        .key
+        .split_once('\t')
+        .unwrap_or(("", metadata.key.as_str()));
+    let mut info = EntryInfo {
+        key: metadata.key.clone(),
+        method: method.to_owned(),
+        url: url.to_owned(),
+        size: metadata.size,
+        version: None,
+        stored_at: None,
+        age_secs: None,
+        ttl_secs: None,
+        status: None,
+        variants,
+        error: None,
+    };
+
+    let result = read_entry(cache_dir, &metadata.key).and_then(|(cached, version)| {
+        let cached_at = cached.cached_at;
+        policy_from_cached(cached).map(|(policy, response, _)| (policy, response, cached_at, version))
+    });
+    match result {
+        Ok((policy, response, cached_at, version)) => {
+            let now = SystemTime::now();
+            info.version = Some(version);
+            info.stored_at = Some(unix_secs(cached_at));
+            info.age_secs = Some(now.duration_since(cached_at).unwrap_or_default().as_secs());
+            info.ttl_secs = Some(policy.time_to_live(now).as_secs());
+            info.status = Some(response.status().as_u16());
+        }
+        Err(e) => info.error = Some(e.to_string()),
+    }
+    info
+}
+
+/// Entries whose url (the key without its method) starts with `prefix`, sorted by key.
+pub fn list(cache_dir: &str, prefix: Option<&str>) -> Vec<EntryInfo> {
+    let (variants, entries): (Vec<_>, Vec<_>) = cacache::list_sync(cache_dir)
+        .filter_map(|m| m.ok())
+        .partition(|m| compression::is_variant_key(&m.key));
+
+    let mut variant_counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for variant in &variants {
+        if let Some((base, _)) = variant.key.rsplit_once('\t') {
+            *variant_counts.entry(base).or_default() += 1;
+        }
+    }
+
+    let mut infos: Vec<EntryInfo> = entries
+        .iter()
+        .filter(|m| {
+            let url = m.key.split_once('\t').map_or(m.key.as_str(), |(_, url)| url);
+            prefix.is_none_or(|p| url.starts_with(p))
+        })
+        .map(|m| info(cache_dir, m, variant_counts.get(m.key.as_str()).copied().unwrap_or(0)))
+        .collect();
+    infos.sort_by(|a, b| a.key.cmp(&b.key));
+    infos
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DumpedEntry {
+    pub key: String,
+    pub version: u16,
+    pub cached_at: u64,
+    pub ttl_secs: u64,
+    pub request: DumpedRequest,
+    pub response: DumpedResponse,
+    #[serde(skip)]
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DumpedRequest {
+    pub method: String,
+    pub uri: String,
+    pub headers: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DumpedResponse {
+    pub status: u16,
+    pub headers: BTreeMap<String, Vec<String>>,
+    pub body_bytes: usize,
+}
+
+/// Stores `response` under `key` as the node would have cached it at `cached_at` for
+/// `request`, to seed a cache directory offline.
+pub fn store(
+    cache_dir: &str,
+    key: &str,
+    request: Request<()>,
+    response: Response<Bytes>,
+    cached_at: SystemTime,
+) -> Result<()> {
+    let entry = envelope::encode(&CachedResponse {
+        request: request.into_inner_cached_request()?,
+        response: response.into_inner_cached_response()?,
+        cached_at,
+    })?;
+    cacache::write_sync(cache_dir, key, entry).into_diagnostic()?;
+    Ok(())
+}
+
+/// The decoded entry, its body apart so it can be written to a file as it is.
+pub fn dump(cache_dir: &str, key: &str) -> Result<DumpedEntry> {
+    let (cached, version) = read_entry(cache_dir, key)?;
+    let cached_at = cached.cached_at;
+    let request = DumpedRequest {
+        method: cached.request.method.to_string(),
+        uri: cached.request.uri.to_string(),
+        headers: headers_json(&cached.request.headers),
+    };
+    let (policy, response, _) = policy_from_cached(cached)?;
+
+    Ok(DumpedEntry {
+        key: key.to_owned(),
+        version,
+        cached_at: unix_secs(cached_at),
+        ttl_secs: policy.time_to_live(SystemTime::now()).as_secs(),
+        request,
+        response: DumpedResponse {
+            status: response.status().as_u16(),
+            headers: headers_json(response.headers()),
+            body_bytes: response.body().len(),
+        },
+        body: response.body().to_vec(),
+    })
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct VerifyReport {
+    /// Entries and compressed variants checked.
+    pub checked: usize,
+    pub failed: Vec<VerifyFailure>,
+    /// Whether the failed entries were quarantined and the failed variants removed.
+    pub quarantined: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VerifyFailure {
+    pub key: String,
+    pub reason: String,
+}
+
+/// Checks the content of every entry against its stored integrity, and that entries decode.
+pub fn verify(cache_dir: &str, quarantine: bool) -> Result<VerifyReport> {
+    let mut report = VerifyReport {
+        quarantined: quarantine,
+        ..Default::default()
+    };
+    for metadata in cacache::list_sync(cache_dir).filter_map(|m| m.ok()) {
+        report.checked += 1;
+        let variant = compression::is_variant_key(&metadata.key);
+        let (bytes, reason) = match cacache::read_sync(cache_dir, &metadata.key) {
+            Err(e) => (None, e.to_string()),
+            Ok(_) if variant => continue,
+            Ok(bytes) => match envelope::decode(&bytes) {
+                Ok(_) => continue,
+                Err(e) => (Some(bytes), e.to_string()),
+            },
+        };
+        if quarantine {
+            match variant {
+                true => cacache::remove_sync(cache_dir, &metadata.key).into_diagnostic()?,
+                false => envelope::quarantine(cache_dir, &metadata.key, bytes.as_deref(), &reason)?,
+            }
+        }
+        report.failed.push(VerifyFailure {
+            key: metadata.key,
+            reason,
+        });
+    }
+    Ok(report)
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct GcReport {
+    pub dry_run: bool,
+    /// Expired entries, along with their compressed variants.
+    pub expired: Vec<String>,
+    /// Compressed variants whose entry is gone.
+    pub orphaned_variants: Vec<String>,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+/// Removes the entries no longer fresh, which the node would have to revalidate anyway.
+/// Unreadable entries are left to `verify`. The content of removed entries stays on disk
+/// until the cache is compacted.
+pub fn gc(cache_dir: &str, dry_run: bool) -> Result<GcReport> {
+    let mut report = GcReport {
+        dry_run,
+        bytes_before: disk_usage(Path::new(cache_dir)),
+        ..Default::default()
+    };
+    let (variants, entries): (Vec<_>, Vec<_>) = cacache::list_sync(cache_dir)
+        .filter_map(|m| m.ok())
+        .map(|m| m.key)
+        .partition(|k| compression::is_variant_key(k));
+
+    let now = SystemTime::now();
+    for key in &entries {
+        let Ok((cached, _)) = read_entry(cache_dir, key) else {
+            continue;
+        };
+        let Ok((policy, _, _)) = policy_from_cached(cached) else {
+            continue;
+        };
+        if policy.time_to_live(now).is_zero() {
+            report.expired.push(key.clone());
+        }
+    }
+    for variant in variants {
+        let base = variant.rsplit_once('\t').map_or("", |(base, _)| base);
+        if !entries.iter().any(|k| k == base) {
+            report.orphaned_variants.push(variant);
+        }
+    }
+
+    if !dry_run {
+        for key in &report.expired {
+            for variant in compression::variant_keys(key) {
+                let _ = cacache::remove_sync(cache_dir, variant);
+            }
+            cacache::remove_sync(cache_dir, key).into_diagnostic()?;
+        }
+        for variant in &report.orphaned_variants {
+            cacache::remove_sync(cache_dir, variant).into_diagnostic()?;
+        }
+    }
+    report.bytes_after = disk_usage(Path::new(cache_dir));
+    Ok(report)
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct DiskUsage {
+    pub entries: usize,
+    pub variants: usize,
+    /// Bytes of the live entries and variants, as indexed.
+    pub live_bytes: u64,
+    /// Bytes of the directory, including index history and content no entry points to.
+    pub disk_bytes: u64,
+    pub quarantined: usize,
+    pub quarantine_bytes: u64,
+}
+
+pub fn usage(cache_dir: &str) -> Result<DiskUsage> {
+    if !Path::new(cache_dir).is_dir() {
+        return Err(miette!("{} is not a cache directory", cache_dir));
+    }
+    let mut usage = DiskUsage {
+        disk_bytes: disk_usage(Path::new(cache_dir)),
+        quarantined: envelope::quarantined(cache_dir).len(),
+        quarantine_bytes: disk_usage(&envelope::quarantine_dir(cache_dir)),
+        ..Default::default()
+    };
+    for metadata in cacache::list_sync(cache_dir).filter_map(|m| m.ok()) {
+        match compression::is_variant_key(&metadata.key) {
+            true => usage.variants += 1,
+            false => usage.entries += 1,
+        }
+        usage.live_bytes += metadata.size as u64;
+    }
+    Ok(usage)
+}
@@ -0,0 +1,443 @@
+//! In-memory tier of hot entries in front of the disk cache.
+//!
+//! A hit on disk reads the whole entry, decodes it and rebuilds its policy. Entries read
+//! often enough are kept here already decoded, bounded by their size in bytes. Admission
+//! follows TinyLFU: a newcomer only evicts entries that were requested less often than it,
+//! so a scan of one-off urls does not push out the popular ones.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+    sync::Mutex,
+};
+
+use axum::body::Bytes;
+use http::{HeaderMap, Response, StatusCode, Uri};
+use http_cache_semantics::CachePolicy;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MemoryCacheConfig {
+    /// Bytes of responses kept in memory, 0 disables the tier.
+    pub max_bytes: usize,
+    /// Larger responses are only served from disk.
+    pub max_entry_bytes: usize,
+}
+
+impl Default for MemoryCacheConfig {
+    fn default() -> Self {
+        MemoryCacheConfig {
+            max_bytes: 64 * 1024 * 1024,
+            max_entry_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// Rough cost of the policy and the bookkeeping of an entry, on top of its headers and body.
+const ENTRY_OVERHEAD: usize = 512;
+
+/// Invalidations bump the generation of the shard of their key only, so writes of
+/// unrelated keys do not keep the entries read meanwhile out of the tier.
+const GENERATION_SHARDS: usize = 1024;
+
+fn hash(key: &str) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn shard(key: &str) -> usize {
+    hash(key) as usize % GENERATION_SHARDS
+}
+
+/// Counters per row of the frequency sketch.
+const SKETCH_WIDTH: usize = 1 << 14;
+const SKETCH_ROWS: usize = 4;
+
+/// Count-min sketch of how often keys were requested. Counters are halved every
+/// `10 * SKETCH_WIDTH` requests, so popularity that is no longer current fades.
+struct Sketch {
+    counters: Vec<u8>,
+    additions: usize,
+}
+
+impl Sketch {
+    fn new() -> Self {
+        Sketch {
+            counters: vec![0; SKETCH_WIDTH * SKETCH_ROWS],
+            additions: 0,
+        }
+    }
+
+    fn slots(key: &str) -> [usize; SKETCH_ROWS] {
+        let hash = hash(key);
+        let (h1, h2) = (hash as u32 as usize, (hash >> 32) as usize | 1);
+        std::array::from_fn(|row| row * SKETCH_WIDTH + h1.wrapping_add(row * h2) % SKETCH_WIDTH)
+    }
+
+    fn increment(&mut self, key: &str) {
+        for slot in Self::slots(key) {
+            self.counters[slot] = self.counters[slot].saturating_add(1);
+        }
+        self.additions += 1;
+        if self.additions >= 10 * SKETCH_WIDTH {
+            self.additions = 0;
+            for counter in self.counters.iter_mut() {
+                *counter /= 2;
+            }
+        }
+    }
+
+    fn estimate(&self, key: &str) -> u8 {
+        Self::slots(key)
+            .into_iter()
+            .map(|slot| self.counters[slot])
+            .min()
+            .unwrap_or_default()
+    }
+}
+
+struct Hot {
+    policy: CachePolicy,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    /// Uri of the request the entry was stored for.
+    uri: Uri,
+    size: usize,
+    last_used: u64,
+}
+
+impl Hot {
+    fn response(&self) -> Response<Bytes> {
+        let mut response = Response::new(self.body.clone());
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        response
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct MemoryStats {
+    pub entries: usize,
+    pub bytes: usize,
+    pub max_bytes: usize,
+    /// Lookups answered from memory.
+    pub hits: u64,
+    /// Lookups that had to read the disk, whether or not the entry was there.
+    pub misses: u64,
+    pub hit_ratio: f64,
+    pub admitted: u64,
+    /// Entries read from disk but kept out, as too large or less popular than the ones they would evict.
+    pub rejected: u64,
+    pub evicted: u64,
+}
+
+struct Tier {
+    config: MemoryCacheConfig,
+    entries: HashMap<String, Hot>,
+    /// Keys by the tick they were last used at, least recently used first.
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    bytes: usize,
+    sketch: Sketch,
+    /// Bumped by every invalidation of a key of the shard, so an entry read from disk
+    /// before it is not admitted after it.
+    generations: Vec<u64>,
+    stats: MemoryStats,
+}
+
+impl Tier {
+    fn enabled(&self) -> bool {
+        self.config.max_bytes > 0
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Hot> {
+        let hot = self.entries.remove(key)?;
+        self.recency.remove(&hot.last_used);
+        self.bytes -= hot.size;
+        Some(hot)
+    }
+
+    fn evict_to(&mut self, max_bytes: usize) {
+        while self.bytes > max_bytes {
+            let Some((_, key)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some(hot) = self.entries.remove(&key) {
+                self.bytes -= hot.size;
+                self.stats.evicted += 1;
+            }
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<(CachePolicy, Response<Bytes>, Uri)> {
+        if !self.enabled() {
+            return None;
+        }
+        self.sketch.increment(key);
+        self.tick += 1;
+        let tick = self.tick;
+        let Some(hot) = self.entries.get_mut(key) else {
+            self.stats.misses += 1;
+            return None;
+        };
+        self.recency.remove(&hot.last_used);
+        hot.last_used = tick;
+        self.recency.insert(tick, key.to_owned());
+        self.stats.hits += 1;
+        Some((hot.policy.clone(), hot.response(), hot.uri.clone()))
+    }
+
+    /// Whether the candidate is requested more often than every entry it would evict.
+    fn admits(&self, key: &str, size: usize) -> bool {
+        let frequency = self.sketch.estimate(key);
+        let mut freed = 0;
+        for victim in self.recency.values() {
+            if self.bytes - freed + size <= self.config.max_bytes {
+                break;
+            }
+            if self.sketch.estimate(victim) >= frequency {
+                return false;
+            }
+            freed += self.entries[victim].size;
+        }
+        true
+    }
+
+    fn offer(&mut self, key: &str, generation: u64, hot: Hot) {
+        if !self.enabled()
+            || generation != self.generations[shard(key)]
+            || self.entries.contains_key(key)
+        {
+            return;
+        }
+        if hot.size > self.config.max_entry_bytes.min(self.config.max_bytes) || !self.admits(key, hot.size) {
+            self.stats.rejected += 1;
+            return;
+        }
+
+        self.evict_to(self.config.max_bytes - hot.size);
+        self.tick += 1;
+        let hot = Hot {
+            last_used: self.tick,
+            ..hot
+        };
+        self.recency.insert(hot.last_used, key.to_owned());
+        self.bytes += hot.size;
+        self.entries.insert(key.to_owned(), hot);
+        self.stats.admitted += 1;
+    }
+}
+
+lazy_static! {
+    static ref TIER: Mutex<Tier> = Mutex::new(Tier {
+        config: MemoryCacheConfig::default(),
+        entries: HashMap::new(),
+        recency: BTreeMap::new(),
+        tick: 0,
+        bytes: 0,
+        sketch: Sketch::new(),
+        generations: vec![0; GENERATION_SHARDS],
+        stats: MemoryStats::default(),
+    });
+}
+
+/// Applies the limits of the node config, evicting what no longer fits.
+pub fn configure(config: &MemoryCacheConfig) {
+    let mut tier = TIER.lock().unwrap();
+    tier.config = config.clone();
+    tier.evict_to(config.max_bytes);
+}
+
+/// The decoded entry, when it is hot. Counts towards the popularity of `key` either way.
+pub(crate) fn get(key: &str) -> Option<(CachePolicy, Response<Bytes>, Uri)> {
+    TIER.lock().unwrap().get(key)
+}
+
+/// To be taken before reading the entry of `key` from disk and handed to `offer` with it.
+pub(crate) fn generation(key: &str) -> u64 {
+    TIER.lock().unwrap().generations[shard(key)]
+}
+
+/// Keeps an entry just read from disk, if the admission policy lets it in.
+pub(crate) fn offer(key: &str, generation: u64, policy: &CachePolicy, response: &Response<Bytes>, uri: &Uri) {
+    let headers_size: usize = response
+        .headers()
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len())
+        .sum();
+    let hot = Hot {
+        policy: policy.clone(),
+        status: response.status(),
+        headers: response.headers().clone(),
+        body: response.body().clone(),
+        uri: uri.clone(),
+        size: ENTRY_OVERHEAD + key.len() + headers_size + response.body().len(),
+        last_used: 0,
+    };
+    TIER.lock().unwrap().offer(key, generation, hot);
+}
+
+/// Drops the entry, to be called whenever it is written or removed on disk.
+pub(crate) fn invalidate(key: &str) {
+    let mut tier = TIER.lock().unwrap();
+    tier.generations[shard(key)] += 1;
+    tier.remove(key);
+}
+
+pub(crate) fn clear() {
+    let mut tier = TIER.lock().unwrap();
+    for generation in tier.generations.iter_mut() {
+        *generation += 1;
+    }
+    tier.entries.clear();
+    tier.recency.clear();
+    tier.bytes = 0;
+}
+
+pub(crate) fn contains(key: &str) -> bool {
+    TIER.lock().unwrap().entries.contains_key(key)
+}
+
+pub fn stats() -> MemoryStats {
+    let tier = TIER.lock().unwrap();
+    let lookups = tier.stats.hits + tier.stats.misses;
+    MemoryStats {
+        entries: tier.entries.len(),
+        bytes: tier.bytes,
+        max_bytes: tier.config.max_bytes,
+        hit_ratio: match lookups {
+            0 => 0.0,
+            lookups => tier.stats.hits as f64 / lookups as f64,
+        },
+        ..tier.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tier(max_bytes: usize) -> Tier {
+        Tier {
+            config: MemoryCacheConfig {
+                max_bytes,
+                max_entry_bytes: max_bytes,
+            },
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            bytes: 0,
+            sketch: Sketch::new(),
+            generations: vec![0; GENERATION_SHARDS],
+            stats: MemoryStats::default(),
+        }
+    }
+
+    fn hot(size: usize) -> Hot {
+        let request = http::Request::get("/").body(()).unwrap();
+        let response = Response::builder()
+            .header("cache-control", "max-age=60")
+            .body(())
+            .unwrap();
+        Hot {
+            policy: CachePolicy::new(&request, &response),
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Bytes::new(),
+            uri: Uri::from_static("/"),
+            size,
+            last_used: 0,
+        }
+    }
+
+    /// A lookup that misses, then the disk read offering the entry.
+    fn read(tier: &mut Tier, key: &str, size: usize) -> bool {
+        if tier.get(key).is_some() {
+            return true;
+        }
+        let generation = tier.generations[shard(key)];
+        tier.offer(key, generation, hot(size));
+        false
+    }
+
+    #[test]
+    fn serves_admitted_entries_from_memory() {
+        let mut tier = tier(1000);
+        assert!(!read(&mut tier, "a", 100));
+        assert!(read(&mut tier, "a", 100));
+        assert_eq!(tier.stats.hits, 1);
+        assert_eq!(tier.stats.misses, 1);
+        assert_eq!(tier.bytes, 100);
+    }
+
+    #[test]
+    fn keeps_popular_entries_over_one_off_keys() {
+        let mut tier = tier(300);
+        for _ in 0..5 {
+            for key in ["a", "b", "c"] {
+                read(&mut tier, key, 100);
+            }
+        }
+        for i in 0..50 {
+            read(&mut tier, &format!("scan-{}", i), 100);
+        }
+        for key in ["a", "b", "c"] {
+            assert!(tier.entries.contains_key(key), "{} was evicted", key);
+        }
+        assert_eq!(tier.stats.rejected, 50);
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_for_a_more_popular_key() {
+        let mut tier = tier(200);
+        read(&mut tier, "a", 100);
+        read(&mut tier, "b", 100);
+        for _ in 0..3 {
+            read(&mut tier, "c", 100);
+        }
+        assert!(tier.entries.contains_key("c"));
+        assert!(!tier.entries.contains_key("a"));
+        assert_eq!(tier.stats.evicted, 1);
+        assert!(tier.bytes <= 200);
+    }
+
+    #[test]
+    fn rejects_entries_read_before_an_invalidation() {
+        let mut tier = tier(1000);
+        assert!(tier.get("a").is_none());
+        let generation = tier.generations[shard("a")];
+        // written while the stale copy was being read from disk
+        tier.generations[shard("a")] += 1;
+        tier.remove("a");
+        tier.offer("a", generation, hot(100));
+        assert!(!tier.entries.contains_key("a"));
+    }
+
+    #[test]
+    fn admits_entries_read_while_other_keys_were_written() {
+        let mut tier = tier(1000);
+        let other = (0..)
+            .map(|i| format!("other-{}", i))
+            .find(|k| shard(k) != shard("a"))
+            .unwrap();
+        assert!(tier.get("a").is_none());
+        let generation = tier.generations[shard("a")];
+        tier.generations[shard(&other)] += 1;
+        tier.remove(&other);
+        tier.offer("a", generation, hot(100));
+        assert!(tier.entries.contains_key("a"));
+    }
+
+    #[test]
+    fn rejects_entries_larger_than_the_limit() {
+        let mut tier = tier(1000);
+        tier.config.max_entry_bytes = 500;
+        read(&mut tier, "a", 600);
+        assert!(tier.entries.is_empty());
+        assert_eq!(tier.stats.rejected, 1);
+    }
+}
@@ -1,41 +1,62 @@
 use crate::{
     WrappedError,
-    decode_cache_key,
-    PROXY_ORIGIN_DOMAIN, PROXY_FROM_DOMAIN,
     InnerCachedResponse,
+    http_request_from_parts,
     http_response_from_parts,
+    read_cached,
     CachedResponse,
     IntoInnerCachedRequest, IntoInnerCachedResponse,
     CACHE_DIR,
+    compression,
+    envelope,
+    hits,
+    integrity,
+    keying,
+    memory,
+    link::CacheLink,
+    config::Config,
+    rules::{self, CacheRule},
 };
 
-use http::{header::HOST, Method, Request, Uri};
-use http_cache_semantics::CachePolicy;
+use axum::body::Bytes;
+use http::{header::HOST, uri::PathAndQuery, HeaderMap, Method, Request, Response};
+use http_cache_semantics::RequestLike;
 use std::time::SystemTime;
 use miette::{miette, Context, IntoDiagnostic};
 
 
-pub(crate) async fn populate(cache_key: String) -> Result<(), WrappedError> {
-    let (mut method, url) = decode_cache_key(&cache_key);
-    method = method.to_uppercase();
-    let cache_key = crate::cache_key(method.clone(), url.clone());
+pub(crate) async fn populate(link: String, config: &Config) -> Result<(), WrappedError> {
+    let link = CacheLink::parse(&link).into_diagnostic()?;
+    let site = config
+        .site_for_link(&link)
+        .ok_or_else(|| miette!("No site configured for {}", link))?;
+    if link.method == Method::HEAD {
+        return Err(miette!("HEAD is served from the GET entry of {}", link.url()).into());
+    }
+    if !site.is_cacheable(&link.method) {
+        return Err(miette!("{} is not cacheable for {}", link.method, site.front_domain).into());
+    }
+    let cache_key = site.cache_key(&link.method, &link.path_and_query, &HeaderMap::new(), &[])?;
+    let base_key = site.base_cache_key(&link.method, &link.path_and_query)?;
 
-    let path = url
-        .parse::<Uri>()
-        .into_diagnostic()?
-        .path_and_query()
-        .cloned()
-        .unwrap();
+    match link.integrity.clone() {
+        Some(sri) => integrity::pin(&base_key, sri),
+        None => integrity::unpin(&base_key),
+    }
+
+    let path = link.path_and_query
+        .parse::<PathAndQuery>()
+        .into_diagnostic()?;
 
     let proxy_url = http::Uri::builder()
         .scheme("http")
-        .authority(PROXY_ORIGIN_DOMAIN)
+        .authority(site.origin_domain.as_str())
         .path_and_query(path.clone())
         .build()
         .into_diagnostic()?;
 
     let client = reqwest::Client::new();
-    let method: Method = method.parse().map_err(|_| miette::miette!("Method parse failed"))?;
+    let method = link.method.clone();
 
     let origin_response = client
         .request(method.clone(), proxy_url.to_string())
@@ -57,42 +78,166 @@ pub(crate) async fn populate(cache_key: String) -> Result<(), WrappedError> {
         body: origin_bytes.into(),
         version: origin_version,
     };
-    let response_to_cache = http_response_from_parts(parts.clone())
+    integrity::verify(&base_key, &parts.body)?;
+
+    let response_to_cache = http_response_from_parts(parts)
         .map_err(|_| miette::miette!("Could not build response"))?;
+    let rule = site.response_rule(&link.method, &link.path_and_query, response_to_cache.headers());
     let request_to_cache: Request<()> = Request::builder()
         .method(method)
         .uri(path)
-        .header(HOST, PROXY_FROM_DOMAIN)
+        .header(HOST, site.front_domain.as_str())
         .body(())
         .into_diagnostic()?;
 
-    let policy = CachePolicy::new(&request_to_cache, &response_to_cache);
+    store(&cache_key, request_to_cache, response_to_cache, rule).await?;
+
+    Ok(())
+}
 
-    if policy.is_storable() && !policy.time_to_live(SystemTime::now()).is_zero() {
-        let response_to_cache = CachedResponse {
-            request: request_to_cache.into_inner_cached_request()?,
-            response: response_to_cache.into_inner_cached_response()?,
-            cached_at: SystemTime::now(),
-        };
+/// Pins the integrity of every link on the CDN list, and unpins links no longer on it.
+/// `NewLink` events only pin what is added while the node follows them.
+pub(crate) fn seed_pins(links: &[String], config: &Config) {
+    let pins = links
+        .iter()
+        .filter_map(|link| {
+            let link = CacheLink::parse(link).ok()?;
+            let sri = link.integrity.clone()?;
+            let site = config.site_for_link(&link)?;
+            let base_key = site.base_cache_key(&link.method, &link.path_and_query).ok()?;
+            Some((base_key, sri))
+        })
+        .collect();
+    let front_domains: Vec<String> = config.sites.iter().map(|s| s.front_domain.clone()).collect();
+    integrity::replace(&front_domains, pins);
+}
 
-        cacache::write(
-            CACHE_DIR,
-            cache_key,
-            postcard::to_allocvec(&response_to_cache).into_diagnostic()?,
-        )
+/// Writes the entry when the policy of the response, as the cache rule of the site makes it,
+/// allows it, and reports whether it did.
+async fn store<B>(
+    cache_key: &str,
+    request: Request<B>,
+    response: Response<Bytes>,
+    rule: Option<&CacheRule>,
+) -> miette::Result<bool>
+where
+    Request<B>: IntoInnerCachedRequest + RequestLike,
+{
+    let response = rules::edge_response(rule, response);
+    let policy = rules::edge_policy(&request, &response, SystemTime::now());
+    if !rules::is_worth_storing(rule, &policy) {
+        return Ok(false);
+    }
+
+    let response_to_cache = CachedResponse {
+        request: request.into_inner_cached_request()?,
+        response: response.into_inner_cached_response()?,
+        cached_at: SystemTime::now(),
+    };
+
+    cacache::write(
+        CACHE_DIR.as_str(),
+        cache_key,
+        envelope::encode(&response_to_cache)?,
+    )
+    .await
+    .context("Could not write to cache")?;
+    memory::invalidate(cache_key);
+    compression::invalidate(cache_key).await;
+
+    Ok(true)
+}
+
+/// Fetches an entry again by replaying the request it was stored for against the origin.
+/// An origin that no longer allows caching the response gets the entry removed.
+pub(crate) async fn refresh(cache_key: &str, config: &Config) -> Result<(), WrappedError> {
+    let cached = read_cached(cache_key).await?;
+    let front_domain = keying::front_domain(cache_key);
+    let site = config
+        .site_for_host(front_domain)
+        .ok_or_else(|| miette!("No site configured for {}", front_domain))?;
+
+    let request = http_request_from_parts(cached.request)?;
+    let path = request
+        .uri()
+        .path_and_query()
+        .cloned()
+        .unwrap_or_else(|| PathAndQuery::from_static("/"));
+    let proxy_url = http::Uri::builder()
+        .scheme("http")
+        .authority(site.origin_domain.as_str())
+        .path_and_query(path)
+        .build()
+        .into_diagnostic()?;
+
+    let mut headers = request.headers().clone();
+    let base_key = keying::base_key(cache_key);
+    integrity::strip_accept_encoding(base_key, &mut headers);
+    let origin_response = reqwest::Client::new()
+        .request(request.method().clone(), proxy_url.to_string())
+        .headers(headers)
+        .body(request.body().clone())
+        .send()
+        .await
+        .into_diagnostic()?;
+
+    let origin_status = origin_response.status();
+    let origin_headers = origin_response.headers().clone();
+    let origin_version = origin_response.version();
+    let origin_bytes = origin_response
+        .bytes()
         .await
-        .context("Could not write to cache")?;
+        .into_diagnostic()?;
+
+    let parts = InnerCachedResponse {
+        status_code: origin_status,
+        headers: origin_headers,
+        body: origin_bytes.into(),
+        version: origin_version,
+    };
+    integrity::verify(base_key, &parts.body)?;
+    let response = http_response_from_parts(parts)?;
+    let path_and_query = request.uri().path_and_query().map_or("/", |p| p.as_str());
+    let rule = site.response_rule(request.method(), path_and_query, response.headers());
+
+    if !store(cache_key, request, response, rule).await? {
+        cacache::remove(CACHE_DIR.as_str(), cache_key).await
+            .map_err(|_| miette!("Could not remove cache entry"))?;
+        memory::invalidate(cache_key);
+        compression::invalidate(cache_key).await;
+        hits::forget(cache_key);
     }
 
     Ok(())
 }
 
-pub(crate) async fn remove(cache_key: String) -> Result<(), WrappedError> {
-    let (mut method, url) = decode_cache_key(&cache_key);
-    method = method.to_uppercase();
-    let cache_key = crate::cache_key(method.clone(), url.clone());
+pub(crate) async fn remove(link: String, config: &Config) -> Result<(), WrappedError> {
+    let link = CacheLink::parse(&link).into_diagnostic()?;
+    let site = config
+        .site_for_link(&link)
+        .ok_or_else(|| miette!("No site configured for {}", link))?;
+    let cache_key = site.base_cache_key(&link.method, &link.path_and_query)?;
+    integrity::unpin(&cache_key);
+
+    // header and cookie variants share the base key as prefix
+    let variant_prefix = format!("{}\t", cache_key);
+    let variants: Vec<String> = tokio::task::spawn_blocking(move || {
+        cacache::list_sync(CACHE_DIR.as_str())
+            .filter_map(|m| m.ok())
+            .map(|m| m.key)
+            .filter(|k| k.starts_with(&variant_prefix))
+            .collect()
+    })
+    .await
+    .into_diagnostic()?;
+
+    for key in variants.into_iter().chain(std::iter::once(cache_key)) {
+        cacache::remove(CACHE_DIR.as_str(), &key).await
+            .map_err(|_| miette!("Could not remove cache entry"))?;
+        memory::invalidate(&key);
+        compression::invalidate(&key).await;
+        hits::forget(&key);
+    }
 
-    return cacache::remove(CACHE_DIR, cache_key).await
-            .map_err(|_| miette!("record thread error").into());
-    //        .into_diagnostic()?
-}
\ No newline at end of file
+    Ok(())
+}
@@ -0,0 +1,162 @@
+use std::time::SystemTime;
+
+use http::HeaderMap;
+use miette::{miette, Context, IntoDiagnostic, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{compression, envelope, get_policy_from_cache, hits, memory, read_cached, CACHE_DIR};
+
+/// Which entries a purge applies to.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PurgeTarget {
+    /// One cache key, as listed by the admin pages.
+    Key(String),
+    /// Entries whose url (the key without its method) starts with this, e.g. `node1.chainedge.io:3001/static/`.
+    Prefix(String),
+    /// Entries whose url matches this regular expression.
+    Regex(String),
+    /// Entries the origin tagged with this surrogate key through `Surrogate-Key` or `Cache-Tag`.
+    Tag(String),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PurgeRequest {
+    #[serde(flatten)]
+    pub target: PurgeTarget,
+    /// Mark the entries stale so they are revalidated with the origin, instead of removing them.
+    #[serde(default)]
+    pub soft: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PurgeResult {
+    pub soft: bool,
+    pub purged: usize,
+    pub keys: Vec<String>,
+}
+
+/// The key without its method: `front_domain/path?query` followed by any variant components.
+fn key_url(key: &str) -> &str {
+    key.split_once('\t').map_or(key, |(_, url)| url)
+}
+
+/// Surrogate keys are space separated, `Cache-Tag` values comma separated.
+pub fn tags(headers: &HeaderMap) -> Vec<String> {
+    let surrogate_keys = headers
+        .get_all("surrogate-key")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split_ascii_whitespace());
+    let cache_tags = headers
+        .get_all("cache-tag")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim);
+
+    surrogate_keys
+        .chain(cache_tags)
+        .filter(|t| !t.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+async fn entry_keys() -> Result<Vec<String>> {
+    let keys = tokio::task::spawn_blocking(|| {
+        cacache::list_sync(CACHE_DIR.as_str())
+            .filter_map(|m| m.ok())
+            .map(|m| m.key)
+            .filter(|k| !compression::is_variant_key(k))
+            .collect()
+    })
+    .await
+    .into_diagnostic()?;
+
+    Ok(keys)
+}
+
+async fn matching_keys(target: &PurgeTarget) -> Result<Vec<String>> {
+    let keys = entry_keys().await?;
+
+    let matching = match target {
+        PurgeTarget::Key(key) => keys.into_iter().filter(|k| k == key).collect(),
+        PurgeTarget::Prefix(prefix) => keys
+            .into_iter()
+            .filter(|k| key_url(k).starts_with(prefix.as_str()))
+            .collect(),
+        PurgeTarget::Regex(pattern) => {
+            let regex = Regex::new(pattern)
+                .into_diagnostic()
+                .wrap_err("Invalid purge regex")?;
+            keys.into_iter()
+                .filter(|k| regex.is_match(key_url(k)))
+                .collect()
+        }
+        PurgeTarget::Tag(tag) => {
+            let mut matching = Vec::new();
+            for key in keys {
+                // entries that can not be read are left to the proxy to replace
+                let Ok((_, response, _)) = get_policy_from_cache(&key).await else {
+                    continue;
+                };
+                if tags(response.headers()).iter().any(|t| t == tag) {
+                    matching.push(key);
+                }
+            }
+            matching
+        }
+    };
+
+    Ok(matching)
+}
+
+/// Backdates the entry so its policy no longer considers it fresh. It is still
+/// there to be revalidated against, and replaced when the origin answers.
+async fn mark_stale(key: &str) -> Result<()> {
+    let mut cached = read_cached(key).await?;
+    cached.cached_at = SystemTime::UNIX_EPOCH;
+
+    cacache::write(
+        CACHE_DIR.as_str(),
+        key,
+        envelope::encode(&cached)?,
+    )
+    .await
+    .context("Could not write to cache")?;
+    memory::invalidate(key);
+
+    Ok(())
+}
+
+pub async fn purge(request: &PurgeRequest) -> Result<PurgeResult> {
+    let keys = matching_keys(&request.target).await?;
+
+    for key in &keys {
+        if request.soft {
+            mark_stale(key).await?;
+        } else {
+            cacache::remove(CACHE_DIR.as_str(), key)
+                .await
+                .map_err(|_| miette!("Could not remove cache entry"))?;
+            memory::invalidate(key);
+            compression::invalidate(key).await;
+            hits::forget(key);
+        }
+    }
+
+    info!(
+        target = ?request.target,
+        soft = request.soft,
+        "Purged {} entries",
+        keys.len()
+    );
+
+    Ok(PurgeResult {
+        soft: request.soft,
+        purged: keys.len(),
+        keys,
+    })
+}
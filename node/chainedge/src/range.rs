@@ -0,0 +1,277 @@
+use std::ops::Range;
+
+use axum::body::Bytes;
+use http::{
+    header::{
+        ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_RANGE, LAST_MODIFIED,
+        RANGE,
+    },
+    HeaderMap, HeaderValue, Response, StatusCode,
+};
+use miette::{IntoDiagnostic, Result};
+
+/// More ranges than this in one request are answered with the full body.
+const MAX_RANGES: usize = 16;
+
+/// Request headers that must not reach the origin when the edge fetches the full object
+/// to answer a range request itself.
+pub fn strip_range_headers(headers: &mut HeaderMap) {
+    headers.remove(RANGE);
+    headers.remove(IF_RANGE);
+}
+
+/// Parses a `bytes=` range header against a body of `len` bytes.
+/// `None` means the header is to be ignored, an empty list that no range is satisfiable.
+fn parse_ranges(header: &str, len: u64) -> Option<Vec<Range<u64>>> {
+    let specs = header.trim().strip_prefix("bytes=")?;
+    let mut ranges = Vec::new();
+
+    for spec in specs.split(',') {
+        let (start, end) = spec.trim().split_once('-')?;
+        let range = match (start.trim(), end.trim()) {
+            ("", "") => return None,
+            ("", suffix) => {
+                let suffix: u64 = suffix.parse().ok()?;
+                if suffix == 0 || len == 0 {
+                    continue;
+                }
+                len.saturating_sub(suffix)..len
+            }
+            (start, end) => {
+                let start: u64 = start.parse().ok()?;
+                let end = match end {
+                    "" => u64::MAX,
+                    end => end.parse().ok()?,
+                };
+                if end < start {
+                    return None;
+                }
+                if start >= len {
+                    continue;
+                }
+                start..end.min(len - 1) + 1
+            }
+        };
+        ranges.push(range);
+    }
+
+    if ranges.len() > MAX_RANGES {
+        return None;
+    }
+
+    Some(ranges)
+}
+
+/// `If-Range` only lets the range through when it names the stored representation:
+/// a strong ETag match or the exact `Last-Modified` date.
+fn if_range_matches(request: &HeaderMap, response: &HeaderMap) -> bool {
+    let Some(if_range) = request.get(IF_RANGE).and_then(|v| v.to_str().ok()) else {
+        return true;
+    };
+    let if_range = if_range.trim();
+
+    if if_range.starts_with('"') {
+        return response
+            .get(ETAG)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|etag| etag.trim() == if_range);
+    }
+    if if_range.starts_with("W/") {
+        return false;
+    }
+
+    response
+        .get(LAST_MODIFIED)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|lm| lm.trim() == if_range)
+}
+
+/// Answers the `Range` header of a request from a complete `200` response.
+/// Anything else is returned unchanged.
+pub fn apply(request: &HeaderMap, response: Response<Bytes>) -> Result<Response<Bytes>> {
+    if response.status() != StatusCode::OK {
+        return Ok(response);
+    }
+
+    let (mut parts, body) = response.into_parts();
+    parts
+        .headers
+        .insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+
+    let ranges = request
+        .get(RANGE)
+        .and_then(|v| v.to_str().ok())
+        .filter(|_| if_range_matches(request, &parts.headers))
+        .and_then(|v| parse_ranges(v, body.len() as u64));
+    let Some(ranges) = ranges else {
+        return Ok(Response::from_parts(parts, body));
+    };
+
+    let len = body.len() as u64;
+
+    if ranges.is_empty() {
+        parts.status = StatusCode::RANGE_NOT_SATISFIABLE;
+        parts.headers.insert(
+            CONTENT_RANGE,
+            format!("bytes */{}", len).parse().into_diagnostic()?,
+        );
+        parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(0));
+        return Ok(Response::from_parts(parts, Bytes::new()));
+    }
+
+    parts.status = StatusCode::PARTIAL_CONTENT;
+
+    if let [range] = ranges.as_slice() {
+        let slice = body.slice(range.start as usize..range.end as usize);
+        parts.headers.insert(
+            CONTENT_RANGE,
+            format!("bytes {}-{}/{}", range.start, range.end - 1, len)
+                .parse()
+                .into_diagnostic()?,
+        );
+        parts
+            .headers
+            .insert(CONTENT_LENGTH, HeaderValue::from(slice.len()));
+        return Ok(Response::from_parts(parts, slice));
+    }
+
+    let boundary = format!("chainedge-{:016x}", rand::random::<u64>());
+    let content_type = parts.headers.get(CONTENT_TYPE).cloned();
+    let mut multipart = Vec::new();
+    for range in ranges {
+        multipart.extend_from_slice(format!("\r\n--{}\r\n", boundary).as_bytes());
+        if let Some(content_type) = &content_type {
+            multipart.extend_from_slice(b"Content-Type: ");
+            multipart.extend_from_slice(content_type.as_bytes());
+            multipart.extend_from_slice(b"\r\n");
+        }
+        multipart.extend_from_slice(
+            format!(
+                "Content-Range: bytes {}-{}/{}\r\n\r\n",
+                range.start,
+                range.end - 1,
+                len
+            )
+            .as_bytes(),
+        );
+        multipart.extend_from_slice(&body[range.start as usize..range.end as usize]);
+    }
+    multipart.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+    parts.headers.insert(
+        CONTENT_TYPE,
+        format!("multipart/byteranges; boundary={}", boundary)
+            .parse()
+            .into_diagnostic()?,
+    );
+    parts
+        .headers
+        .insert(CONTENT_LENGTH, HeaderValue::from(multipart.len()));
+
+    Ok(Response::from_parts(parts, multipart.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranged(range: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RANGE, range.parse().unwrap());
+        headers
+    }
+
+    fn stored() -> Response<Bytes> {
+        Response::builder()
+            .header(CONTENT_TYPE, "text/plain")
+            .header(ETAG, "\"v1\"")
+            .header(LAST_MODIFIED, "Wed, 21 Oct 2015 07:28:00 GMT")
+            .body(Bytes::from_static(b"0123456789"))
+            .unwrap()
+    }
+
+    /// Ranges as `(start, end)`, end exclusive.
+    fn parsed(header: &str, len: u64) -> Option<Vec<(u64, u64)>> {
+        parse_ranges(header, len).map(|r| r.into_iter().map(|r| (r.start, r.end)).collect())
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parsed("bytes=0-4", 10), Some(vec![(0, 5)]));
+        assert_eq!(parsed("bytes=-3", 10), Some(vec![(7, 10)]));
+        assert_eq!(parsed("bytes=-500", 10), Some(vec![(0, 10)]));
+        assert_eq!(parsed("bytes=6-", 10), Some(vec![(6, 10)]));
+        assert_eq!(parsed("bytes=8-100", 10), Some(vec![(8, 10)]));
+        assert_eq!(parsed("bytes=0-1, 4-5", 10), Some(vec![(0, 2), (4, 6)]));
+        // not satisfiable, but well formed
+        assert_eq!(parsed("bytes=10-", 10), Some(vec![]));
+        assert_eq!(parsed("bytes=-0", 10), Some(vec![]));
+        // ignored
+        assert_eq!(parsed("items=0-4", 10), None);
+        assert_eq!(parsed("bytes=5-4", 10), None);
+        assert_eq!(parsed("bytes=-", 10), None);
+        assert_eq!(parsed("bytes=a-b", 10), None);
+    }
+
+    #[test]
+    fn answers_a_single_range() {
+        let suffix = apply(&ranged("bytes=-3"), stored()).unwrap();
+        assert_eq!(suffix.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(suffix.headers()[CONTENT_RANGE], "bytes 7-9/10");
+        assert_eq!(&suffix.body()[..], b"789");
+
+        let open_ended = apply(&ranged("bytes=4-"), stored()).unwrap();
+        assert_eq!(open_ended.headers()[CONTENT_RANGE], "bytes 4-9/10");
+        assert_eq!(open_ended.headers()[CONTENT_LENGTH], "6");
+        assert_eq!(&open_ended.body()[..], b"456789");
+    }
+
+    #[test]
+    fn refuses_unsatisfiable_ranges() {
+        let response = apply(&ranged("bytes=20-30"), stored()).unwrap();
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes */10");
+        assert!(response.body().is_empty());
+    }
+
+    #[test]
+    fn answers_several_ranges_as_multipart() {
+        let response = apply(&ranged("bytes=0-2, 1-3, -2"), stored()).unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        let content_type = response.headers()[CONTENT_TYPE].to_str().unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        let body = std::str::from_utf8(response.body()).unwrap();
+        assert_eq!(body.matches(&format!("--{}\r\n", boundary)).count(), 3);
+        assert!(body.ends_with(&format!("--{}--\r\n", boundary)));
+        // overlapping ranges are sent as asked
+        assert!(body.contains("Content-Range: bytes 0-2/10\r\n\r\n012"));
+        assert!(body.contains("Content-Range: bytes 1-3/10\r\n\r\n123"));
+        assert!(body.contains("Content-Range: bytes 8-9/10\r\n\r\n89"));
+        assert_eq!(response.headers()[CONTENT_LENGTH], body.len().to_string().as_str());
+    }
+
+    #[test]
+    fn answers_too_many_ranges_with_the_full_body() {
+        let many: Vec<String> = (0..=MAX_RANGES).map(|i| format!("{0}-{0}", i % 10)).collect();
+        let response = apply(&ranged(&format!("bytes={}", many.join(","))), stored()).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(&response.body()[..], b"0123456789");
+    }
+
+    #[test]
+    fn honours_if_range() {
+        let with_if_range = |if_range: &str| {
+            let mut request = ranged("bytes=0-0");
+            request.insert(IF_RANGE, if_range.parse().unwrap());
+            apply(&request, stored()).unwrap().status()
+        };
+        assert_eq!(with_if_range("\"v1\""), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(with_if_range("\"v2\""), StatusCode::OK);
+        // a weak ETag never matches
+        assert_eq!(with_if_range("W/\"v1\""), StatusCode::OK);
+        assert_eq!(with_if_range("Wed, 21 Oct 2015 07:28:00 GMT"), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(with_if_range("Thu, 22 Oct 2015 07:28:00 GMT"), StatusCode::OK);
+    }
+}
@@ -0,0 +1,48 @@
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+/// What the thread reporting served bytes to the contract last did.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReporterStatus {
+    pub reports: u64,
+    pub last_report_at: Option<u64>,
+    pub last_reported_bytes: u64,
+    /// Serve count of the contract after the last report.
+    pub total_on_chain: Option<String>,
+    pub failures: u64,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<u64>,
+}
+
+lazy_static! {
+    static ref STATUS: RwLock<ReporterStatus> = RwLock::new(ReporterStatus::default());
+}
+
+fn now() -> Option<u64> {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .ok()
+        .map(|d| d.as_secs())
+}
+
+pub fn reported(bytes: u64, total_on_chain: Option<String>) {
+    let mut status = STATUS.write().unwrap();
+    status.reports += 1;
+    status.last_report_at = now();
+    status.last_reported_bytes = bytes;
+    status.total_on_chain = total_on_chain;
+}
+
+pub fn failed(error: String) {
+    let mut status = STATUS.write().unwrap();
+    status.failures += 1;
+    status.last_error = Some(error);
+    status.last_error_at = now();
+}
+
+pub fn status() -> ReporterStatus {
+    STATUS.read().unwrap().clone()
+}
@@ -0,0 +1,60 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Requests kept for the dashboard to show before live ones arrive.
+const RECENT: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+    /// The method is not cached for the site.
+    Bypass,
+    /// A stale entry the origin confirmed with a `304`.
+    Revalidated,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LoggedRequest {
+    /// Seconds since the unix epoch.
+    pub at: u64,
+    pub method: String,
+    pub host: String,
+    pub path: String,
+    pub status: u16,
+    /// Missing when the request failed before reaching the cache.
+    pub cache: Option<CacheStatus>,
+    pub bytes: usize,
+    pub millis: u64,
+}
+
+lazy_static! {
+    static ref RECENT_REQUESTS: Mutex<VecDeque<LoggedRequest>> = Mutex::new(VecDeque::new());
+    static ref LIVE: broadcast::Sender<LoggedRequest> = broadcast::channel(256).0;
+}
+
+pub fn log(request: LoggedRequest) {
+    {
+        let mut recent = RECENT_REQUESTS.lock().unwrap();
+        if recent.len() == RECENT {
+            recent.pop_front();
+        }
+        recent.push_back(request.clone());
+    }
+    // nobody may be listening
+    let _ = LIVE.send(request);
+}
+
+/// Oldest first.
+pub fn recent() -> Vec<LoggedRequest> {
+    RECENT_REQUESTS.lock().unwrap().iter().cloned().collect()
+}
+
+pub fn subscribe() -> broadcast::Receiver<LoggedRequest> {
+    LIVE.subscribe()
+}
@@ -0,0 +1,397 @@
+use std::time::SystemTime;
+
+use http::{
+    header::{CACHE_CONTROL, CONTENT_TYPE, SET_COOKIE},
+    HeaderMap, HeaderName, HeaderValue, Method, Response,
+};
+use http_cache_semantics::{CachePolicy, RequestLike};
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::config::SiteConfig;
+
+/// Stored with an entry whose freshness at the edge is not the one of its `Cache-Control`,
+/// which clients keep getting. Never sent to clients.
+pub(crate) const EDGE_CACHE_CONTROL: HeaderName =
+    HeaderName::from_static("x-chainedge-edge-cache-control");
+/// `Cache-Control` for CDNs only (RFC 9213).
+pub const CDN_CACHE_CONTROL: HeaderName = HeaderName::from_static("cdn-cache-control");
+/// Directives for surrogates (Edge Architecture Specification), of which the edge is one.
+pub const SURROGATE_CONTROL: HeaderName = HeaderName::from_static("surrogate-control");
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheAction {
+    /// Store what the origin headers allow, with the overrides of the rule.
+    #[default]
+    Origin,
+    /// Store for `edge_ttl` whatever the origin headers say, except responses setting cookies.
+    Cache,
+    /// Neither answer from nor store in the cache.
+    Bypass,
+}
+
+/// Per-site rule overriding what the caching headers of the origin allow. The first rule
+/// of a site matching a request applies.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, remote = "Self")]
+pub struct CacheRule {
+    /// Glob on the path, `*` matches within a segment and `**` across segments.
+    pub path: Option<String>,
+    /// Regex on the path and query.
+    pub path_regex: Option<PathRegex>,
+    /// Methods the rule applies to, all when empty. HEAD matches as GET.
+    pub methods: Vec<String>,
+    /// Mime types (without parameters) of the response, a trailing `*` matches a prefix.
+    /// Such a rule is only known to match once the response is, so it can not bypass the
+    /// cache lookup.
+    pub content_types: Vec<String>,
+    pub action: CacheAction,
+    /// Seconds the edge serves the response for, instead of the freshness the origin gives.
+    /// Responses the origin forbids storing are still not stored, unless `action = "cache"`.
+    pub edge_ttl: Option<u64>,
+    /// `max-age` of the `Cache-Control` sent to clients, instead of the origin's.
+    pub browser_ttl: Option<u64>,
+    /// Store responses that set cookies, without their `Set-Cookie`.
+    pub ignore_set_cookie: bool,
+    /// Store responses the origin marked `private`.
+    pub ignore_private: bool,
+}
+
+impl Serialize for CacheRule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        CacheRule::serialize(self, serializer)
+    }
+}
+
+/// Checked as it is read, so an invalid rule fails whatever config it comes in.
+impl<'de> Deserialize<'de> for CacheRule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let rule = CacheRule::deserialize(deserializer)?;
+        rule.validate().map_err(serde::de::Error::custom)?;
+        Ok(rule)
+    }
+}
+
+/// A regex checked when the config is read.
+#[derive(Debug, Clone)]
+pub struct PathRegex(pub Regex);
+
+impl Serialize for PathRegex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for PathRegex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Regex::new(&pattern)
+            .map(PathRegex)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+fn glob_matches(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=path.len()).any(|i| glob_matches(rest, &path[i..])),
+        [b'*', rest @ ..] => {
+            let segment = path.iter().position(|&b| b == b'/').unwrap_or(path.len());
+            (0..=segment).any(|i| glob_matches(rest, &path[i..]))
+        }
+        [c, rest @ ..] => path.first() == Some(c) && glob_matches(rest, &path[1..]),
+    }
+}
+
+fn mime(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(';').next().unwrap_or_default().trim().to_ascii_lowercase())
+}
+
+impl CacheRule {
+    /// Forcing caching needs a TTL, the origin headers give none the edge could follow.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        match self.action {
+            CacheAction::Cache if self.edge_ttl.is_none() => {
+                Err("action = \"cache\" forces caching, it needs an edge_ttl")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Rules on content types do not match without one.
+    pub fn matches(&self, method: &Method, path_and_query: &str, mime: Option<&str>) -> bool {
+        let method = if method == Method::HEAD { &Method::GET } else { method };
+        let path = path_and_query.split('?').next().unwrap_or_default();
+
+        self.path
+            .as_ref()
+            .is_none_or(|glob| glob_matches(glob.as_bytes(), path.as_bytes()))
+            && self
+                .path_regex
+                .as_ref()
+                .is_none_or(|regex| regex.0.is_match(path_and_query))
+            && (self.methods.is_empty()
+                || self.methods.iter().any(|m| m.eq_ignore_ascii_case(method.as_str())))
+            && (self.content_types.is_empty()
+                || mime.is_some_and(|mime| {
+                    self.content_types.iter().any(|t| match t.strip_suffix('*') {
+                        Some(prefix) => mime.starts_with(&prefix.to_ascii_lowercase()),
+                        None => mime.eq_ignore_ascii_case(t),
+                    })
+                }))
+    }
+}
+
+fn directives<'a>(headers: &'a HeaderMap, name: &HeaderName) -> impl Iterator<Item = &'a str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+}
+
+fn directive_name(directive: &str) -> &str {
+    directive.split('=').next().unwrap_or_default().trim()
+}
+
+/// The directives the origin gives the edge, and whether they are meant for it alone:
+/// `CDN-Cache-Control`, else the `Surrogate-Control` directives the edge understands (an
+/// untargeted `max-age` or `no-store`), else `Cache-Control`.
+fn origin_directives(headers: &HeaderMap) -> (Vec<String>, bool) {
+    if headers.contains_key(CDN_CACHE_CONTROL) {
+        let cdn = directives(headers, &CDN_CACHE_CONTROL).map(str::to_owned).collect();
+        return (cdn, true);
+    }
+    let surrogate: Vec<String> = directives(headers, &SURROGATE_CONTROL)
+        // targeted at another surrogate
+        .filter(|d| !d.contains(';'))
+        .filter_map(|d| match directive_name(d).to_ascii_lowercase().as_str() {
+            // the `+<stale>` extension is not supported
+            "max-age" => Some(d.split('+').next().unwrap_or_default().to_owned()),
+            "no-store" | "no-store-remote" => Some("no-store".to_owned()),
+            _ => None,
+        })
+        .collect();
+    if !surrogate.is_empty() {
+        return (surrogate, true);
+    }
+    let cache_control = directives(headers, &CACHE_CONTROL).map(str::to_owned).collect();
+    (cache_control, false)
+}
+
+/// The `Cache-Control` the edge applies instead of the origin's, if the origin sent directives
+/// for the edge alone or `rule` overrides them.
+fn edge_cache_control(rule: Option<&CacheRule>, headers: &HeaderMap) -> Option<HeaderValue> {
+    let (origin, targeted) = origin_directives(headers);
+    let directives: Vec<String> = match rule {
+        Some(CacheRule {
+            action: CacheAction::Cache,
+            edge_ttl: Some(ttl),
+            ..
+        }) => vec![format!("s-maxage={}", ttl)],
+        Some(rule) if rule.edge_ttl.is_some() || rule.ignore_private => origin
+            .into_iter()
+            .filter(|d| {
+                let name = directive_name(d);
+                let overridden = (rule.ignore_private && name.eq_ignore_ascii_case("private"))
+                    || (rule.edge_ttl.is_some() && name.eq_ignore_ascii_case("s-maxage"));
+                !overridden
+            })
+            .chain(rule.edge_ttl.map(|ttl| format!("s-maxage={}", ttl)))
+            .collect(),
+        _ if targeted => origin,
+        _ => return None,
+    };
+    HeaderValue::from_str(&directives.join(", ")).ok()
+}
+
+impl SiteConfig {
+    /// The rule applying to a request, before its response is known.
+    pub fn request_rule(&self, method: &Method, path_and_query: &str) -> Option<&CacheRule> {
+        self.cache_rules
+            .iter()
+            .find(|r| r.matches(method, path_and_query, None))
+    }
+
+    /// The rule applying to a response, given its headers.
+    pub fn response_rule(
+        &self,
+        method: &Method,
+        path_and_query: &str,
+        headers: &HeaderMap,
+    ) -> Option<&CacheRule> {
+        let mime = mime(headers);
+        self.cache_rules
+            .iter()
+            .find(|r| r.matches(method, path_and_query, mime.as_deref()))
+    }
+}
+
+/// The response as it is stored: with the edge freshness the origin or `rule` set, and
+/// without the cookies the rule ignores.
+pub(crate) fn edge_response<B>(rule: Option<&CacheRule>, mut response: Response<B>) -> Response<B> {
+    let headers = response.headers_mut();
+    if rule.is_some_and(|r| r.ignore_set_cookie) {
+        headers.remove(SET_COOKIE);
+    }
+    if let Some(cache_control) = edge_cache_control(rule, headers) {
+        headers.insert(EDGE_CACHE_CONTROL, cache_control);
+    }
+    response
+}
+
+/// Policy of a stored response, following its edge freshness when a rule set one.
+pub(crate) fn edge_policy<Req: RequestLike, B>(
+    request: &Req,
+    response: &Response<B>,
+    response_time: SystemTime,
+) -> CachePolicy {
+    let Some(cache_control) = response.headers().get(EDGE_CACHE_CONTROL) else {
+        return CachePolicy::new_options(request, response, response_time, Default::default());
+    };
+    let mut edge = Response::new(());
+    *edge.status_mut() = response.status();
+    *edge.headers_mut() = response.headers().clone();
+    edge.headers_mut().insert(CACHE_CONTROL, cache_control.clone());
+    CachePolicy::new_options(request, &edge, response_time, Default::default())
+}
+
+/// Whether the edge stores a response under `policy`.
+pub(crate) fn is_worth_storing(rule: Option<&CacheRule>, policy: &CachePolicy) -> bool {
+    rule.is_none_or(|r| r.action != CacheAction::Bypass)
+        && policy.is_storable()
+        && !policy.time_to_live(SystemTime::now()).is_zero()
+}
+
+/// Drops the directives meant for the edge alone from a response to a client.
+pub(crate) fn strip_edge_headers(headers: &mut HeaderMap) {
+    for name in [EDGE_CACHE_CONTROL, CDN_CACHE_CONTROL, SURROGATE_CONTROL] {
+        headers.remove(name);
+    }
+}
+
+/// Replaces the `Cache-Control` clients get when the rule has a browser TTL.
+pub(crate) fn apply_browser_ttl(rule: Option<&CacheRule>, headers: &mut HeaderMap) {
+    let value = rule
+        .and_then(|r| r.browser_ttl)
+        .and_then(|ttl| HeaderValue::from_str(&format!("max-age={}", ttl)).ok());
+    if let Some(value) = value {
+        headers.insert(CACHE_CONTROL, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(toml: &str) -> CacheRule {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn matches_globs_within_and_across_segments() {
+        assert!(glob_matches(b"/static/*.css", b"/static/app.css"));
+        assert!(!glob_matches(b"/static/*.css", b"/static/v1/app.css"));
+        assert!(glob_matches(b"/static/**", b"/static/v1/app.css"));
+        assert!(glob_matches(b"/**/*.png", b"/a/b/c.png"));
+        assert!(!glob_matches(b"/static/**", b"/api/static/a"));
+    }
+
+    #[test]
+    fn matches_methods_paths_and_content_types() {
+        let images = rule("path = \"/img/**\"\nmethods = [\"GET\"]\ncontent_types = [\"image/*\"]");
+        assert!(images.matches(&Method::HEAD, "/img/a.png?w=10", Some("image/png")));
+        assert!(!images.matches(&Method::GET, "/img/a.png", None));
+        assert!(!images.matches(&Method::POST, "/img/a.png", Some("image/png")));
+        assert!(!images.matches(&Method::GET, "/img/a.png", Some("text/html")));
+
+        let api = rule("path_regex = \"^/api/v[0-9]+/.*\\\\?nocache\"");
+        assert!(api.matches(&Method::GET, "/api/v2/items?nocache", None));
+        assert!(!api.matches(&Method::GET, "/api/v2/items", None));
+        assert!(toml::from_str::<CacheRule>("path_regex = \"(\"").is_err());
+    }
+
+    #[test]
+    fn rejects_forced_caching_without_an_edge_ttl() {
+        let error = toml::from_str::<CacheRule>("path = \"/a\"\naction = \"cache\"").unwrap_err();
+        assert!(error.to_string().contains("it needs an edge_ttl"), "{}", error);
+
+        let forced = CacheRule {
+            action: CacheAction::Cache,
+            ..CacheRule::default()
+        };
+        assert!(forced.validate().is_err());
+        assert!(rule("action = \"cache\"\nedge_ttl = 0").validate().is_ok());
+        assert!(rule("action = \"bypass\"").validate().is_ok());
+
+        let written = toml::to_string(&rule("path = \"/a/**\"\naction = \"cache\"\nedge_ttl = 60")).unwrap();
+        assert_eq!(rule(&written).edge_ttl, Some(60));
+    }
+
+    #[test]
+    fn overrides_the_edge_freshness() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("private, max-age=10, s-maxage=20"));
+
+        let ttl = rule("edge_ttl = 300\nignore_private = true");
+        assert_eq!(edge_cache_control(Some(&ttl), &headers).unwrap(), "max-age=10, s-maxage=300");
+        let forced = rule("action = \"cache\"\nedge_ttl = 60");
+        assert_eq!(edge_cache_control(Some(&forced), &headers).unwrap(), "s-maxage=60");
+        assert!(edge_cache_control(Some(&rule("browser_ttl = 5")), &headers).is_none());
+        assert!(edge_cache_control(None, &headers).is_none());
+    }
+
+    #[test]
+    fn prefers_the_directives_meant_for_the_edge() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=10"));
+        headers.insert(
+            SURROGATE_CONTROL,
+            HeaderValue::from_static("max-age=3600+600, content=\"ESI/1.0\", no-store;other"),
+        );
+        assert_eq!(edge_cache_control(None, &headers).unwrap(), "max-age=3600");
+
+        headers.insert(CDN_CACHE_CONTROL, HeaderValue::from_static("max-age=60, stale-if-error=30"));
+        assert_eq!(edge_cache_control(None, &headers).unwrap(), "max-age=60, stale-if-error=30");
+        let ttl = rule("edge_ttl = 300");
+        assert_eq!(
+            edge_cache_control(Some(&ttl), &headers).unwrap(),
+            "max-age=60, stale-if-error=30, s-maxage=300"
+        );
+
+        let mut stripped = headers.clone();
+        strip_edge_headers(&mut stripped);
+        assert_eq!(stripped.len(), 1);
+    }
+
+    #[test]
+    fn stores_forced_responses_for_the_edge_ttl() {
+        let request = http::Request::get("/a").body(()).unwrap();
+        let response = Response::builder()
+            .header(CACHE_CONTROL, "no-store")
+            .header(SET_COOKIE, "session=1")
+            .body(())
+            .unwrap();
+        let forced = rule("action = \"cache\"\nedge_ttl = 60");
+        let policy = edge_policy(&request, &response, SystemTime::now());
+        assert!(!is_worth_storing(Some(&forced), &policy));
+
+        // the cookie keeps it out until it is ignored
+        let stored = edge_response(Some(&forced), response);
+        let policy = edge_policy(&request, &stored, SystemTime::now());
+        assert!(!is_worth_storing(Some(&forced), &policy));
+
+        let ignoring = rule("action = \"cache\"\nedge_ttl = 60\nignore_set_cookie = true");
+        let stored = edge_response(Some(&ignoring), stored);
+        assert!(!stored.headers().contains_key(SET_COOKIE));
+        let policy = edge_policy(&request, &stored, SystemTime::now());
+        assert!(is_worth_storing(Some(&ignoring), &policy));
+        assert!(policy.time_to_live(SystemTime::now()).as_secs() >= 59);
+    }
+}
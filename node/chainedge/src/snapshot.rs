@@ -0,0 +1,302 @@
+//! Portable snapshots of cache entries, to carry a warm cache over to another node.
+//!
+//! A snapshot is a gzip stream of `MAGIC`, then one record per entry: the length of its
+//! JSON header (`u32`, little endian), the header, the length of the entry (`u64`, little
+//! endian) and the entry in its envelope, as stored on disk. A zero header length ends the
+//! snapshot. The entry holds the stored request, response and `cached_at`, so the importing
+//! node applies the same policy. Compressed variants are not carried, they are made again.
+
+use std::{
+    io::{self, Read, Write},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use cacache::Integrity;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use miette::{miette, Context, IntoDiagnostic, Result};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{compression, envelope, policy_from_cached, CachedResponse};
+
+pub const MAGIC: [u8; 8] = *b"CHEDSNAP";
+/// Longer headers mean the stream is not a snapshot or is damaged.
+const MAX_HEADER_LEN: u32 = 64 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecordHeader {
+    key: String,
+    /// Of the entry bytes that follow.
+    integrity: String,
+    cached_at: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ExportReport {
+    pub entries: usize,
+    /// Bytes of the entries, before compression.
+    pub bytes: u64,
+    /// Entries not fresh anymore, which the importing node could not serve.
+    pub expired: usize,
+    pub unreadable: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub imported: Vec<String>,
+    pub expired: usize,
+    /// Entries the cache already had, kept as they were.
+    pub existing: usize,
+    /// Entries turned down by the importer, e.g. of sites the node does not serve.
+    pub refused: usize,
+    pub invalid: Vec<InvalidRecord>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InvalidRecord {
+    pub key: String,
+    pub reason: String,
+}
+
+fn is_fresh(cached: CachedResponse) -> bool {
+    policy_from_cached(cached)
+        .is_ok_and(|(policy, _, _)| !policy.time_to_live(SystemTime::now()).is_zero())
+}
+
+fn write_record(out: &mut impl Write, key: &str, cached_at: SystemTime, entry: &[u8]) -> io::Result<()> {
+    let header = RecordHeader {
+        key: key.to_owned(),
+        integrity: Integrity::from(entry).to_string(),
+        cached_at: cached_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+    };
+    let header = serde_json::to_vec(&header)?;
+    out.write_all(&(header.len() as u32).to_le_bytes())?;
+    out.write_all(&header)?;
+    out.write_all(&(entry.len() as u64).to_le_bytes())?;
+    out.write_all(entry)
+}
+
+/// Writes the fresh entries whose url (the key without its method) starts with `prefix`.
+/// Entries of older schema versions are written with the current one.
+pub fn export(cache_dir: &str, prefix: Option<&str>, out: impl Write) -> Result<ExportReport> {
+    let mut out = GzEncoder::new(out, Compression::default());
+    out.write_all(&MAGIC).into_diagnostic()?;
+
+    let mut report = ExportReport::default();
+    let keys = cacache::list_sync(cache_dir)
+        .filter_map(|m| m.ok())
+        .map(|m| m.key)
+        .filter(|k| !compression::is_variant_key(k))
+        .filter(|k| {
+            let url = k.split_once('\t').map_or(k.as_str(), |(_, url)| url);
+            prefix.is_none_or(|p| url.starts_with(p))
+        });
+
+    for key in keys {
+        let decoded = cacache::read_sync(cache_dir, &key)
+            .into_diagnostic()
+            .and_then(|bytes| envelope::decode(&bytes).into_diagnostic());
+        let Ok((cached, _)) = decoded else {
+            report.unreadable.push(key);
+            continue;
+        };
+        let cached_at = cached.cached_at;
+        let entry = envelope::encode(&cached)?;
+        if !is_fresh(cached) {
+            report.expired += 1;
+            continue;
+        }
+        write_record(&mut out, &key, cached_at, &entry).into_diagnostic()?;
+        report.entries += 1;
+        report.bytes += entry.len() as u64;
+    }
+
+    out.write_all(&0u32.to_le_bytes()).into_diagnostic()?;
+    out.finish().into_diagnostic()?.flush().into_diagnostic()?;
+    info!("Exported {} entries", report.entries);
+    Ok(report)
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    input.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    input.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// The entry of a record, if it matches its integrity and can be decoded.
+fn check_record(header: &RecordHeader, entry: &[u8]) -> Result<CachedResponse> {
+    let expected = Integrity::from_str(&header.integrity)
+        .into_diagnostic()
+        .wrap_err("Invalid integrity")?;
+    expected
+        .check(entry)
+        .into_diagnostic()
+        .wrap_err("Entry does not match its integrity")?;
+    Ok(envelope::decode(entry).into_diagnostic()?.0)
+}
+
+/// Stores the fresh entries of a snapshot that `accept` agrees to, given their key and
+/// response body. A damaged record is skipped, a damaged stream stops the import with the
+/// entries read so far stored. Existing entries are kept unless `overwrite`.
+pub fn import(
+    cache_dir: &str,
+    input: impl Read,
+    overwrite: bool,
+    accept: impl Fn(&str, &[u8]) -> bool,
+) -> Result<ImportReport> {
+    let mut input = GzDecoder::new(input);
+    let mut magic = [0; MAGIC.len()];
+    input
+        .read_exact(&mut magic)
+        .into_diagnostic()
+        .wrap_err("Not a snapshot")?;
+    if magic != MAGIC {
+        return Err(miette!("Not a snapshot"));
+    }
+
+    let mut report = ImportReport::default();
+    loop {
+        let header_len = read_u32(&mut input)
+            .into_diagnostic()
+            .wrap_err("Snapshot ends without its end marker")?;
+        if header_len == 0 {
+            break;
+        }
+        if header_len > MAX_HEADER_LEN {
+            return Err(miette!("Damaged snapshot, record header of {} bytes", header_len));
+        }
+        let mut header = vec![0; header_len as usize];
+        input.read_exact(&mut header).into_diagnostic()?;
+        let header: RecordHeader = serde_json::from_slice(&header)
+            .into_diagnostic()
+            .wrap_err("Damaged snapshot, unreadable record header")?;
+        let entry_len = read_u64(&mut input).into_diagnostic()?;
+        let mut entry = Vec::new();
+        (&mut input)
+            .take(entry_len)
+            .read_to_end(&mut entry)
+            .into_diagnostic()?;
+        if entry.len() as u64 != entry_len {
+            return Err(miette!("Snapshot ends within the entry of {:?}", header.key));
+        }
+
+        let cached = match check_record(&header, &entry) {
+            Ok(cached) => cached,
+            Err(e) => {
+                report.invalid.push(InvalidRecord {
+                    key: header.key,
+                    reason: format!("{:#}", e),
+                });
+                continue;
+            }
+        };
+        if !accept(&header.key, &cached.response.body) {
+            report.refused += 1;
+            continue;
+        }
+        if !is_fresh(cached) {
+            report.expired += 1;
+            continue;
+        }
+        let exists = cacache::metadata_sync(cache_dir, &header.key)
+            .into_diagnostic()?
+            .is_some();
+        if exists && !overwrite {
+            report.existing += 1;
+            continue;
+        }
+
+        cacache::write_sync(cache_dir, &header.key, &entry).into_diagnostic()?;
+        for variant in compression::variant_keys(&header.key) {
+            let _ = cacache::remove_sync(cache_dir, variant);
+        }
+        report.imported.push(header.key);
+    }
+
+    info!(
+        "Imported {} entries, skipped {} expired and {} existing",
+        report.imported.len(),
+        report.expired,
+        report.existing
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{IntoInnerCachedRequest, IntoInnerCachedResponse};
+    use axum::body::Bytes;
+
+    fn entry(cache_control: &str) -> Vec<u8> {
+        let request = http::Request::get("/a").body(()).unwrap();
+        let response = http::Response::builder()
+            .header("cache-control", cache_control)
+            .body(Bytes::from_static(b"body"))
+            .unwrap();
+        envelope::encode(&CachedResponse {
+            request: request.into_inner_cached_request().unwrap(),
+            response: response.into_inner_cached_response().unwrap(),
+            cached_at: SystemTime::now(),
+        })
+        .unwrap()
+    }
+
+    fn cache_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("chainedge-snapshot-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn carries_fresh_entries_to_another_cache() {
+        let (from, to) = (cache_dir("from"), cache_dir("to"));
+        cacache::write_sync(&from, "GET\ta/fresh", entry("max-age=60")).unwrap();
+        cacache::write_sync(&from, "GET\ta/fresh\te:br", b"br").unwrap();
+        cacache::write_sync(&from, "GET\ta/expired", entry("max-age=0")).unwrap();
+        cacache::write_sync(&from, "GET\tb/other", entry("max-age=60")).unwrap();
+        cacache::write_sync(&to, "GET\tb/other", entry("max-age=60")).unwrap();
+
+        let mut snapshot = Vec::new();
+        let exported = export(&from, None, &mut snapshot).unwrap();
+        assert_eq!(exported.entries, 2);
+        assert_eq!(exported.expired, 1);
+
+        let imported = import(&to, snapshot.as_slice(), false, |_, _| true).unwrap();
+        assert_eq!(imported.imported, vec!["GET\ta/fresh"]);
+        assert_eq!(imported.existing, 1);
+        assert!(cacache::read_sync(&to, "GET\ta/fresh\te:br").is_err());
+
+        let other = cache_dir("refused");
+        let refused = import(&other, snapshot.as_slice(), false, |k, _| !k.contains("a/")).unwrap();
+        assert_eq!((refused.imported.len(), refused.refused), (1, 1));
+
+        let mut prefixed = Vec::new();
+        assert_eq!(export(&from, Some("b/"), &mut prefixed).unwrap().entries, 1);
+
+        for dir in [from, to, other] {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+
+    #[test]
+    fn rejects_records_not_matching_their_integrity() {
+        let header = RecordHeader {
+            key: "GET\ta/fresh".to_owned(),
+            integrity: Integrity::from(b"something else").to_string(),
+            cached_at: 0,
+        };
+        assert!(check_record(&header, &entry("max-age=60")).is_err());
+        assert!(import(&cache_dir("garbage"), &b"not a snapshot"[..], false, |_, _| true).is_err());
+    }
+}
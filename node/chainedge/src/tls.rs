@@ -0,0 +1,502 @@
+use std::{
+    collections::HashMap,
+    io::BufReader,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    extract::{Host, State},
+    http::Request,
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+};
+use http::{
+    header::{ALT_SVC, STRICT_TRANSPORT_SECURITY},
+    HeaderValue,
+};
+use miette::{miette, Context, IntoDiagnostic, Result};
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    Certificate, PrivateKey, ServerConfig,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    acme,
+    config::{Config, SiteConfig},
+};
+
+/// The HTTPS listener of the node.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TlsConfig {
+    #[serde(default = "default_listen")]
+    pub listen: String,
+    /// How often certificate files are checked for changes.
+    #[serde(default = "default_reload_interval_secs")]
+    pub reload_interval_secs: u64,
+    /// Also serve HTTP/3 over QUIC on the UDP port of `listen`, advertised through `Alt-Svc`.
+    #[serde(default)]
+    pub http3: bool,
+}
+
+impl TlsConfig {
+    pub fn port(&self) -> &str {
+        self.listen.rsplit_once(':').map_or("443", |(_, port)| port)
+    }
+}
+
+fn default_listen() -> String {
+    "0.0.0.0:3443".to_owned()
+}
+
+fn default_reload_interval_secs() -> u64 {
+    30
+}
+
+/// Certificate of a site, selected by SNI for its front domain.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SiteTlsConfig {
+    /// Defaults to the ACME data dir when `acme` is set.
+    #[serde(default)]
+    pub cert: PathBuf,
+    #[serde(default)]
+    pub key: PathBuf,
+    /// Obtain and renew the certificate through the node's ACME account.
+    #[serde(default)]
+    pub acme: bool,
+    /// Answer plain HTTP requests for the site with a redirect to HTTPS.
+    #[serde(default = "default_redirect_http")]
+    pub redirect_http: bool,
+    pub hsts: Option<HstsConfig>,
+}
+
+fn default_redirect_http() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HstsConfig {
+    pub max_age: u64,
+    #[serde(default)]
+    pub include_subdomains: bool,
+    #[serde(default)]
+    pub preload: bool,
+}
+
+impl HstsConfig {
+    fn header_value(&self) -> String {
+        let mut value = format!("max-age={}", self.max_age);
+        if self.include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if self.preload {
+            value.push_str("; preload");
+        }
+        value
+    }
+}
+
+/// ALPN protocol of TLS-ALPN-01 validation connections.
+const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+/// Host name without the port, as presented through SNI.
+pub fn host_name(domain: &str) -> String {
+    domain
+        .rsplit_once(':')
+        .filter(|(_, port)| port.chars().all(|c| c.is_ascii_digit()))
+        .map_or(domain, |(host, _)| host)
+        .to_ascii_lowercase()
+}
+
+fn load_certified_key(tls: &SiteTlsConfig) -> Result<CertifiedKey> {
+    let cert_file = std::fs::File::open(&tls.cert)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Could not open certificate {}", tls.cert.display()))?;
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut BufReader::new(cert_file))
+        .into_diagnostic()?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(miette!("No certificate found in {}", tls.cert.display()));
+    }
+
+    let key_file = std::fs::File::open(&tls.key)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Could not open private key {}", tls.key.display()))?;
+    let key = rustls_pemfile::read_all(&mut BufReader::new(key_file))
+        .into_diagnostic()?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| miette!("No private key found in {}", tls.key.display()))?;
+    let key = sign::any_supported_type(&key)
+        .map_err(|_| miette!("Unsupported private key in {}", tls.key.display()))?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+struct LoadedCert {
+    tls: SiteTlsConfig,
+    modified: Option<SystemTime>,
+    /// Missing until ACME issued the first certificate.
+    key: Option<Arc<CertifiedKey>>,
+}
+
+fn modified(tls: &SiteTlsConfig) -> Option<SystemTime> {
+    let cert = std::fs::metadata(&tls.cert).and_then(|m| m.modified()).ok()?;
+    let key = std::fs::metadata(&tls.key).and_then(|m| m.modified()).ok()?;
+    Some(cert.max(key))
+}
+
+/// Picks the certificate of the front domain named in the client hello.
+#[derive(Default)]
+pub struct CertResolver {
+    certs: RwLock<HashMap<String, LoadedCert>>,
+    /// TLS-ALPN-01 validation certificates of pending ACME orders.
+    alpn_challenges: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl CertResolver {
+    pub fn load(config: &Config) -> Result<Self> {
+        let resolver = CertResolver::default();
+        for site in &config.sites {
+            if let Some(tls) = &site.tls {
+                resolver.insert(site, tls)?;
+            }
+        }
+        Ok(resolver)
+    }
+
+    fn insert(&self, site: &SiteConfig, tls: &SiteTlsConfig) -> Result<()> {
+        let key = match load_certified_key(tls) {
+            Ok(key) => Some(Arc::new(key)),
+            Err(e) if tls.acme => {
+                info!("No certificate yet for {}: {}", site.front_domain, e);
+                None
+            }
+            Err(e) => return Err(e),
+        };
+        let loaded = LoadedCert {
+            tls: tls.clone(),
+            modified: modified(tls),
+            key,
+        };
+        self.certs
+            .write()
+            .unwrap()
+            .insert(host_name(&site.front_domain), loaded);
+        Ok(())
+    }
+
+    /// Reloads the certificates whose files changed on disk since they were last read.
+    pub fn reload_changed(&self) {
+        let stale: Vec<(String, SiteTlsConfig)> = self
+            .certs
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, c)| modified(&c.tls) != c.modified || c.key.is_none())
+            .map(|(host, c)| (host.clone(), c.tls.clone()))
+            .collect();
+
+        for (host, tls) in stale {
+            match load_certified_key(&tls) {
+                Ok(key) => {
+                    info!("Reloaded certificate for {}", host);
+                    self.certs.write().unwrap().insert(
+                        host,
+                        LoadedCert {
+                            modified: modified(&tls),
+                            tls,
+                            key: Some(Arc::new(key)),
+                        },
+                    );
+                }
+                // keep serving the previous certificate until the files are fixed
+                Err(e) if !tls.acme => warn!("Could not reload certificate for {}: {}", host, e),
+                Err(_) => {}
+            }
+        }
+    }
+
+    pub fn set_alpn_challenge(&self, host: &str, key: CertifiedKey) {
+        self.alpn_challenges
+            .write()
+            .unwrap()
+            .insert(host_name(host), Arc::new(key));
+    }
+
+    pub fn clear_alpn_challenge(&self, host: &str) {
+        self.alpn_challenges.write().unwrap().remove(&host_name(host));
+    }
+
+    /// The certificate presented to TLS-ALPN-01 validation connections for `host`.
+    pub fn alpn_challenge(&self, host: &str) -> Option<Arc<CertifiedKey>> {
+        self.alpn_challenges.read().unwrap().get(&host_name(host)).cloned()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let name = client_hello.server_name()?.to_ascii_lowercase();
+
+        let acme_validation = client_hello
+            .alpn()
+            .is_some_and(|mut protocols| protocols.any(|p| p == ACME_TLS_ALPN));
+        if acme_validation {
+            return self.alpn_challenge(&name);
+        }
+
+        self.certs
+            .read()
+            .unwrap()
+            .get(&name)
+            .and_then(|c| c.key.clone())
+    }
+}
+
+pub fn server_config(resolver: Arc<CertResolver>) -> Arc<ServerConfig> {
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    config.alpn_protocols = vec![
+        b"h2".to_vec(),
+        b"http/1.1".to_vec(),
+        ACME_TLS_ALPN.to_vec(),
+    ];
+    Arc::new(config)
+}
+
+pub fn quic_server_config(resolver: Arc<CertResolver>) -> Arc<ServerConfig> {
+    let mut config = ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .expect("TLS 1.3 is supported")
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"h3".to_vec()];
+    Arc::new(config)
+}
+
+pub fn start_reload_thread(
+    resolver: Arc<CertResolver>,
+    interval: Duration,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            let resolver = resolver.clone();
+            let _ = tokio::task::spawn_blocking(move || resolver.reload_changed()).await;
+        }
+    })
+}
+
+/// On the plain listener: redirects requests for sites serving HTTPS.
+pub(crate) async fn redirect_http<B>(
+    State(config): State<Arc<Config>>,
+    Host(host): Host,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let site = config.site_for_host(&host);
+    let Some(tls_listen) = &config.tls else {
+        return next.run(request).await;
+    };
+    // HTTP-01 validation happens over plain HTTP
+    if request.uri().path().starts_with(acme::HTTP_CHALLENGE_PREFIX) {
+        return next.run(request).await;
+    }
+    if !site.and_then(|s| s.tls.as_ref()).is_some_and(|t| t.redirect_http) {
+        return next.run(request).await;
+    }
+
+    let authority = match tls_listen.port() {
+        "443" => host_name(&host),
+        port => format!("{}:{}", host_name(&host), port),
+    };
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |p| p.as_str());
+
+    Redirect::permanent(&format!("https://{}{}", authority, path)).into_response()
+}
+
+/// On the TLS listeners: adds `Strict-Transport-Security` for sites configuring it,
+/// and advertises HTTP/3 when it is enabled.
+pub(crate) async fn secure_headers<B>(
+    State(config): State<Arc<Config>>,
+    Host(host): Host,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let hsts = config
+        .site_for_host(&host)
+        .and_then(|s| s.tls.as_ref())
+        .and_then(|t| t.hsts.as_ref())
+        .and_then(|h| HeaderValue::from_str(&h.header_value()).ok());
+
+    let alt_svc = config
+        .tls
+        .as_ref()
+        .filter(|t| t.http3)
+        .and_then(|t| HeaderValue::from_str(&format!("h3=\":{}\"; ma=86400", t.port())).ok());
+
+    let mut response = next.run(request).await;
+    if let Some(hsts) = hsts {
+        response
+            .headers_mut()
+            .insert(STRICT_TRANSPORT_SECURITY, hsts);
+    }
+    if let Some(alt_svc) = alt_svc {
+        response.headers_mut().insert(ALT_SVC, alt_svc);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{
+        client::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        ClientConfig, ClientConnection, Connection, DigitallySignedStruct, ServerConnection,
+        ServerName,
+    };
+
+    const DOMAIN: &str = "chainedge.test";
+
+    /// Accepts any certificate and keeps the one the server presented.
+    #[derive(Default)]
+    struct Presented(std::sync::Mutex<Option<Certificate>>);
+
+    impl ServerCertVerifier for Presented {
+        fn verify_server_cert(
+            &self,
+            end_entity: &Certificate,
+            _: &[Certificate],
+            _: &ServerName,
+            _: &mut dyn Iterator<Item = &[u8]>,
+            _: &[u8],
+            _: SystemTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            *self.0.lock().unwrap() = Some(end_entity.clone());
+            Ok(ServerCertVerified::assertion())
+        }
+
+        // challenge certificates carry a critical extension webpki does not know
+        fn verify_tls12_signature(
+            &self,
+            _: &[u8],
+            _: &Certificate,
+            _: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            _: &[u8],
+            _: &Certificate,
+            _: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+    }
+
+    fn transfer(from: &mut Connection, to: &mut Connection) -> Result<bool, rustls::Error> {
+        let mut buf = Vec::new();
+        while from.wants_write() {
+            from.write_tls(&mut buf).unwrap();
+        }
+        let mut reader = buf.as_slice();
+        while !reader.is_empty() {
+            to.read_tls(&mut reader).unwrap();
+            to.process_new_packets()?;
+        }
+        Ok(!buf.is_empty())
+    }
+
+    /// Connects in memory with the given ALPN protocols and returns the certificate served.
+    fn presented(resolver: Arc<CertResolver>, alpn: &[u8]) -> Option<Certificate> {
+        let verifier = Arc::new(Presented::default());
+        let mut client_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(verifier.clone())
+            .with_no_client_auth();
+        client_config.alpn_protocols = vec![alpn.to_vec()];
+
+        let mut client = Connection::from(
+            ClientConnection::new(Arc::new(client_config), DOMAIN.try_into().unwrap()).unwrap(),
+        );
+        let mut server =
+            Connection::from(ServerConnection::new(server_config(resolver)).unwrap());
+        loop {
+            let sent = transfer(&mut client, &mut server).ok()?;
+            let answered = transfer(&mut server, &mut client).ok()?;
+            if !sent && !answered {
+                break;
+            }
+        }
+
+        let mut presented = verifier.0.lock().unwrap();
+        presented.take()
+    }
+
+    fn self_signed() -> rcgen::Certificate {
+        rcgen::generate_simple_self_signed(vec![DOMAIN.to_owned()]).unwrap()
+    }
+
+    #[test]
+    fn presents_the_challenge_only_to_validation_connections() {
+        let dir = std::env::temp_dir().join(format!("chainedge-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let site_cert = self_signed();
+        std::fs::write(dir.join("cert.pem"), site_cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(dir.join("key.pem"), site_cert.serialize_private_key_pem()).unwrap();
+        let site: SiteConfig = toml::from_str(&format!(
+            "front_domain = \"{}:3443\"\norigin_domain = \"origin.test\"\n[tls]\ncert = {:?}\nkey = {:?}\n",
+            DOMAIN,
+            dir.join("cert.pem"),
+            dir.join("key.pem"),
+        ))
+        .unwrap();
+        let resolver = Arc::new(CertResolver::default());
+        resolver.insert(&site, site.tls.as_ref().unwrap()).unwrap();
+        // rcgen signs again on every serialization, so the certificate is read back from the file
+        let site_der = Certificate(
+            rustls_pemfile::certs(&mut BufReader::new(
+                std::fs::File::open(dir.join("cert.pem")).unwrap(),
+            ))
+            .unwrap()
+            .remove(0),
+        );
+
+        assert_eq!(presented(resolver.clone(), b"h2"), Some(site_der.clone()));
+        // no validation is pending, so validation connections get no certificate at all
+        assert_eq!(presented(resolver.clone(), ACME_TLS_ALPN), None);
+
+        let challenge = self_signed();
+        let challenge_der = Certificate(challenge.serialize_der().unwrap());
+        let key = sign::any_supported_type(&PrivateKey(challenge.serialize_private_key_der()))
+            .unwrap();
+        resolver.set_alpn_challenge(DOMAIN, CertifiedKey::new(vec![challenge_der.clone()], key));
+        assert_eq!(presented(resolver.clone(), ACME_TLS_ALPN), Some(challenge_der));
+        assert_eq!(presented(resolver.clone(), b"http/1.1"), Some(site_der));
+
+        resolver.clear_alpn_challenge(DOMAIN);
+        assert!(resolver.alpn_challenge(DOMAIN).is_none());
+        assert_eq!(presented(resolver, ACME_TLS_ALPN), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
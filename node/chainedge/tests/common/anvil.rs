@@ -0,0 +1,145 @@
+//! An anvil node with `ChainEdge.sol` deployed, for running tests against the RPC
+//! backend. Needs Foundry: `forge` compiles the contract and `anvil` runs the chain.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+    time::Duration,
+};
+
+use chainedge::chain::{BackendKind, ChainConfig};
+use ethers::{abi::Abi, prelude::*};
+use serde_json::Value;
+use tokio::{
+    process::{Child, Command},
+    sync::OnceCell,
+};
+
+use super::{ephemeral_listener, EVENTUALLY};
+
+/// Signs the deployment and, through `chain.wallet_key`, everything the node sends.
+/// Any key does, anvil funds it before the deployment.
+const OWNER_KEY: &str = "0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+
+static ARTIFACT: OnceCell<(Abi, Bytes)> = OnceCell::const_new();
+
+pub struct Anvil {
+    /// Killed with the harness.
+    _process: Child,
+    pub ws_url: String,
+    pub contract: Address,
+}
+
+fn contract_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../../contract")
+}
+
+async fn installed(binary: &str) -> bool {
+    Command::new(binary)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await
+        .is_ok_and(|s| s.success())
+}
+
+/// Compiles `ChainEdge.sol` once per test binary, as `out/ChainEdge.sol/ChainEdge.json`.
+async fn artifact() -> (Abi, Bytes) {
+    ARTIFACT
+        .get_or_init(|| async {
+            assert!(installed("forge").await, "forge is not installed, see Foundry");
+            let built = Command::new("forge")
+                .arg("build")
+                .current_dir(contract_dir())
+                .status()
+                .await
+                .expect("could not run forge");
+            assert!(built.success(), "forge build failed");
+
+            let path = contract_dir().join("out/ChainEdge.sol/ChainEdge.json");
+            let artifact: Value =
+                serde_json::from_slice(&std::fs::read(path).expect("no ChainEdge artifact"))
+                    .unwrap();
+            let abi: Abi = serde_json::from_value(artifact["abi"].clone()).unwrap();
+            let bytecode: Bytes = artifact["bytecode"]["object"]
+                .as_str()
+                .expect("the artifact has no bytecode")
+                .parse()
+                .unwrap();
+            (abi, bytecode)
+        })
+        .await
+        .clone()
+}
+
+impl Anvil {
+    /// Starts anvil on an ephemeral port and deploys the contract. Panics when Foundry
+    /// is not installed, the tests needing it are `#[ignore]`d.
+    pub async fn start() -> Anvil {
+        assert!(installed("anvil").await, "anvil is not installed, see Foundry");
+        let (abi, bytecode) = artifact().await;
+
+        let port = ephemeral_listener().local_addr().unwrap().port();
+        let process = Command::new("anvil")
+            .args(["--host", "127.0.0.1", "--port", &port.to_string()])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .expect("could not start anvil");
+
+        let rpc_url = format!("http://127.0.0.1:{}", port);
+        let provider = Provider::<Http>::try_from(rpc_url.as_str())
+            .unwrap()
+            .interval(Duration::from_millis(100));
+        let started = tokio::time::Instant::now();
+        let chain_id = loop {
+            match provider.get_chainid().await {
+                Ok(chain_id) => break chain_id.as_u64(),
+                Err(_) if started.elapsed() < EVENTUALLY => {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+                Err(e) => panic!("anvil did not start: {}", e),
+            }
+        };
+
+        let owner = OWNER_KEY
+            .parse::<LocalWallet>()
+            .unwrap()
+            .with_chain_id(chain_id);
+        provider
+            .request::<_, Value>(
+                "anvil_setBalance",
+                (owner.address(), U256::exp10(20)),
+            )
+            .await
+            .expect("could not fund the owner");
+
+        let client = Arc::new(SignerMiddleware::new(provider, owner));
+        let contract = ContractFactory::new(abi, bytecode, client)
+            .deploy(())
+            .unwrap()
+            .send()
+            .await
+            .expect("could not deploy ChainEdge");
+
+        Anvil {
+            _process: process,
+            ws_url: format!("ws://127.0.0.1:{}", port),
+            contract: contract.address(),
+        }
+    }
+
+    pub fn chain_config(&self) -> ChainConfig {
+        ChainConfig {
+            backend: BackendKind::Ws,
+            rpc_url: self.ws_url.clone(),
+            contract_address: format!("{:?}", self.contract),
+            chain_id: None,
+            links: Vec::new(),
+            wallet_key: Some(OWNER_KEY.to_owned()),
+        }
+    }
+}
@@ -0,0 +1,323 @@
+//! Boots `origin_server`, an in-process `ChainEdge` contract and an edge node on
+//! ephemeral ports. Nothing outside the test process is needed, not even a chain:
+//! [`MockChain`] mines every transaction immediately and emits the events of
+//! `ChainEdge.sol`. [`Harness::start_on_anvil`] deploys the real contract to anvil
+//! instead, for the `#[ignore]`d tests run with `cargo test -- --ignored` where
+//! Foundry is installed.
+
+#![allow(dead_code)]
+
+mod anvil;
+
+use std::{
+    future::Future,
+    net::{SocketAddr, TcpListener},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Once,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use chainedge::{
+    cdn_list::ListAction,
+    chain::{self, BackendInfo, ChainBackend, LinkEvent, MockChain, Receipt},
+    config::{Config, SiteConfig},
+    Node,
+};
+use ethers::types::{H256, U256};
+use futures::stream::BoxStream;
+use reqwest::{header, redirect, Response, StatusCode};
+use serde_json::Value;
+use tokio::{sync::oneshot, task::JoinHandle};
+
+use anvil::Anvil;
+
+pub const ADMIN_PASSWORD: &str = "e2e-admin";
+
+/// How long [`Harness::eventually`] waits for the node to catch up.
+const EVENTUALLY: Duration = Duration::from_secs(15);
+
+static CACHE_DIR: Once = Once::new();
+
+/// All nodes of a test binary share the cache directory, their entries are
+/// apart since every node has a front domain of its own.
+fn use_temporary_cache_dir() {
+    CACHE_DIR.call_once(|| {
+        let dir = std::env::temp_dir().join(format!("chainedge-e2e-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::env::set_var("CHAINEDGE_CACHE_DIR", &dir);
+    });
+}
+
+pub struct Harness {
+    pub edge: SocketAddr,
+    pub origin: SocketAddr,
+    /// The backend the node uses, calls are made as the contract owner.
+    pub chain: Arc<dyn ChainBackend>,
+    /// Does not follow redirects, so they can be asserted on.
+    pub client: reqwest::Client,
+    shutdown: Option<oneshot::Sender<()>>,
+    node: Option<JoinHandle<miette::Result<()>>>,
+    anvil: Option<Anvil>,
+}
+
+/// Counts the event streams the node opened, it misses the events sent before.
+#[derive(Debug)]
+struct Followed {
+    chain: Arc<dyn ChainBackend>,
+    listeners: AtomicUsize,
+}
+
+#[async_trait]
+impl ChainBackend for Followed {
+    fn info(&self) -> BackendInfo {
+        self.chain.info()
+    }
+
+    async fn block_number(&self) -> miette::Result<u64> {
+        self.chain.block_number().await
+    }
+
+    async fn cdn_list(&self) -> miette::Result<Vec<String>> {
+        self.chain.cdn_list().await
+    }
+
+    async fn serve_count(&self) -> miette::Result<U256> {
+        self.chain.serve_count().await
+    }
+
+    async fn add_serve_count(&self, count: u64) -> miette::Result<H256> {
+        self.chain.add_serve_count(count).await
+    }
+
+    async fn change_list(&self, action: ListAction, links: Vec<String>) -> miette::Result<H256> {
+        self.chain.change_list(action, links).await
+    }
+
+    async fn receipt(&self, hash: H256) -> miette::Result<Option<Receipt>> {
+        self.chain.receipt(hash).await
+    }
+
+    async fn link_events(&self) -> miette::Result<BoxStream<'static, miette::Result<LinkEvent>>> {
+        let events = self.chain.link_events().await?;
+        self.listeners.fetch_add(1, Ordering::SeqCst);
+        Ok(events)
+    }
+}
+
+fn ephemeral_listener() -> TcpListener {
+    TcpListener::bind("127.0.0.1:0").expect("could not bind an ephemeral port")
+}
+
+impl Harness {
+    pub async fn start() -> Harness {
+        Self::start_with_links(Vec::new()).await
+    }
+
+    /// Starts with `links` already on the CDN list, as if added before the node started.
+    pub async fn start_with_links(links: Vec<String>) -> Harness {
+        Self::boot(links, |_| {}).await
+    }
+
+    /// Starts with the site changed by `configure`, e.g. to add cache rules.
+    pub async fn start_with_site(configure: impl FnOnce(&mut SiteConfig)) -> Harness {
+        Self::boot(Vec::new(), configure).await
+    }
+
+    /// Starts with both `links` on the CDN list and the site changed by `configure`.
+    pub async fn start_with_links_and_site(
+        links: Vec<String>,
+        configure: impl FnOnce(&mut SiteConfig),
+    ) -> Harness {
+        Self::boot(links, configure).await
+    }
+
+    /// Starts with `ChainEdge.sol` deployed to anvil and the node following it over
+    /// WebSocket. Needs anvil and forge.
+    pub async fn start_on_anvil() -> Harness {
+        let anvil = Anvil::start().await;
+        let chain = chain::connect(&anvil.chain_config())
+            .await
+            .expect("could not connect to anvil");
+        Self::boot_on(chain, Some(anvil), |_| {}).await
+    }
+
+    async fn boot(links: Vec<String>, configure: impl FnOnce(&mut SiteConfig)) -> Harness {
+        Self::boot_on(Arc::new(MockChain::new(links)), None, configure).await
+    }
+
+    async fn boot_on(
+        chain: Arc<dyn ChainBackend>,
+        anvil: Option<Anvil>,
+        configure: impl FnOnce(&mut SiteConfig),
+    ) -> Harness {
+        use_temporary_cache_dir();
+
+        let origin_listener = ephemeral_listener();
+        let origin = origin_listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(origin_listener)
+                .unwrap()
+                .serve(origin_server::app().into_make_service()),
+        );
+
+        let edge_listener = ephemeral_listener();
+        let edge = edge_listener.local_addr().unwrap();
+
+        let mut config = Config::default();
+        config.listen = edge.to_string();
+        config.sites = vec![SiteConfig {
+            front_domain: edge.to_string(),
+            origin_domain: origin.to_string(),
+            ..config.sites.remove(0)
+        }];
+        configure(&mut config.sites[0]);
+
+        let followed = Arc::new(Followed {
+            chain,
+            listeners: AtomicUsize::new(0),
+        });
+        let node = Node {
+            config,
+            admin_password: ADMIN_PASSWORD.to_owned(),
+            chain: followed.clone(),
+        };
+        let (shutdown, stop) = oneshot::channel::<()>();
+        let node = tokio::spawn(chainedge::serve(node, edge_listener, async {
+            let _ = stop.await;
+        }));
+
+        let client = reqwest::Client::builder()
+            .redirect(redirect::Policy::none())
+            .build()
+            .unwrap();
+
+        let harness = Harness {
+            edge,
+            origin,
+            chain: followed.clone(),
+            client,
+            shutdown: Some(shutdown),
+            node: Some(node),
+            anvil,
+        };
+        harness
+            .eventually("the node to follow the contract events", || async {
+                (followed.listeners.load(Ordering::SeqCst) > 0).then_some(())
+            })
+            .await;
+        harness
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.edge, path)
+    }
+
+    /// The cache key the node stores a GET of `path` under.
+    pub fn cache_key(&self, path: &str) -> String {
+        format!("GET\t{}{}", self.edge, path)
+    }
+
+    pub async fn get(&self, path: &str) -> Response {
+        self.client.get(self.url(path)).send().await.unwrap()
+    }
+
+    /// Calls the admin API with the bearer token and returns the JSON body.
+    pub async fn api(&self, path: &str) -> (StatusCode, Value) {
+        let response = self
+            .client
+            .get(self.url(&format!("/_chainedge/api/v1{}", path)))
+            .header(header::AUTHORIZATION, format!("Bearer {}", ADMIN_PASSWORD))
+            .send()
+            .await
+            .unwrap();
+        let status = response.status();
+        (status, response.json().await.unwrap())
+    }
+
+    /// The admin API view of the entry stored under `key`, `None` while there is none.
+    pub async fn entry(&self, key: &str) -> Option<Value> {
+        let mut url = reqwest::Url::parse(&self.url("/_chainedge/api/v1/entry")).unwrap();
+        url.query_pairs_mut().append_pair("key", key);
+        let response = self
+            .client
+            .get(url)
+            .header(header::AUTHORIZATION, format!("Bearer {}", ADMIN_PASSWORD))
+            .send()
+            .await
+            .unwrap();
+        match response.status() {
+            StatusCode::NOT_FOUND => None,
+            StatusCode::OK => Some(response.json().await.unwrap()),
+            status => panic!("unexpected status {} for entry {:?}", status, key),
+        }
+    }
+
+    /// How many requests for `path` reached the origin.
+    pub async fn origin_requests(&self, path: &str) -> u64 {
+        self.origin_counters(path).await["requests"].as_u64().unwrap_or(0)
+    }
+
+    /// What the origin counted for `path`: `requests`, `not_modified` and `failed`.
+    pub async fn origin_counters(&self, path: &str) -> Value {
+        let counters: Value = self
+            .client
+            .get(format!("http://{}/_origin/requests", self.origin))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        counters[path].clone()
+    }
+
+    /// Logs in to the admin pages and returns the session cookie, as `name=value`.
+    pub async fn login(&self) -> String {
+        let response = self
+            .client
+            .post(self.url("/_chainedge/auth"))
+            .form(&[("password", ADMIN_PASSWORD)])
+            .send()
+            .await
+            .unwrap();
+        response.headers()[header::SET_COOKIE]
+            .to_str()
+            .unwrap()
+            .split(';')
+            .next()
+            .unwrap()
+            .to_owned()
+    }
+
+    /// Polls `check` until it returns something, panicking with `what` after a while.
+    pub async fn eventually<T, F, Fut>(&self, what: &str, mut check: F) -> T
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Option<T>>,
+    {
+        let started = tokio::time::Instant::now();
+        loop {
+            if let Some(value) = check().await {
+                return value;
+            }
+            if started.elapsed() > EVENTUALLY {
+                panic!("timed out waiting for {}", what);
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Shuts the node down gracefully and fails if it did not stop cleanly.
+    pub async fn stop(mut self) {
+        let _ = self.shutdown.take().unwrap().send(());
+        self.node
+            .take()
+            .unwrap()
+            .await
+            .expect("node task panicked")
+            .expect("node failed");
+    }
+}
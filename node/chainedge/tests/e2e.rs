@@ -0,0 +1,875 @@
+mod common;
+
+use chainedge::{
+    cdn_list::ListAction,
+    rules::{CacheAction, CacheRule},
+};
+use common::{Harness, ADMIN_PASSWORD};
+use ethers::types::U256;
+use reqwest::{header, StatusCode};
+use serde_json::{json, Value};
+
+#[tokio::test]
+async fn serves_misses_from_the_origin_and_hits_from_the_cache() {
+    let harness = Harness::start().await;
+    let key = harness.cache_key("/fast");
+
+    let miss = harness.get("/fast").await;
+    assert_eq!(miss.status(), StatusCode::OK);
+    let miss_body = miss.text().await.unwrap();
+    let entry = harness.entry(&key).await.expect("the miss is stored");
+    assert_eq!(entry["entry"]["hits"], 0);
+
+    let hit = harness.get("/fast").await;
+    assert_eq!(hit.status(), StatusCode::OK);
+    // the page shows the time it was rendered at, so only a cached copy is identical
+    assert_eq!(hit.text().await.unwrap(), miss_body);
+    let entry = harness.entry(&key).await.unwrap();
+    assert_eq!(entry["entry"]["hits"], 1);
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn answers_head_from_the_cached_get() {
+    let harness = Harness::start().await;
+    let path = "/programmable/head?cache_control=max-age%3D60";
+
+    let get = harness.get(path).await;
+    let length = get.headers()[header::CONTENT_LENGTH].clone();
+    assert!(!get.bytes().await.unwrap().is_empty());
+
+    let head = harness.client.head(harness.url(path)).send().await.unwrap();
+    assert_eq!(head.status(), StatusCode::OK);
+    assert_eq!(head.headers()[header::CONTENT_LENGTH], length);
+    assert!(head.bytes().await.unwrap().is_empty());
+    assert_eq!(harness.origin_requests("/programmable/head").await, 1);
+    assert_eq!(harness.entry(&harness.cache_key(path)).await.unwrap()["entry"]["hits"], 1);
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn never_stores_head_responses() {
+    let harness = Harness::start().await;
+    let path = "/programmable/head-only?cache_control=max-age%3D60";
+
+    for _ in 0..2 {
+        let head = harness.client.head(harness.url(path)).send().await.unwrap();
+        assert_eq!(head.status(), StatusCode::OK);
+    }
+    assert_eq!(harness.origin_requests("/programmable/head-only").await, 2);
+    assert!(harness.entry(&harness.cache_key(path)).await.is_none());
+    let (_, entries) = harness.api("/entries?contains=/programmable/head-only").await;
+    assert_eq!(entries["entries"], json!([]));
+
+    // the GET that follows is a miss, then a hit
+    harness.get(path).await;
+    harness.get(path).await;
+    assert_eq!(harness.origin_requests("/programmable/head-only").await, 3);
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn keys_post_requests_by_their_body() {
+    let harness = Harness::start_with_site(|site| site.cache_methods.push("POST".to_owned())).await;
+    let path = "/programmable/search?cache_control=max-age%3D60";
+    let post = |body: &'static str| harness.client.post(harness.url(path)).body(body).send();
+
+    let first = post("q=first").await.unwrap().text().await.unwrap();
+    let second = post("q=second").await.unwrap().text().await.unwrap();
+    assert_ne!(first, second);
+    assert_eq!(post("q=first").await.unwrap().text().await.unwrap(), first);
+    assert_eq!(post("q=second").await.unwrap().text().await.unwrap(), second);
+    assert_eq!(harness.origin_requests("/programmable/search").await, 2);
+
+    let (_, entries) = harness.api("/entries?contains=/programmable/search").await;
+    let keys: Vec<&str> = entries["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter_map(|e| e["key"].as_str())
+        .collect();
+    assert_eq!(keys.len(), 2);
+    assert!(keys.iter().all(|k| k.starts_with("POST\t") && k.contains("\tb:sha")));
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn refuses_request_bodies_over_the_limit() {
+    let harness = Harness::start_with_site(|site| site.cache_methods.push("POST".to_owned())).await;
+    let path = "/programmable/upload?cache_control=max-age%3D60";
+
+    let response = harness
+        .client
+        .post(harness.url(path))
+        .body(vec![b'x'; 2 * 1024 * 1024 + 1])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(harness.origin_requests("/programmable/upload").await, 0);
+
+    let response = harness.client.post(harness.url(path)).body("small").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(harness.origin_requests("/programmable/upload").await, 1);
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn does_not_store_responses_without_freshness() {
+    let harness = Harness::start().await;
+
+    // the root page has no Cache-Control
+    assert_eq!(harness.get("/").await.status(), StatusCode::OK);
+    assert!(harness.entry(&harness.cache_key("/")).await.is_none());
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn fetches_fresh_responses_from_the_origin_once() {
+    let harness = Harness::start().await;
+    let path = "/programmable/fresh?cache_control=max-age=60";
+
+    for _ in 0..3 {
+        assert_eq!(harness.get(path).await.status(), StatusCode::OK);
+    }
+    assert_eq!(harness.origin_requests("/programmable/fresh").await, 1);
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn passes_no_store_responses_through() {
+    let harness = Harness::start().await;
+    let path = "/programmable/private?cache_control=no-store";
+
+    let first = harness.get(path).await.text().await.unwrap();
+    let second = harness.get(path).await.text().await.unwrap();
+    assert_eq!(first, "GET /programmable/private #1");
+    assert_eq!(second, "GET /programmable/private #2");
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn does_not_cache_origin_failures() {
+    let harness = Harness::start().await;
+    let path = "/programmable/flaky?cache_control=max-age=60&fail_first=1";
+
+    assert_eq!(harness.get(path).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(harness.get(path).await.status(), StatusCode::OK);
+    assert_eq!(harness.get(path).await.status(), StatusCode::OK);
+    assert_eq!(harness.origin_requests("/programmable/flaky").await, 2);
+
+    harness.stop().await;
+}
+
+async fn populates_and_removes_entries(harness: Harness) {
+    let key = harness.cache_key("/fast");
+
+    harness
+        .chain
+        .change_list(ListAction::Add, vec!["get@/fast".to_owned()])
+        .await
+        .unwrap();
+    harness
+        .eventually("the listed link to be populated", || harness.entry(&key))
+        .await;
+
+    let hit = harness.get("/fast").await;
+    assert_eq!(hit.status(), StatusCode::OK);
+    assert_eq!(harness.entry(&key).await.unwrap()["entry"]["hits"], 1);
+
+    harness
+        .chain
+        .change_list(ListAction::Remove, vec!["get@/fast".to_owned()])
+        .await
+        .unwrap();
+    harness
+        .eventually("the removed link to be evicted", || async {
+            harness.entry(&key).await.is_none().then_some(())
+        })
+        .await;
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn populates_and_removes_entries_on_contract_events() {
+    populates_and_removes_entries(Harness::start().await).await;
+}
+
+#[tokio::test]
+#[ignore = "needs Foundry (anvil and forge)"]
+async fn populates_and_removes_entries_on_anvil() {
+    populates_and_removes_entries(Harness::start_on_anvil().await).await;
+}
+
+#[tokio::test]
+async fn verifies_links_pinned_before_the_node_started() {
+    // written as normalized, so the paths are their cache keys
+    let matching = "/programmable/pinned?body=pinned&cache_control=max-age%3D60&gzip=true";
+    let tampered = "/programmable/tampered?body=tampered&cache_control=max-age%3D60";
+    let pinned = cacache::Integrity::from(b"pinned");
+    let harness = Harness::start_with_links(vec![
+        format!("get\t{}\t{}", matching, pinned),
+        format!("get\t{}\t{}", tampered, pinned),
+    ])
+    .await;
+    // the node runs in this process, its pins can be looked at directly
+    let tampered_key = harness.cache_key(tampered);
+    harness
+        .eventually("the listed links to be pinned", || async {
+            chainedge::integrity::expected(&tampered_key).map(|_| ())
+        })
+        .await;
+
+    // the origin would gzip the body, which then no longer matches its pin
+    let served = harness
+        .client
+        .get(harness.url(matching))
+        .header(header::ACCEPT_ENCODING, "gzip")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(served.status(), StatusCode::OK);
+    assert_eq!(harness.get(matching).await.text().await.unwrap(), "pinned");
+    assert_eq!(harness.origin_requests("/programmable/pinned").await, 1);
+    assert_eq!(harness.get(tampered).await.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let (_, entries) = harness.api("/entries?contains=programmable/").await;
+    let keys: Vec<&str> = entries["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter_map(|e| e["key"].as_str())
+        .collect();
+    assert!(keys.iter().any(|k| k.contains("/programmable/pinned")));
+    assert!(!keys.iter().any(|k| k.contains("/programmable/tampered")));
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn verifies_every_variant_of_a_pinned_link() {
+    let matching = "/programmable/pinned-variant?body=pinned&cache_control=max-age%3D60&gzip=true";
+    let tampered = "/programmable/tampered-variant?body=tampered&cache_control=max-age%3D60";
+    let pinned = cacache::Integrity::from(b"pinned");
+    let harness = Harness::start_with_links_and_site(
+        vec![
+            format!("get\t{}\t{}", matching, pinned),
+            format!("get\t{}\t{}", tampered, pinned),
+        ],
+        |site| site.cache_key.headers.push("Accept-Language".to_owned()),
+    )
+    .await;
+    // pins are kept under the key without the header variant
+    let tampered_key = harness.cache_key(tampered);
+    harness
+        .eventually("the listed links to be pinned", || async {
+            chainedge::integrity::expected(&tampered_key).map(|_| ())
+        })
+        .await;
+
+    let get = |path: &str| {
+        harness
+            .client
+            .get(harness.url(path))
+            .header(header::ACCEPT_LANGUAGE, "en")
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .send()
+    };
+    assert_eq!(get(tampered).await.unwrap().status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(get(matching).await.unwrap().status(), StatusCode::OK);
+    assert_eq!(get(matching).await.unwrap().status(), StatusCode::OK);
+    assert_eq!(harness.origin_requests("/programmable/pinned-variant").await, 1);
+
+    let (_, entries) = harness.api("/entries?contains=-variant").await;
+    let keys: Vec<&str> = entries["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter_map(|e| e["key"].as_str())
+        .collect();
+    assert!(keys
+        .iter()
+        .any(|k| k.contains("/programmable/pinned-variant") && k.contains("\th:accept-language=en")));
+    assert!(!keys.iter().any(|k| k.contains("/programmable/tampered-variant")));
+
+    harness.stop().await;
+}
+
+async fn reports_bytes_served_from_the_cache(harness: Harness) {
+
+    // misses are served by the origin and not counted
+    harness.get("/fast").await.bytes().await.unwrap();
+    let served = harness.get("/fast").await.bytes().await.unwrap().len();
+
+    harness
+        .eventually("the served bytes to be reported", || async {
+            let count = harness.chain.serve_count().await.unwrap();
+            (count == U256::from(served)).then_some(())
+        })
+        .await;
+
+    let (status, node) = harness.api("/status").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(node["reporter"]["reports"], 1);
+    assert_eq!(node["unreported_served_bytes"], 0);
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn reports_bytes_served_from_the_cache_to_the_contract() {
+    reports_bytes_served_from_the_cache(Harness::start().await).await;
+}
+
+#[tokio::test]
+#[ignore = "needs Foundry (anvil and forge)"]
+async fn reports_bytes_served_from_the_cache_to_anvil() {
+    reports_bytes_served_from_the_cache(Harness::start_on_anvil().await).await;
+}
+
+#[tokio::test]
+async fn admin_api_requires_the_bearer_token() {
+    let harness = Harness::start().await;
+    let url = harness.url("/_chainedge/api/v1/status");
+
+    let anonymous = harness.client.get(&url).send().await.unwrap();
+    assert_eq!(anonymous.status(), StatusCode::UNAUTHORIZED);
+    let body: serde_json::Value = anonymous.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "unauthorized");
+
+    let wrong = harness
+        .client
+        .get(&url)
+        .bearer_auth("not-the-password")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(wrong.status(), StatusCode::UNAUTHORIZED);
+
+    let (status, _) = harness.api("/status").await;
+    assert_eq!(status, StatusCode::OK);
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn admin_pages_that_act_on_chain_require_a_login() {
+    let harness = Harness::start_with_links(vec!["get@/fast".to_owned()]).await;
+    let login = harness.url("/_chainedge/auth");
+
+    let anonymous = harness.get("/_chainedge/links").await;
+    assert!(anonymous.status().is_redirection());
+    assert_eq!(anonymous.headers()[header::LOCATION], "/_chainedge/auth");
+
+    let wrong = harness
+        .client
+        .post(&login)
+        .form(&[("password", "not-the-password")])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(wrong.headers()[header::LOCATION], "/_chainedge/auth");
+    assert!(wrong.headers().get(header::SET_COOKIE).is_none());
+
+    let right = harness
+        .client
+        .post(&login)
+        .form(&[("password", ADMIN_PASSWORD)])
+        .send()
+        .await
+        .unwrap();
+    let session = right.headers()[header::SET_COOKIE]
+        .to_str()
+        .unwrap()
+        .split(';')
+        .next()
+        .unwrap()
+        .to_owned();
+
+    let page = harness
+        .client
+        .get(harness.url("/_chainedge/links"))
+        .header(header::COOKIE, session)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(page.status(), StatusCode::OK);
+    assert!(page.text().await.unwrap().contains("get@/fast"));
+
+    // like every admin page, it also takes the bearer token
+    let page = harness
+        .client
+        .get(harness.url("/_chainedge/links"))
+        .bearer_auth(ADMIN_PASSWORD)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(page.status(), StatusCode::OK);
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn admin_api_submits_list_changes_to_the_contract() {
+    let harness = Harness::start().await;
+
+    let submitted = harness
+        .client
+        .post(harness.url("/_chainedge/api/v1/links"))
+        .bearer_auth(ADMIN_PASSWORD)
+        .json(&json!({ "action": "add", "links": ["get@/fast", "get@/fast"] }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(submitted.status(), StatusCode::OK);
+    let body: serde_json::Value = submitted.json().await.unwrap();
+    assert_eq!(body["links"][0]["status"], "queued");
+    assert_eq!(body["links"][1]["reason"], "duplicate");
+    let hash = body["transactions"][0]["hash"].clone();
+
+    assert_eq!(harness.chain.cdn_list().await.unwrap(), vec!["get@/fast"]);
+    harness
+        .eventually("the transaction to be mined", || async {
+            let (_, transactions) = harness.api("/transactions").await;
+            transactions
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|t| t["hash"] == hash && t["status"] == "mined")
+                .cloned()
+        })
+        .await;
+
+    let invalid = harness
+        .client
+        .post(harness.url("/_chainedge/api/v1/links"))
+        .bearer_auth(ADMIN_PASSWORD)
+        .json(&json!({ "action": "add", "links": ["not a link"] }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn keeps_hot_entries_in_memory_until_purged() {
+    let harness = Harness::start().await;
+    let path = "/programmable/hot?cache_control=max-age=60";
+
+    harness.get(path).await;
+    // the query is normalized in the key
+    let (_, entries) = harness.api("/entries?contains=/programmable/hot").await;
+    let key = entries["entries"][0]["key"].as_str().unwrap().to_owned();
+    // the first hit reads the disk and promotes the entry
+    let first_hit = harness.get(path).await.text().await.unwrap();
+    assert_eq!(harness.entry(&key).await.unwrap()["in_memory"], true);
+    assert_eq!(harness.get(path).await.text().await.unwrap(), first_hit);
+    assert_eq!(harness.origin_requests("/programmable/hot").await, 1);
+
+    let purged = harness
+        .client
+        .post(harness.url("/_chainedge/api/v1/purge"))
+        .bearer_auth(ADMIN_PASSWORD)
+        .json(&json!({ "key": key }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(purged.status(), StatusCode::OK);
+    assert!(harness.entry(&key).await.is_none());
+
+    harness.get(path).await;
+    assert_eq!(harness.origin_requests("/programmable/hot").await, 2);
+    assert_eq!(harness.entry(&key).await.unwrap()["in_memory"], false);
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn stores_compressed_variants_only_when_smaller() {
+    let harness = Harness::start_with_site(|site| site.compression.min_size = 0).await;
+    let cache_dir = std::env::var("CHAINEDGE_CACHE_DIR").unwrap();
+    let gzip_get = |path: &str| {
+        harness
+            .client
+            .get(harness.url(path))
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .send()
+    };
+
+    // gzip makes a one byte body larger
+    let tiny = "/programmable/tiny?body=x&cache_control=public%2Cmax-age%3D60";
+    for _ in 0..2 {
+        let response = gzip_get(tiny).await.unwrap();
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+    }
+    let tiny_key = format!("{}\te:gzip", harness.cache_key(tiny));
+    assert!(cacache::metadata(&cache_dir, &tiny_key).await.unwrap().is_none());
+
+    let text = "/programmable/text?cache_control=public%2Cmax-age%3D60&size=4096";
+    // the miss is compressed on the fly, the hit stores the variant
+    for _ in 0..2 {
+        let response = gzip_get(text).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+    }
+    let text_key = format!("{}\te:gzip", harness.cache_key(text));
+    assert!(cacache::metadata(&cache_dir, &text_key).await.unwrap().is_some());
+
+    harness.stop().await;
+}
+
+async fn purge(harness: &Harness, request: Value) -> Value {
+    let response = harness
+        .client
+        .post(harness.url("/_chainedge/api/v1/purge"))
+        .bearer_auth(ADMIN_PASSWORD)
+        .json(&request)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response.json().await.unwrap()
+}
+
+#[tokio::test]
+async fn purges_by_prefix_regex_and_tag() {
+    let harness = Harness::start().await;
+    let paths = [
+        "/programmable/static/a?cache_control=max-age%3D60",
+        "/programmable/static/b?cache_control=max-age%3D60",
+        "/programmable/img/x.png?cache_control=max-age%3D60",
+        "/programmable/img/y.jpg?cache_control=max-age%3D60",
+        "/programmable/tagged/1?cache_control=max-age%3D60&header=Surrogate-Key%3Anews%20sports",
+        "/programmable/tagged/2?cache_control=max-age%3D60&header=Cache-Tag%3Aweather%2Cnews",
+    ];
+    for path in paths {
+        assert_eq!(harness.get(path).await.status(), StatusCode::OK);
+    }
+    let stored = |path: &str| {
+        let (harness, key) = (&harness, harness.cache_key(path));
+        async move { harness.entry(&key).await }
+    };
+
+    let prefix = format!("{}/programmable/static/", harness.edge);
+    let purged = purge(&harness, json!({ "prefix": prefix })).await;
+    assert_eq!(purged["purged"], 2);
+    assert!(stored(paths[0]).await.is_none());
+    assert!(stored(paths[1]).await.is_none());
+
+    let purged = purge(&harness, json!({ "regex": "/programmable/img/[^?]*\\.png" })).await;
+    assert_eq!(purged["purged"], 1);
+    assert!(stored(paths[2]).await.is_none());
+    assert!(stored(paths[3]).await.is_some());
+
+    let purged = purge(&harness, json!({ "tag": "sports" })).await;
+    assert_eq!(purged["keys"], json!([harness.cache_key(paths[4])]));
+    assert!(stored(paths[5]).await.is_some());
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn revalidates_soft_purged_entries_with_the_origin() {
+    let harness = Harness::start().await;
+    let path = "/programmable/soft?cache_control=max-age%3D60&etag=v1";
+    let key = harness.cache_key(path);
+
+    let stored = harness.get(path).await.text().await.unwrap();
+    assert_eq!(harness.get(path).await.text().await.unwrap(), stored);
+    assert_eq!(harness.origin_requests("/programmable/soft").await, 1);
+
+    let purged = purge(&harness, json!({ "key": key, "soft": true })).await;
+    assert_eq!(purged["soft"], true);
+    assert_eq!(purged["purged"], 1);
+
+    // the origin answers the conditional request with a 304, the stored body is served
+    let revalidated = harness.get(path).await;
+    assert_eq!(revalidated.status(), StatusCode::OK);
+    assert_eq!(revalidated.text().await.unwrap(), stored);
+    let counters = harness.origin_counters("/programmable/soft").await;
+    assert_eq!(counters["requests"], 2);
+    assert_eq!(counters["not_modified"], 1);
+
+    // and fresh again
+    assert_eq!(harness.get(path).await.text().await.unwrap(), stored);
+    assert_eq!(harness.origin_requests("/programmable/soft").await, 2);
+    assert_eq!(harness.entry(&key).await.unwrap()["entry"]["hits"], 2);
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn quarantines_unreadable_entries_and_refetches_them() {
+    let harness = Harness::start().await;
+    let key = harness.cache_key("/fast");
+    let cache_dir = std::env::var("CHAINEDGE_CACHE_DIR").unwrap();
+    cacache::write(&cache_dir, &key, b"not an entry").await.unwrap();
+
+    assert_eq!(harness.get("/fast").await.status(), StatusCode::OK);
+    assert_eq!(harness.origin_requests("/fast").await, 1);
+    let quarantined = chainedge::envelope::quarantined(&cache_dir);
+    assert!(quarantined.iter().any(|q| q.key == key));
+
+    // the fresh response replaced the bad entry
+    let entry = harness.entry(&key).await.expect("the refetched response is stored");
+    assert!(entry["entry"]["error"].is_null());
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn seeds_the_cache_from_an_exported_snapshot() {
+    let harness = Harness::start().await;
+    let path = "/programmable/seed?cache_control=max-age%3D60";
+
+    harness.get(path).await;
+    let key = harness.cache_key(path);
+    assert!(harness.entry(&key).await.is_some());
+    let snapshot = harness
+        .client
+        .get(harness.url("/_chainedge/api/v1/snapshot"))
+        // the cache dir is shared with the other tests, so only the entries of this node
+        .query(&[("prefix", format!("{}/", harness.edge))])
+        .bearer_auth(ADMIN_PASSWORD)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(snapshot.status(), StatusCode::OK);
+    let snapshot = snapshot.bytes().await.unwrap();
+
+    harness
+        .client
+        .post(harness.url("/_chainedge/api/v1/purge"))
+        .bearer_auth(ADMIN_PASSWORD)
+        .json(&json!({ "key": key }))
+        .send()
+        .await
+        .unwrap();
+    assert!(harness.entry(&key).await.is_none());
+
+    let imported: Value = harness
+        .client
+        .post(harness.url("/_chainedge/api/v1/snapshot"))
+        .bearer_auth(ADMIN_PASSWORD)
+        .body(snapshot)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(imported["imported"].as_array().unwrap().iter().any(|k| k == &key));
+
+    assert_eq!(harness.get(path).await.status(), StatusCode::OK);
+    assert_eq!(harness.origin_requests("/programmable/seed").await, 1);
+
+    let garbage = harness
+        .client
+        .post(harness.url("/_chainedge/api/v1/snapshot"))
+        .bearer_auth(ADMIN_PASSWORD)
+        .body("not a snapshot")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(garbage.status(), StatusCode::BAD_REQUEST);
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn refuses_snapshot_variants_not_matching_their_pin() {
+    let harness =
+        Harness::start_with_site(|site| site.cache_key.headers.push("Accept-Language".to_owned()))
+            .await;
+    let path = "/programmable/seed-variant?body=tampered&cache_control=max-age%3D60";
+    let get = || {
+        harness
+            .client
+            .get(harness.url(path))
+            .header(header::ACCEPT_LANGUAGE, "en")
+            .send()
+    };
+
+    // cached before the link is pinned, under a key with the header variant
+    get().await.unwrap();
+    let (_, entries) = harness.api("/entries?contains=/programmable/seed-variant").await;
+    let key = entries["entries"][0]["key"].as_str().unwrap().to_owned();
+    assert!(key.contains("\th:accept-language=en"));
+    let snapshot = harness
+        .client
+        .get(harness.url("/_chainedge/api/v1/snapshot"))
+        .query(&[("prefix", format!("{}/", harness.edge))])
+        .bearer_auth(ADMIN_PASSWORD)
+        .send()
+        .await
+        .unwrap()
+        .bytes()
+        .await
+        .unwrap();
+    harness
+        .client
+        .post(harness.url("/_chainedge/api/v1/purge"))
+        .bearer_auth(ADMIN_PASSWORD)
+        .json(&json!({ "key": key }))
+        .send()
+        .await
+        .unwrap();
+
+    let pinned = cacache::Integrity::from(b"pinned");
+    harness
+        .chain
+        .change_list(ListAction::Add, vec![format!("get\t{}\t{}", path, pinned)])
+        .await
+        .unwrap();
+    let base_key = harness.cache_key(path);
+    harness
+        .eventually("the added link to be pinned", || async {
+            chainedge::integrity::expected(&base_key).map(|_| ())
+        })
+        .await;
+
+    let imported: Value = harness
+        .client
+        .post(harness.url("/_chainedge/api/v1/snapshot"))
+        .bearer_auth(ADMIN_PASSWORD)
+        .body(snapshot)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(!imported["imported"].as_array().unwrap().iter().any(|k| k == &key));
+    assert!(imported["refused"].as_u64().unwrap() >= 1);
+    assert!(harness.entry(&key).await.is_none());
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn applies_cache_rules_over_the_origin_headers() {
+    let harness = Harness::start_with_site(|site| {
+        site.cache_rules = vec![
+            CacheRule {
+                path: Some("/programmable/forced/**".to_owned()),
+                action: CacheAction::Cache,
+                edge_ttl: Some(60),
+                browser_ttl: Some(5),
+                ignore_set_cookie: true,
+                ..Default::default()
+            },
+            CacheRule {
+                path: Some("/programmable/bypassed/*".to_owned()),
+                action: CacheAction::Bypass,
+                ..Default::default()
+            },
+        ];
+    })
+    .await;
+
+    // the origin forbids storing, and sets a cookie
+    let forced = "/programmable/forced/a?cache_control=no-store&header=Set-Cookie:session%3D1";
+    let miss = harness.get(forced).await;
+    assert_eq!(miss.headers()[header::CACHE_CONTROL], "max-age=5");
+    assert!(miss.headers().contains_key(header::SET_COOKIE));
+    let hit = harness.get(forced).await;
+    assert_eq!(hit.headers()[header::CACHE_CONTROL], "max-age=5");
+    assert!(!hit.headers().contains_key(header::SET_COOKIE));
+    assert!(!hit.headers().contains_key("x-chainedge-edge-cache-control"));
+    assert_eq!(harness.origin_requests("/programmable/forced/a").await, 1);
+
+    let bypassed = "/programmable/bypassed/b?cache_control=max-age=60";
+    harness.get(bypassed).await;
+    harness.get(bypassed).await;
+    assert_eq!(harness.origin_requests("/programmable/bypassed/b").await, 2);
+
+    let (_, config) = harness.api("/config").await;
+    assert_eq!(config["sites"][0]["cache_rules"][0]["action"], "cache");
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn takes_the_edge_ttl_from_cdn_cache_control_and_surrogate_control() {
+    let harness = Harness::start().await;
+
+    for (path, query) in [
+        ("/programmable/cdn", "cache_control=no-store&header=CDN-Cache-Control:max-age%3D60"),
+        ("/programmable/surrogate", "cache_control=max-age=0&header=Surrogate-Control:max-age%3D60"),
+    ] {
+        let url = format!("{}?{}", path, query);
+        for _ in 0..2 {
+            let response = harness.get(&url).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            // browsers keep the origin's Cache-Control, the directives for the edge stay at the edge
+            assert!(response.headers().contains_key(header::CACHE_CONTROL));
+            assert!(!response.headers().contains_key("cdn-cache-control"));
+            assert!(!response.headers().contains_key("surrogate-control"));
+        }
+        assert_eq!(harness.origin_requests(path).await, 1, "{} is served from the cache", path);
+    }
+
+    harness.stop().await;
+}
+
+#[tokio::test]
+async fn dashboard_and_entry_actions_require_a_login_and_hide_credentials() {
+    let harness = Harness::start().await;
+    // public, since a shared cache does not store answers to authorized requests otherwise
+    harness
+        .client
+        .get(harness.url("/programmable/credentials?cache_control=public,max-age=60"))
+        .header(header::COOKIE, "session=secret-session")
+        .header(header::AUTHORIZATION, "Bearer secret-token")
+        .send()
+        .await
+        .unwrap();
+    let (_, entries) = harness.api("/entries?contains=/programmable/credentials").await;
+    let key = entries["entries"][0]["key"].as_str().unwrap().to_owned();
+    let mut entry_page = reqwest::Url::parse(&harness.url("/_chainedge/entry")).unwrap();
+    entry_page.query_pairs_mut().append_pair("key", &key);
+    let entry_page = format!("/_chainedge/entry?{}", entry_page.query().unwrap());
+
+    for path in ["/_chainedge/list", "/_chainedge/events", entry_page.as_str()] {
+        let anonymous = harness.get(path).await;
+        assert_eq!(anonymous.headers()[header::LOCATION], "/_chainedge/auth", "{}", path);
+    }
+    for path in ["/_chainedge/entry/purge", "/_chainedge/entry/refresh"] {
+        let anonymous = harness
+            .client
+            .post(harness.url(path))
+            .form(&[("key", key.as_str())])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(anonymous.headers()[header::LOCATION], "/_chainedge/auth", "{}", path);
+    }
+    assert!(harness.entry(&key).await.is_some(), "the anonymous purge did nothing");
+
+    let session = harness.login().await;
+    let page = harness
+        .client
+        .get(harness.url(&entry_page))
+        .header(header::COOKIE, &session)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(page.status(), StatusCode::OK);
+    let page = page.text().await.unwrap();
+    assert!(!page.contains("secret-session") && !page.contains("secret-token"));
+    assert!(page.contains("&lt;redacted&gt;"));
+
+    let entry = harness.entry(&key).await.unwrap();
+    assert_eq!(entry["request"]["headers"]["cookie"][0], "<redacted>");
+
+    harness.stop().await;
+}
@@ -0,0 +1,248 @@
+//! Recording responses of a real origin and replaying them without it.
+//!
+//! Every exchange is stored as `<name>.json`, the request line and response head,
+//! next to `<name>.body`, the raw response body. The name is derived from the method,
+//! the path with its query and the request body, which is also how replayed requests
+//! are matched. Recording a request again replaces its fixture.
+
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+/// Connection-specific headers, which are not part of a recorded response.
+const HOP_BY_HOP: [HeaderName; 6] = [
+    header::CONNECTION,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+    header::TE,
+    header::TRAILER,
+    header::CONTENT_LENGTH,
+];
+
+/// Conditional headers, which would let the upstream answer 304 and the fixture lose its body.
+/// The replayer answers conditional requests from the recorded validators itself.
+const CONDITIONAL: [HeaderName; 5] = [
+    header::IF_NONE_MATCH,
+    header::IF_MODIFIED_SINCE,
+    header::IF_MATCH,
+    header::IF_UNMODIFIED_SINCE,
+    header::IF_RANGE,
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fixture {
+    pub method: String,
+    /// Path and query of the request.
+    pub uri: String,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    /// How long the origin took to answer.
+    pub elapsed_ms: u64,
+}
+
+/// FNV-1a, stable across builds unlike the std hasher.
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// File name of the fixture of a request, readable enough to find one by its path.
+pub fn fixture_name(method: &Method, uri: &str, body: &[u8]) -> String {
+    let mut hash = 0xcbf29ce484222325;
+    for part in [method.as_str().as_bytes(), b"\t", uri.as_bytes(), b"\t", body] {
+        hash = fnv1a(hash, part);
+    }
+
+    let readable: String = uri
+        .trim_start_matches('/')
+        .chars()
+        .take(40)
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("{}-{}-{:016x}", method.as_str().to_ascii_lowercase(), readable, hash)
+}
+
+fn path_and_query(uri: &Uri) -> String {
+    uri.path_and_query()
+        .map_or_else(|| uri.path().to_owned(), |p| p.to_string())
+}
+
+pub struct Recorder {
+    upstream: String,
+    dir: PathBuf,
+    client: reqwest::Client,
+}
+
+impl Recorder {
+    /// Proxies to `upstream`, e.g. `http://localhost:3000`, saving into `dir`.
+    pub fn new(upstream: String, dir: PathBuf) -> io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(Recorder {
+            upstream: upstream.trim_end_matches('/').to_owned(),
+            dir,
+            client: reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .map_err(io::Error::other)?,
+        })
+    }
+
+    async fn record(&self, method: Method, uri: &Uri, headers: HeaderMap, body: Bytes) -> Result<Response, String> {
+        let uri = path_and_query(uri);
+        let name = fixture_name(&method, &uri, &body);
+
+        let mut upstream_headers = headers;
+        upstream_headers.remove(header::HOST);
+        for name in CONDITIONAL {
+            upstream_headers.remove(name);
+        }
+        let started = Instant::now();
+        let response = self
+            .client
+            .request(method.clone(), format!("{}{}", self.upstream, uri))
+            .headers(upstream_headers)
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| format!("upstream failed: {}", e))?;
+        let status = response.status();
+        let mut headers = response.headers().clone();
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| format!("upstream body failed: {}", e))?;
+        for name in HOP_BY_HOP {
+            headers.remove(name);
+        }
+
+        let fixture = Fixture {
+            method: method.to_string(),
+            uri,
+            status: status.as_u16(),
+            headers: headers
+                .iter()
+                .filter_map(|(n, v)| Some((n.to_string(), v.to_str().ok()?.to_owned())))
+                .collect(),
+            elapsed_ms: started.elapsed().as_millis() as u64,
+        };
+        let json = serde_json::to_vec_pretty(&fixture).map_err(|e| e.to_string())?;
+        tokio::fs::write(self.dir.join(format!("{}.body", name)), &body)
+            .await
+            .map_err(|e| format!("could not save {}: {}", name, e))?;
+        tokio::fs::write(self.dir.join(format!("{}.json", name)), json)
+            .await
+            .map_err(|e| format!("could not save {}: {}", name, e))?;
+        tracing::info!("recorded {} {} as {}", fixture.method, fixture.uri, name);
+
+        Ok((status, headers, body).into_response())
+    }
+}
+
+pub async fn record(
+    State(recorder): State<Arc<Recorder>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    match recorder.record(method, &uri, headers, body).await {
+        Ok(response) => response,
+        Err(e) => (StatusCode::BAD_GATEWAY, e).into_response(),
+    }
+}
+
+pub struct Replayer {
+    fixtures: HashMap<String, (Fixture, Bytes)>,
+    /// Waits as long as the origin took when recording.
+    latency: bool,
+}
+
+impl Replayer {
+    /// Loads every fixture of `dir` up front.
+    pub fn load(dir: &Path, latency: bool) -> io::Result<Self> {
+        let mut fixtures = HashMap::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let fixture: Fixture = serde_json::from_slice(&std::fs::read(&path)?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+            let body = std::fs::read(path.with_extension("body"))?;
+            let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+            fixtures.insert(name, (fixture, Bytes::from(body)));
+        }
+        tracing::info!("loaded {} fixtures from {}", fixtures.len(), dir.display());
+        Ok(Replayer { fixtures, latency })
+    }
+
+    pub fn len(&self) -> usize {
+        self.fixtures.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fixtures.is_empty()
+    }
+}
+
+fn not_modified(fixture_headers: &HeaderMap, request_headers: &HeaderMap) -> bool {
+    let (Some(etag), Some(if_none_match)) = (
+        fixture_headers.get(header::ETAG).and_then(|v| v.to_str().ok()),
+        request_headers
+            .get(header::IF_NONE_MATCH)
+            .and_then(|v| v.to_str().ok()),
+    ) else {
+        return false;
+    };
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+pub async fn replay(
+    State(replayer): State<Arc<Replayer>>,
+    method: Method,
+    uri: Uri,
+    request_headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let name = fixture_name(&method, &path_and_query(&uri), &body);
+    let Some((fixture, body)) = replayer.fixtures.get(&name) else {
+        return (StatusCode::NOT_FOUND, format!("no fixture {}", name)).into_response();
+    };
+
+    if replayer.latency {
+        tokio::time::sleep(Duration::from_millis(fixture.elapsed_ms)).await;
+    }
+
+    let mut headers = HeaderMap::new();
+    for (name, value) in &fixture.headers {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            headers.append(name, value);
+        }
+    }
+    let status = StatusCode::from_u16(fixture.status).unwrap_or(StatusCode::OK);
+
+    if status == StatusCode::OK && not_modified(&headers, &request_headers) {
+        return (StatusCode::NOT_MODIFIED, headers).into_response();
+    }
+    (status, headers, body.clone()).into_response()
+}
@@ -0,0 +1,130 @@
+use std::sync::Arc;
+
+use axum::{
+    http::{header, HeaderMap},
+    response::IntoResponse,
+    routing::*,
+    Router,
+};
+use tower_http::services::ServeDir;
+use chrono::Local;
+use maud::Markup;
+
+pub mod fixtures;
+pub mod programmable;
+
+/// The sample site, with its static files served from `assets`, and the
+/// programmable routes described in [`programmable`].
+pub fn app() -> Router {
+    let state = Arc::new(programmable::OriginState::default());
+
+    Router::new()
+        .route("/", get(root))
+        .route("/slow", get(slow))
+        .route("/fast", get(fast))
+        .nest_service("/assets", get_service(ServeDir::new("assets")))
+        .route("/programmable/*path", any(programmable::programmable))
+        .route(
+            "/_origin/script",
+            post(programmable::set_script).delete(programmable::clear_scripts),
+        )
+        .route(
+            "/_origin/requests",
+            get(programmable::requests).delete(programmable::reset_requests),
+        )
+        .fallback(programmable::scripted)
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            programmable::count,
+        ))
+        .with_state(state)
+}
+
+/// Forwards everything to the origin of `recorder`, saving the responses as fixtures.
+pub fn record_app(recorder: fixtures::Recorder) -> Router {
+    with_counters(Router::new().fallback(fixtures::record).with_state(Arc::new(recorder)))
+}
+
+/// Answers from the fixtures of `replayer` only.
+pub fn replay_app(replayer: fixtures::Replayer) -> Router {
+    with_counters(Router::new().fallback(fixtures::replay).with_state(Arc::new(replayer)))
+}
+
+/// Adds the request counters of the programmable origin at `/_origin/requests`.
+fn with_counters(app: Router) -> Router {
+    let state = Arc::new(programmable::OriginState::default());
+    app.route(
+        "/_origin/requests",
+        get(programmable::requests)
+            .delete(programmable::reset_requests)
+            .with_state(state.clone()),
+    )
+    .layer(axum::middleware::from_fn_with_state(state, programmable::count))
+}
+
+// basic handler that responds with a static string
+async fn root() -> impl axum::response::IntoResponse {
+    outer_template(maud::html! {
+        h1."text-6xl mb-4" { "Hey! I'm a sample app that's slow to respond." }
+
+        h3."text-4xl mb-4" {
+            "We have a few routes that respond differently."
+        }
+
+        h3."text-4xl mb-16" {
+            "The root is the only route that responds without any waiting."
+        }
+
+        p."text-xl" {
+            a."text-blue-400" href="/slow" { "/slow" }
+            " responds after 5 seconds with the current time"
+        }
+        p."text-xl" {
+            a."text-blue-400" href="/fast" { "/fast" }
+            " responds after 1 second with the current time"
+        }
+        p."text-xl" {
+            a."text-blue-400" href="/programmable/example?cache_control=max-age=60&etag=v1" { "/programmable/..." }
+            " responds as its query says, see the programmable module"
+        }
+    })
+}
+
+fn outer_template(body: Markup) -> Markup {
+    maud::html! {
+        script src="https://cdn.tailwindcss.com" {}
+
+        body class="flex flex-col items-center justify-center h-screen" {
+            (body)
+        }
+    }
+}
+
+fn now_template(title: &str) -> impl IntoResponse {
+    let now = Local::now();
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CACHE_CONTROL, "max-age=60".parse().unwrap());
+
+    let template = outer_template(maud::html! {
+        h1 class="text-6xl" { (title) }
+        p class="text-4xl" { (now) }
+
+        a class="text-blue-400 pt-16 text-xl" href="/" { "Go back home" }
+    });
+
+    (headers, template)
+}
+
+// handler that responds after 5 seconds
+async fn slow() -> impl IntoResponse {
+    tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+
+    now_template("Slow")
+}
+// handler that responds after 1 second
+async fn fast() -> impl IntoResponse {
+    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+
+    now_template("Fast")
+}
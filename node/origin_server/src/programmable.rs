@@ -0,0 +1,381 @@
+//! Responses controlled by the request, to put the edge through its paces.
+//!
+//! `/programmable/<anything>` answers as its query says, e.g.
+//! `/programmable/a?cache_control=max-age=60&etag=v1&delay_ms=200`:
+//!
+//! - `status`: status code, 200 by default
+//! - `cache_control`, `etag`, `vary`, `content_type`: the headers of the same name
+//! - `last_modified`: unix seconds, sent as an HTTP date
+//! - `header=Name:Value`: any other header, repeatable
+//! - `delay_ms`: wait before answering
+//! - `body`: the body, `size` bytes of filler instead, or by default `<METHOD> <path> #<n>`
+//!   where `n` counts the requests to the path
+//! - `gzip`: gzip the body for requests accepting it, as a compressing origin does
+//! - `chunks`, `chunk_delay_ms`: stream the body in that many chunks, without Content-Length
+//! - `fail=abort`: break the connection halfway through the body
+//! - `fail_first=N`: answer the first N requests to the path with a 503
+//!
+//! A request with `If-None-Match` or `If-Modified-Since` matching `etag` or `last_modified`
+//! gets a 304.
+//!
+//! Any other path answers from a script posted to `/_origin/script`, a JSON object
+//! `{"path": "/a", "responses": [...]}` of the same settings: every request takes the next
+//! response, the last one repeats. `DELETE /_origin/script` forgets all scripts.
+//!
+//! `GET /_origin/requests` counts the requests per path, `DELETE` resets the counts.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    body::{Bytes, StreamBody},
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use flate2::{write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Default)]
+pub struct OriginState {
+    counters: Mutex<BTreeMap<String, PathCounter>>,
+    scripts: Mutex<HashMap<String, Script>>,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct PathCounter {
+    pub requests: u64,
+    pub not_modified: u64,
+    pub failed: u64,
+}
+
+#[derive(Debug)]
+struct Script {
+    responses: Vec<Spec>,
+    next: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Failure {
+    Abort,
+}
+
+/// How to answer one request.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Spec {
+    pub status: Option<u16>,
+    pub cache_control: Option<String>,
+    pub etag: Option<String>,
+    pub last_modified: Option<i64>,
+    pub vary: Option<String>,
+    pub content_type: Option<String>,
+    pub headers: BTreeMap<String, String>,
+    pub delay_ms: u64,
+    pub body: Option<String>,
+    pub size: Option<usize>,
+    pub gzip: bool,
+    pub chunks: usize,
+    pub chunk_delay_ms: u64,
+    pub fail: Option<Failure>,
+    pub fail_first: u64,
+}
+
+impl Spec {
+    fn from_query(pairs: Vec<(String, String)>) -> Result<Spec, String> {
+        fn number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+            value
+                .parse()
+                .map_err(|_| format!("{} must be a number, not {:?}", name, value))
+        }
+
+        let mut spec = Spec::default();
+        for (name, value) in pairs {
+            match name.as_str() {
+                "status" => spec.status = Some(number(&name, &value)?),
+                "cache_control" => spec.cache_control = Some(value),
+                "etag" => spec.etag = Some(value),
+                "last_modified" => spec.last_modified = Some(number(&name, &value)?),
+                "vary" => spec.vary = Some(value),
+                "content_type" => spec.content_type = Some(value),
+                "header" => {
+                    let (name, value) = value
+                        .split_once(':')
+                        .ok_or_else(|| format!("header must be Name:Value, not {:?}", value))?;
+                    spec.headers.insert(name.trim().to_owned(), value.trim().to_owned());
+                }
+                "delay_ms" => spec.delay_ms = number(&name, &value)?,
+                "body" => spec.body = Some(value),
+                "size" => spec.size = Some(number(&name, &value)?),
+                "gzip" => spec.gzip = value != "false",
+                "chunks" => spec.chunks = number(&name, &value)?,
+                "chunk_delay_ms" => spec.chunk_delay_ms = number(&name, &value)?,
+                "fail" if value == "abort" => spec.fail = Some(Failure::Abort),
+                "fail" => return Err(format!("unknown failure {:?}", value)),
+                "fail_first" => spec.fail_first = number(&name, &value)?,
+                // anything else only varies the url, e.g. to bust a cache
+                _ => {}
+            }
+        }
+        Ok(spec)
+    }
+}
+
+fn http_date(unix_secs: i64) -> Option<String> {
+    DateTime::<Utc>::from_timestamp(unix_secs, 0)
+        .map(|t| t.format("%a, %d %b %Y %H:%M:%S GMT").to_string())
+}
+
+fn not_modified(spec: &Spec, request_headers: &HeaderMap) -> bool {
+    if let Some(etag) = &spec.etag {
+        if let Some(if_none_match) = request_headers
+            .get(header::IF_NONE_MATCH)
+            .and_then(|v| v.to_str().ok())
+        {
+            let quoted = format!("\"{}\"", etag.trim_matches('"'));
+            return if_none_match.split(',').map(str::trim).any(|candidate| {
+                candidate == "*" || candidate.trim_start_matches("W/") == quoted
+            });
+        }
+    }
+    if let (Some(last_modified), Some(since)) = (
+        spec.last_modified,
+        request_headers
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| DateTime::parse_from_rfc2822(v).ok()),
+    ) {
+        return since.timestamp() >= last_modified;
+    }
+    false
+}
+
+fn spec_headers(spec: &Spec) -> Result<HeaderMap, String> {
+    let mut headers = HeaderMap::new();
+    let mut set = |name: HeaderName, value: &str| -> Result<(), String> {
+        let value = HeaderValue::from_str(value)
+            .map_err(|_| format!("invalid value {:?} for {}", value, name))?;
+        headers.insert(name, value);
+        Ok(())
+    };
+
+    if let Some(cache_control) = &spec.cache_control {
+        set(header::CACHE_CONTROL, cache_control)?;
+    }
+    if let Some(etag) = &spec.etag {
+        set(header::ETAG, &format!("\"{}\"", etag.trim_matches('"')))?;
+    }
+    if let Some(last_modified) = spec.last_modified {
+        let date = http_date(last_modified).ok_or("last_modified is out of range")?;
+        set(header::LAST_MODIFIED, &date)?;
+    }
+    if let Some(vary) = &spec.vary {
+        set(header::VARY, vary)?;
+    }
+    set(
+        header::CONTENT_TYPE,
+        spec.content_type.as_deref().unwrap_or("text/plain; charset=utf-8"),
+    )?;
+    for (name, value) in &spec.headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("invalid header name {:?}", name))?;
+        set(name, value)?;
+    }
+    Ok(headers)
+}
+
+fn body(spec: &Spec, method: &Method, path: &str, request_number: u64) -> Vec<u8> {
+    match (&spec.body, spec.size) {
+        (Some(body), _) => body.clone().into_bytes(),
+        (None, Some(size)) => b"0123456789abcdef".iter().copied().cycle().take(size).collect(),
+        (None, None) => format!("{} {} #{}", method, path, request_number).into_bytes(),
+    }
+}
+
+fn gzip(body: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body).expect("writing to memory does not fail");
+    encoder.finish().expect("writing to memory does not fail")
+}
+
+/// Streams `body` in `spec.chunks` pieces, erroring halfway when asked to abort.
+fn streamed(spec: &Spec, body: Vec<u8>) -> Response {
+    let chunks = spec.chunks.max(1);
+    let chunk_size = body.len().div_ceil(chunks).max(1);
+    let mut pieces: Vec<Result<Bytes, std::io::Error>> = body
+        .chunks(chunk_size)
+        .map(|c| Ok(Bytes::copy_from_slice(c)))
+        .collect();
+    if spec.fail == Some(Failure::Abort) {
+        pieces.truncate(pieces.len() / 2);
+        pieces.push(Err(std::io::Error::new(
+            std::io::ErrorKind::ConnectionAborted,
+            "aborted as asked",
+        )));
+    }
+
+    let delay = Duration::from_millis(spec.chunk_delay_ms);
+    let stream = futures::stream::unfold(
+        (pieces.into_iter(), true),
+        move |(mut pieces, first)| async move {
+            let piece = pieces.next()?;
+            if !first && !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            Some((piece, (pieces, false)))
+        },
+    );
+    StreamBody::new(stream).into_response()
+}
+
+async fn respond(
+    state: &OriginState,
+    spec: Spec,
+    method: &Method,
+    path: &str,
+    request_headers: &HeaderMap,
+) -> Response {
+    let request_number = state
+        .counters
+        .lock()
+        .unwrap()
+        .get(path)
+        .map_or(0, |c| c.requests);
+
+    if spec.delay_ms > 0 {
+        tokio::time::sleep(Duration::from_millis(spec.delay_ms)).await;
+    }
+
+    if request_number <= spec.fail_first {
+        state.counter(path, |c| c.failed += 1);
+        return (StatusCode::SERVICE_UNAVAILABLE, "failing as asked").into_response();
+    }
+
+    let mut headers = match spec_headers(&spec) {
+        Ok(headers) => headers,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    let status = match StatusCode::from_u16(spec.status.unwrap_or(200)) {
+        Ok(status) => status,
+        Err(_) => return (StatusCode::BAD_REQUEST, "invalid status").into_response(),
+    };
+
+    if status == StatusCode::OK && not_modified(&spec, request_headers) {
+        state.counter(path, |c| c.not_modified += 1);
+        return (StatusCode::NOT_MODIFIED, headers).into_response();
+    }
+
+    let mut body = body(&spec, method, path, request_number);
+    let accepts_gzip = request_headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.split(',').any(|c| c.trim().starts_with("gzip")));
+    if spec.gzip && accepts_gzip {
+        body = gzip(&body);
+        headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    }
+    let streams = spec.chunks > 1 || spec.fail.is_some();
+    let mut response = match streams {
+        true => streamed(&spec, body),
+        false => body.into_response(),
+    };
+    *response.status_mut() = status;
+    response.headers_mut().extend(headers);
+    response
+}
+
+impl OriginState {
+    fn counter(&self, path: &str, update: impl FnOnce(&mut PathCounter)) {
+        update(self.counters.lock().unwrap().entry(path.to_owned()).or_default());
+    }
+}
+
+/// Counts every request by path, before it is answered.
+pub async fn count<B>(
+    State(state): State<Arc<OriginState>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let path = request.uri().path().to_owned();
+    if !path.starts_with("/_origin/") {
+        state.counter(&path, |c| c.requests += 1);
+    }
+    next.run(request).await
+}
+
+pub async fn programmable(
+    State(state): State<Arc<OriginState>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    Query(query): Query<Vec<(String, String)>>,
+) -> Response {
+    match Spec::from_query(query) {
+        Ok(spec) => respond(&state, spec, &method, uri.path(), &headers).await,
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+    }
+}
+
+pub async fn scripted(
+    State(state): State<Arc<OriginState>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Response {
+    let spec = {
+        let mut scripts = state.scripts.lock().unwrap();
+        let Some(script) = scripts.get_mut(uri.path()) else {
+            return (StatusCode::NOT_FOUND, "no script for this path").into_response();
+        };
+        let spec = script.responses[script.next.min(script.responses.len() - 1)].clone();
+        script.next += 1;
+        spec
+    };
+    respond(&state, spec, &method, uri.path(), &headers).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScriptRequest {
+    path: String,
+    responses: Vec<Spec>,
+}
+
+pub async fn set_script(
+    State(state): State<Arc<OriginState>>,
+    Json(script): Json<ScriptRequest>,
+) -> Response {
+    if script.responses.is_empty() {
+        return (StatusCode::BAD_REQUEST, "a script needs at least one response").into_response();
+    }
+    state.scripts.lock().unwrap().insert(
+        script.path,
+        Script {
+            responses: script.responses,
+            next: 0,
+        },
+    );
+    StatusCode::NO_CONTENT.into_response()
+}
+
+pub async fn clear_scripts(State(state): State<Arc<OriginState>>) -> StatusCode {
+    state.scripts.lock().unwrap().clear();
+    StatusCode::NO_CONTENT
+}
+
+pub async fn requests(State(state): State<Arc<OriginState>>) -> Json<BTreeMap<String, PathCounter>> {
+    Json(state.counters.lock().unwrap().clone())
+}
+
+pub async fn reset_requests(State(state): State<Arc<OriginState>>) -> StatusCode {
+    state.counters.lock().unwrap().clear();
+    StatusCode::NO_CONTENT
+}
@@ -0,0 +1,127 @@
+use std::{
+    net::{SocketAddr, TcpListener},
+    path::PathBuf,
+};
+
+use axum::Router;
+use origin_server::fixtures::{Recorder, Replayer};
+use reqwest::{header, StatusCode};
+
+async fn start(app: Router) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(
+        axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service()),
+    );
+    addr
+}
+
+fn fixture_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("origin-fixtures-{}-{}", std::process::id(), test));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+#[tokio::test]
+async fn replays_what_was_recorded() {
+    let dir = fixture_dir("replay");
+    let origin = start(origin_server::app()).await;
+    let recorder = start(origin_server::record_app(
+        Recorder::new(format!("http://{}", origin), dir.clone()).unwrap(),
+    ))
+    .await;
+
+    let recorded = reqwest::get(format!(
+        "http://{}/programmable/a?cache_control=max-age=60&etag=v1&status=202",
+        recorder
+    ))
+    .await
+    .unwrap();
+    assert_eq!(recorded.status(), StatusCode::ACCEPTED);
+    let recorded_body = recorded.text().await.unwrap();
+    let posted = reqwest::Client::new()
+        .post(format!("http://{}/programmable/b", recorder))
+        .body("payload")
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+
+    // the replayer answers on its own, the origin would count up the bodies
+    let replayer = Replayer::load(&dir, false).unwrap();
+    assert_eq!(replayer.len(), 2);
+    let replay = start(origin_server::replay_app(replayer)).await;
+
+    for _ in 0..2 {
+        let replayed = reqwest::get(format!(
+            "http://{}/programmable/a?cache_control=max-age=60&etag=v1&status=202",
+            replay
+        ))
+        .await
+        .unwrap();
+        assert_eq!(replayed.status(), StatusCode::ACCEPTED);
+        assert_eq!(replayed.headers()[header::CACHE_CONTROL], "max-age=60");
+        assert_eq!(replayed.headers()[header::ETAG], "\"v1\"");
+        assert_eq!(replayed.text().await.unwrap(), recorded_body);
+    }
+
+    let replayed_post = reqwest::Client::new()
+        .post(format!("http://{}/programmable/b", replay))
+        .body("payload")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(replayed_post.text().await.unwrap(), posted);
+
+    let other_body = reqwest::Client::new()
+        .post(format!("http://{}/programmable/b", replay))
+        .body("other payload")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(other_body.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn replays_not_modified_for_a_matching_etag() {
+    let dir = fixture_dir("etag");
+    let origin = start(origin_server::app()).await;
+    let recorder = start(origin_server::record_app(
+        Recorder::new(format!("http://{}", origin), dir.clone()).unwrap(),
+    ))
+    .await;
+    reqwest::get(format!("http://{}/programmable/a?etag=v1", recorder))
+        .await
+        .unwrap();
+    // recorded again with a matching validator, the fixture must keep the full response
+    let recorded_again = reqwest::Client::new()
+        .get(format!("http://{}/programmable/a?etag=v1", recorder))
+        .header(header::IF_NONE_MATCH, "\"v1\"")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(recorded_again.status(), StatusCode::OK);
+
+    let replay = start(origin_server::replay_app(Replayer::load(&dir, false).unwrap())).await;
+    let conditional = reqwest::Client::new()
+        .get(format!("http://{}/programmable/a?etag=v1", replay))
+        .header(header::IF_NONE_MATCH, "\"v1\"")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(conditional.status(), StatusCode::NOT_MODIFIED);
+    let unconditional = reqwest::get(format!("http://{}/programmable/a?etag=v1", replay))
+        .await
+        .unwrap();
+    assert_eq!(unconditional.status(), StatusCode::OK);
+    assert!(!unconditional.bytes().await.unwrap().is_empty());
+
+    let unknown = reqwest::get(format!("http://{}/programmable/unknown", replay))
+        .await
+        .unwrap();
+    assert_eq!(unknown.status(), StatusCode::NOT_FOUND);
+}
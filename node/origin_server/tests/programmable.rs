@@ -0,0 +1,134 @@
+use std::net::{SocketAddr, TcpListener};
+
+use reqwest::{header, StatusCode};
+use serde_json::{json, Value};
+
+async fn start() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(
+        axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(origin_server::app().into_make_service()),
+    );
+    addr
+}
+
+#[tokio::test]
+async fn answers_as_the_query_says() {
+    let origin = start().await;
+    let response = reqwest::get(format!(
+        "http://{}/programmable/a?status=201&cache_control=max-age=5&vary=Accept&header=X-Test:yes&body=hello",
+        origin
+    ))
+    .await
+    .unwrap();
+
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(response.headers()[header::CACHE_CONTROL], "max-age=5");
+    assert_eq!(response.headers()[header::VARY], "Accept");
+    assert_eq!(response.headers()["x-test"], "yes");
+    assert_eq!(response.text().await.unwrap(), "hello");
+}
+
+#[tokio::test]
+async fn answers_conditional_requests_with_not_modified() {
+    let origin = start().await;
+    let url = format!("http://{}/programmable/a?etag=v1&last_modified=1700000000", origin);
+    let client = reqwest::Client::new();
+
+    let full = client.get(&url).send().await.unwrap();
+    assert_eq!(full.headers()[header::ETAG], "\"v1\"");
+    assert_eq!(full.headers()[header::LAST_MODIFIED], "Tue, 14 Nov 2023 22:13:20 GMT");
+
+    let by_etag = client
+        .get(&url)
+        .header(header::IF_NONE_MATCH, "\"v1\"")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(by_etag.status(), StatusCode::NOT_MODIFIED);
+
+    let by_date = client
+        .get(&url)
+        .header(header::IF_MODIFIED_SINCE, "Tue, 14 Nov 2023 22:13:20 GMT")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(by_date.status(), StatusCode::NOT_MODIFIED);
+
+    let changed = client
+        .get(&url)
+        .header(header::IF_NONE_MATCH, "\"v0\"")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(changed.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn streams_chunked_bodies_and_aborts_them() {
+    let origin = start().await;
+
+    let streamed = reqwest::get(format!("http://{}/programmable/a?size=1000&chunks=4", origin))
+        .await
+        .unwrap();
+    assert!(streamed.headers().get(header::CONTENT_LENGTH).is_none());
+    assert_eq!(streamed.bytes().await.unwrap().len(), 1000);
+
+    // depending on buffering the break shows before or after the response head
+    let aborted = match reqwest::get(format!("http://{}/programmable/a?size=1000&chunks=4&fail=abort", origin)).await {
+        Ok(response) => response.bytes().await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    assert!(aborted.is_err());
+}
+
+#[tokio::test]
+async fn fails_the_first_requests_and_counts_them() {
+    let origin = start().await;
+    let url = format!("http://{}/programmable/a?fail_first=2", origin);
+
+    assert_eq!(reqwest::get(&url).await.unwrap().status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(reqwest::get(&url).await.unwrap().status(), StatusCode::SERVICE_UNAVAILABLE);
+    let third = reqwest::get(&url).await.unwrap();
+    assert_eq!(third.status(), StatusCode::OK);
+    assert_eq!(third.text().await.unwrap(), "GET /programmable/a #3");
+
+    let counters: Value = reqwest::get(format!("http://{}/_origin/requests", origin))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(counters["/programmable/a"]["requests"], 3);
+    assert_eq!(counters["/programmable/a"]["failed"], 2);
+}
+
+#[tokio::test]
+async fn plays_scripts_in_order_and_repeats_the_last_response() {
+    let origin = start().await;
+    let client = reqwest::Client::new();
+
+    let scripted = client
+        .post(format!("http://{}/_origin/script", origin))
+        .json(&json!({
+            "path": "/scripted",
+            "responses": [
+                { "status": 500 },
+                { "body": "v1", "cache_control": "max-age=60" },
+            ],
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(scripted.status(), StatusCode::NO_CONTENT);
+
+    let url = format!("http://{}/scripted", origin);
+    assert_eq!(reqwest::get(&url).await.unwrap().status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(reqwest::get(&url).await.unwrap().text().await.unwrap(), "v1");
+    assert_eq!(reqwest::get(&url).await.unwrap().text().await.unwrap(), "v1");
+
+    let unknown = reqwest::get(format!("http://{}/not-scripted", origin)).await.unwrap();
+    assert_eq!(unknown.status(), StatusCode::NOT_FOUND);
+}